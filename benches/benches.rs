@@ -1,86 +1,86 @@
-use criterion::Criterion;
-use criterion::black_box;
-use criterion::{criterion_group, criterion_main};
-use rand_pcg::rand_core::RngCore;
-use rand_pcg::rand_core::SeedableRng;
-use rand::{Rng};
-use kvs::{KvsEngine, KvStore, SledStorage};
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use kvs::{KvsEngine, KvStore, LsmStore, SledStorage};
 
 fn read_kvs_benchmark(c: &mut Criterion) {
     let dir = tempfile::tempdir().unwrap();
-    let mut kvs = KvStore::open(dir.path()).unwrap();
-
-    let mut data = Vec::new();
-    for i in 0..1000 {
-        let key = random_data(10, 3);
-        let val = random_data(10, 3);
-        kvs.set(key.clone(), key.clone()).unwrap();
-        data.push((key, val));
-    }
-
-    c.bench_function("read", |b| {
-        let (key, val) = data.pop().unwrap();
-        b.iter(|| {
-            assert_eq!(kvs.get(key.clone()).unwrap(), Some(val.clone()));
-    })});
+    let kvs = KvStore::open(dir.path()).unwrap();
+    read_benchmark(c, "kvs read", kvs);
 }
 
 fn read_sled_benchmark(c: &mut Criterion) {
     let dir = tempfile::tempdir().unwrap();
-    let mut kvs = SledStorage::open(dir.path()).unwrap();
-
-    let mut data = Vec::new();
-    for _ in 0..1000 {
-        let key = random_data(10, 3);
-        let val = random_data(10, 3);
-        kvs.set(key.clone(), key.clone()).unwrap();
-        data.push((key, val));
-    }
+    let kvs = SledStorage::open(dir.path()).unwrap();
+    read_benchmark(c, "sled read", kvs);
+}
 
-    c.bench_function("read", |b| {
-        for (key, val) in &data {
-            b.iter(|| {
-                assert_eq!(kvs.get(key.clone()).unwrap(), Some(val.clone()));
-            })
-        };
-     });
+fn read_lsm_benchmark(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let kvs = LsmStore::open(dir.path()).unwrap();
+    read_benchmark(c, "lsm read", kvs);
 }
 
 fn write_kvs_benchmark(c: &mut Criterion) {
     let dir = tempfile::tempdir().unwrap();
-    let mut kvs = KvStore::open(dir.path()).unwrap();
-    
-    c.bench_function("write", |b| {
-        let key = random_data(10, 100_000);
-        let data = random_data(10, 100_000);
-        b.iter(|| {
-            assert!(kvs.set(key.clone(), data.clone()).is_ok());
-    })});
+    let kvs = KvStore::open(dir.path()).unwrap();
+    write_benchmark(c, "kvs write", kvs);
 }
 
 fn write_sled_benchmark(c: &mut Criterion) {
     let dir = tempfile::tempdir().unwrap();
-    let mut kvs = SledStorage::open(dir.path()).unwrap();
-    
-    c.bench_function("write", |b| {
-        let key = random_data(10, 100_000);
-        let data = random_data(10, 100_000);
+    let kvs = SledStorage::open(dir.path()).unwrap();
+    write_benchmark(c, "sled write", kvs);
+}
+
+fn write_lsm_benchmark(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let kvs = LsmStore::open(dir.path()).unwrap();
+    write_benchmark(c, "lsm write", kvs);
+}
+
+/// Read back 1000 pairs one after another
+fn read_benchmark(c: &mut Criterion, id: &str, mut kvs: impl KvsEngine + 'static) {
+    let mut data = Vec::new();
+    for i in 0..1000 {
+        let key = random_data(i, 10);
+        let val = random_data(i + 1000, 10);
+        kvs.set(key.clone(), val.clone()).unwrap();
+        data.push((key, val));
+    }
+
+    c.bench_function(id, move |b| {
+        let mut pairs = data.iter().cycle();
+        b.iter(|| {
+            let (key, val) = pairs.next().unwrap();
+            assert_eq!(kvs.get(key.clone()).unwrap(), Some(val.clone()));
+        })
+    });
+}
+
+/// Overwrite a key with a value of 100 KB
+fn write_benchmark(c: &mut Criterion, id: &str, mut kvs: impl KvsEngine + 'static) {
+    let key = random_data(10, 100);
+    let data = random_data(11, 100_000);
+    c.bench_function(id, move |b| {
         b.iter(|| {
             assert!(kvs.set(key.clone(), data.clone()).is_ok());
-    })});
+        })
+    });
 }
 
-criterion_group!(benches, write_kvs_benchmark, write_sled_benchmark, read_kvs_benchmark, read_sled_benchmark);
+criterion_group!(
+    benches,
+    write_kvs_benchmark,
+    write_sled_benchmark,
+    write_lsm_benchmark,
+    read_kvs_benchmark,
+    read_sled_benchmark,
+    read_lsm_benchmark
+);
 criterion_main!(benches);
 
-fn random_data(iv: u64, len: usize) -> String {
-    let mut data = vec![0; len];
-    let mut rng = rand_pcg::Pcg32::seed_from_u64(iv);
-    let mut i = 0;
-    while i < len {
-        data[i] = rng.gen_range(0, 127);
-        i += 1;
-    }
-
-    String::from_utf8(data).unwrap()
+fn random_data(seed: u64, len: usize) -> String {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len).map(|_| rng.gen_range(0, 127) as u8 as char).collect()
 }
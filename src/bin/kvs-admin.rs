@@ -0,0 +1,470 @@
+use structopt::StructOpt;
+use kvs::{
+    AdminCommand,
+    ClusterStatus,
+    DirLock,
+    EncryptionKey,
+    EngineKind,
+    EngineOptions,
+    Format,
+    KvStore,
+    KvStoreOptions,
+    LogEntry,
+    LogFile,
+    KvsEngine,
+    KvsError,
+    Metadata,
+    Package,
+    Registry,
+    RemotePairs,
+    ReplicationStatus,
+    ServerConfig,
+    admin,
+    construct_package,
+    reply,
+};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(name="migrate")]
+    Migrate {
+        #[structopt(long = "from")]
+        from: EngineKind,
+        #[structopt(long = "to")]
+        to: EngineKind,
+        #[structopt(parse(from_os_str))]
+        src: PathBuf,
+        #[structopt(parse(from_os_str))]
+        dst: PathBuf,
+        #[structopt(flatten)]
+        open: OpenOptions,
+    },
+    /// Write a checkpoint of a running server to a directory on its host
+    #[structopt(name="backup")]
+    Backup {
+        dest: String,
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+    /// Write every pair of a data directory or a running server
+    #[structopt(name="export")]
+    Export {
+        #[structopt(flatten)]
+        store: Store,
+        #[structopt(long = "format", default_value = "jsonl")]
+        format: Format,
+        /// file to write, standard output by default
+        #[structopt(short, long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Set every pair written by export in a data directory or a running server
+    #[structopt(name="import")]
+    Import {
+        #[structopt(flatten)]
+        store: Store,
+        #[structopt(long = "format", default_value = "jsonl")]
+        format: Format,
+        /// file to read, standard input by default
+        #[structopt(short, long = "input", parse(from_os_str))]
+        input: Option<PathBuf>,
+    },
+    /// Decode every record of a kvs directory and report what's in it
+    #[structopt(name="verify")]
+    Verify {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+    /// Write every live record of a kvs directory which can be read to a fresh generation
+    #[structopt(name="repair")]
+    Repair {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+    /// Print the records of a generation file
+    #[structopt(name="dump")]
+    Dump {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// text or json, a JSON object per record
+        #[structopt(long = "format", default_value = "text")]
+        format: DumpFormat,
+        /// print only the records of this key
+        #[structopt(long = "key")]
+        key: Option<String>,
+        /// print only the records starting at this offset or later
+        #[structopt(long = "from")]
+        from: Option<u64>,
+        /// print only the records starting before this offset
+        #[structopt(long = "to")]
+        to: Option<u64>,
+        /// characters of a value printed, 0 prints the whole value
+        #[structopt(long = "max-value", default_value = "40")]
+        max_value: usize,
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+    /// Print the replication state of a running server
+    #[structopt(name="status")]
+    Status {
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+    /// Print the state of a member of a Raft cluster
+    #[structopt(name="cluster")]
+    Cluster {
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+    /// Add a server started with --join to the cluster whose leader is at --addr
+    #[structopt(name="add-member")]
+    AddMember {
+        node: String,
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+    /// Remove a server from the cluster whose leader is at --addr
+    #[structopt(name="remove-member")]
+    RemoveMember {
+        node: String,
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+    /// Stop a running server, letting it finish the requests it got
+    #[structopt(name="shutdown")]
+    Shutdown {
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DumpFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(DumpFormat::Text),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err(format!("unknown dump format {}", s)),
+        }
+    }
+}
+
+// the encryption key is taken from here unless `--key-file` is given
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+/// Store is either a data directory or the address of a server
+#[derive(Debug, StructOpt)]
+struct Store {
+    #[structopt(short, long = "addr", conflicts_with = "dir", required_unless = "dir")]
+    addr: Option<String>,
+    #[structopt(long = "dir", parse(from_os_str))]
+    dir: Option<PathBuf>,
+    /// engine of the directory, needed only when it has no metadata yet
+    #[structopt(long = "engine")]
+    engine: Option<EngineKind>,
+    #[structopt(flatten)]
+    open: OpenOptions,
+}
+
+/// OpenOptions tell how a data directory is opened, the way its server opens it
+#[derive(Debug, StructOpt)]
+struct OpenOptions {
+    /// TOML file of settings of the server of the directory
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(long = "key-file", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let res = match opt.command {
+        Command::Migrate { from, to, src, dst, open } => engine_options(&open).and_then(|options| {
+            kvs::migrate_dir(from, &src, to, &dst, &options).map(|report| {
+                println!("migrated {} pairs, checksum {:016x}", report.pairs, report.checksum);
+            })
+        }),
+        Command::Backup { dest, addr } => admin(&addr, AdminCommand::Backup { dest }).map(drop),
+        Command::Export { store, format, output } => {
+            export(&store, format, output).map(|count| eprintln!("exported {} pairs", count))
+        }
+        Command::Import { store, format, input } => {
+            import(&store, format, input).map(|count| eprintln!("imported {} pairs", count))
+        }
+        Command::Verify { dir, key_file } => verify(&dir, key_file),
+        Command::Repair { dir, key_file } => repair(&dir, key_file),
+        Command::Dump { file, format, key, from, to, max_value, key_file } => {
+            dump(&file, format, key, from.unwrap_or(0)..to.unwrap_or(u64::MAX), max_value, key_file)
+        }
+        Command::Status { addr } => status(&addr),
+        Command::Cluster { addr } => cluster(&addr),
+        Command::AddMember { node, addr } => admin(&addr, AdminCommand::AddMember { node }).map(drop),
+        Command::RemoveMember { node, addr } => admin(&addr, AdminCommand::RemoveMember { node }).map(drop),
+        Command::Shutdown { addr } => admin(&addr, AdminCommand::Shutdown).map(drop),
+    };
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn export(store: &Store, format: Format, output: Option<PathBuf>) -> kvs::Result<u64> {
+    let output: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    match (&store.addr, &store.dir) {
+        (Some(addr), _) => kvs::export(RemotePairs::new(addr), output, format),
+        (None, Some(dir)) => {
+            if !dir.is_dir() {
+                return Err(KvsError::Incompatible(format!("{} is not a directory", dir.display())));
+            }
+            let (mut engine, _lock) = open_dir(dir, store)?;
+            let count = kvs::export(engine.pairs()?, output, format)?;
+            Ok(count)
+        }
+        (None, None) => unreachable!(),
+    }
+}
+
+fn import(store: &Store, format: Format, input: Option<PathBuf>) -> kvs::Result<u64> {
+    let input: Box<dyn Read> = match input {
+        Some(path) => Box::new(std::fs::File::open(path)?),
+        None => Box::new(std::io::stdin()),
+    };
+    let mut count = 0;
+    match (&store.addr, &store.dir) {
+        (Some(addr), _) => {
+            for pair in kvs::import(input, format) {
+                let (key, val) = pair?;
+                let mut socket = TcpStream::connect(addr)?;
+                socket.write_all(&construct_package(Package::Set(key.as_bytes(), val.as_bytes())))?;
+                reply(&mut socket)?;
+                count += 1;
+            }
+        }
+        (None, Some(dir)) => {
+            std::fs::create_dir_all(dir)?;
+            let (mut engine, _lock) = open_dir(dir, store)?;
+            for pair in kvs::import(input, format) {
+                let (key, val) = pair?;
+                engine.set(key, val)?;
+                count += 1;
+            }
+        }
+        (None, None) => unreachable!(),
+    }
+
+    Ok(count)
+}
+
+fn verify(dir: &Path, key_file: Option<PathBuf>) -> kvs::Result<()> {
+    let report = KvStore::verify(dir, &log_options(dir, key_file)?)?;
+    println!("base generation {}", report.base);
+    for info in &report.generations {
+        let key = match info.key_id {
+            0 => "plain".to_owned(),
+            id => format!("key {:016x}", id),
+        };
+        println!(
+            "generation {}: {} bytes, {} records, {}",
+            info.generation, info.size, info.records, key
+        );
+    }
+    println!(
+        "{} keys, {} live bytes, {} dead bytes ({:.1}% dead)",
+        report.keys,
+        report.live_bytes,
+        report.dead_bytes,
+        report.dead_ratio() * 100.0
+    );
+    for corruption in &report.corrupted {
+        println!(
+            "corrupted {} bytes {}..{}",
+            corruption.file.display(),
+            corruption.range.start,
+            corruption.range.end
+        );
+    }
+    for key in &report.lost {
+        println!("lost value of {}", key);
+    }
+
+    if !report.is_healthy() {
+        return Err(KvsError::Verification(format!("{} is corrupted", dir.display())));
+    }
+
+    Ok(())
+}
+
+fn repair(dir: &Path, key_file: Option<PathBuf>) -> kvs::Result<()> {
+    let report = KvStore::repair(dir, &log_options(dir, key_file)?)?;
+    for key in &report.lost {
+        println!("lost value of {}", key);
+    }
+    println!(
+        "salvaged {} keys into generation {}, replaced generations moved to {}",
+        report.keys,
+        report.generation,
+        report.moved_to.display()
+    );
+
+    Ok(())
+}
+
+fn dump(
+    file: &Path,
+    format: DumpFormat,
+    key: Option<String>,
+    offsets: std::ops::Range<u64>,
+    max_value: usize,
+    key_file: Option<PathBuf>,
+) -> kvs::Result<()> {
+    let log = LogFile::open(file, &key_options(key_file)?)?;
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let records = log
+        .records()
+        .filter(|record| offsets.contains(&record.offset))
+        .filter(|record| key.is_none() || record.entry.key() == key.as_deref());
+    for mut record in records {
+        if let LogEntry::Set { value, .. } = &mut record.entry {
+            if max_value > 0 && value.chars().count() > max_value {
+                *value = format!("{}...", value.chars().take(max_value).collect::<String>());
+            }
+        }
+
+        match format {
+            DumpFormat::Json => serde_json::to_writer(&mut out, &record)?,
+            DumpFormat::Text => {
+                write!(out, "{:>10} {:>8} ", record.offset, record.len)?;
+                match &record.entry {
+                    LogEntry::Set { key, value } => write!(out, "set {:?} {:?}", key, value)?,
+                    LogEntry::SetBlob { key, file, pos, len } => write!(
+                        out,
+                        "set-blob {:?} {}.blob at {} ({} bytes)",
+                        key, file, pos, len
+                    )?,
+                    LogEntry::Remove { key } => write!(out, "remove {:?}", key)?,
+                    LogEntry::Corrupted => write!(out, "corrupted")?,
+                }
+            }
+        }
+        writeln!(out)?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+fn status(addr: &str) -> kvs::Result<()> {
+    let status: ReplicationStatus = rmp_serde::decode::from_slice(&admin(addr, AdminCommand::Status)?)?;
+    match &status.leader {
+        Some(leader) => println!(
+            "follower of {} applied {} lag {}ms",
+            leader, status.applied, status.lag_ms
+        ),
+        None => println!("leader at {}", status.applied),
+    }
+    for follower in &status.followers {
+        println!(
+            "follower {} applied {} lag {}ms reported {}ms ago",
+            follower.addr, follower.applied, follower.lag_ms, follower.reported_ms
+        );
+    }
+
+    Ok(())
+}
+
+fn cluster(addr: &str) -> kvs::Result<()> {
+    let status: ClusterStatus = rmp_serde::decode::from_slice(&admin(addr, AdminCommand::Cluster)?)?;
+    println!("{} {} of term {}", status.id, status.role, status.term);
+    match &status.leader {
+        Some(leader) => println!("leader {}", leader),
+        None => println!("no leader"),
+    }
+    println!("commit {} applied {}", status.commit, status.applied);
+    println!("members {}", status.members.join(","));
+
+    Ok(())
+}
+
+/// Options to read the log of a directory kept in the kvs format
+fn log_options(dir: &Path, key_file: Option<PathBuf>) -> kvs::Result<KvStoreOptions> {
+    match Metadata::load(dir)? {
+        // memory snapshots are kept in the kvs format as well
+        Some(meta) if meta.engine != EngineKind::Kvs && meta.engine != EngineKind::Memory => {
+            let msg = format!("{} belongs to the {} engine", dir.display(), meta.engine);
+            return Err(KvsError::Incompatible(msg));
+        }
+        _ => {}
+    }
+
+    key_options(key_file)
+}
+
+/// Options holding the key of `key_file` or the one in the environment
+fn key_options(key_file: Option<PathBuf>) -> kvs::Result<KvStoreOptions> {
+    let encryption = match key_file {
+        Some(path) => Some(EncryptionKey::from_file(path)?),
+        None if std::env::var_os(KEY_ENV).is_some() => Some(EncryptionKey::from_env(KEY_ENV)?),
+        None => None,
+    };
+    Ok(KvStoreOptions {
+        encryption,
+        ..KvStoreOptions::default()
+    })
+}
+
+/// Options of the engines taken from the configuration file of the server,
+/// memory directories are read from and written to their snapshots
+fn engine_options(open: &OpenOptions) -> kvs::Result<EngineOptions> {
+    let config = match &open.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    let mut options = config.engine_options();
+    let key_file = open.key_file.clone().or(config.kvs.key_file);
+    options.kvs.encryption = key_options(key_file)?.encryption;
+    // a snapshot is taken when the store is dropped after a write
+    options.snapshot_interval = options.snapshot_interval.or(Some(Duration::MAX));
+    Ok(options)
+}
+
+/// Open the data directory of `store` with the engine it belongs to,
+/// it's locked until the returned lock is dropped
+fn open_dir(dir: &Path, store: &Store) -> kvs::Result<(Box<dyn KvsEngine>, DirLock)> {
+    let engine = match (Metadata::load(dir)?, store.engine) {
+        (_, Some(engine)) => engine,
+        (Some(meta), None) => meta.engine,
+        (None, None) => {
+            let msg = format!("{} has no metadata, pass --engine", dir.display());
+            return Err(KvsError::Incompatible(msg));
+        }
+    };
+    let options = engine_options(&store.open)?;
+    let (engine, _, lock) = Registry::new().open_dir(engine, dir, &options)?;
+    Ok((engine, lock))
+}
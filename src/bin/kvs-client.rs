@@ -1,9 +1,13 @@
 use structopt::StructOpt;
 use kvs::{
+    Change,
+    KeyVersion,
+    KeyWatch,
+    KvsError,
     Package, 
-    ok_package,
+    Subscription,
     construct_package,
-    deconstruct_package
+    reply,
 };
 use std::io::prelude::*;
 use std::net::TcpStream;
@@ -35,54 +39,123 @@ enum Command {
         #[structopt(short, long = "addr")]
         addr: String,
     },
+    /// Print changes of keys starting with `prefix` until interrupted
+    #[structopt(name="watch")]
+    Watch {
+        #[structopt(default_value = "")]
+        prefix: String,
+        /// print the changes made after this sequence number as well
+        #[structopt(long = "since")]
+        since: Option<u64>,
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
+    /// Wait until `key` gets a version newer than `since` and print it along with the value
+    #[structopt(name="wait")]
+    Wait {
+        key: String,
+        #[structopt(long = "since", default_value = "0")]
+        since: u64,
+        /// seconds to wait for a change
+        #[structopt(long = "timeout", default_value = "30")]
+        timeout: u64,
+        #[structopt(short, long = "addr")]
+        addr: String,
+    },
 }
 
 fn main() -> std::io::Result<()> {
     let opt = Opt::from_args();
 
-    let mut buffer = [0; 1024];
     match opt.command {
     Command::Get {key, addr} => {
         let mut socket = TcpStream::connect(addr.clone())?;
-        socket.write(&construct_package(Package::Get(key.as_bytes())))?;
-        socket.read(&mut buffer)?;
-        match deconstruct_package(&buffer) {
-            Package::OK(val) => {
-                if val.len() > 0 {
+        socket.write_all(&construct_package(Package::Get(key.as_bytes())))?;
+        match reply(&mut socket) {
+            Ok(val) => {
+                if !val.is_empty() {
                     // this is a problem should be refactored
-                    println!("{}", std::str::from_utf8(val).unwrap().trim_matches(char::from(0)));
+                    println!("{}", String::from_utf8_lossy(&val).trim_matches(char::from(0)));
                 } else {
                     println!("Key not found");
                 }
             },
-            Package::Error(e) => println!("{}", std::str::from_utf8(e).unwrap()),
-            _ => unreachable!(),
+            Err(KvsError::Io(err)) => return Err(err),
+            Err(err) => println!("{}", err),
         };
     },
     Command::Set {key, val, addr} => {
             let mut socket = TcpStream::connect(addr.clone())?;
-            socket.write(&construct_package(Package::Set(key.as_bytes(), val.as_bytes())))?;
-            socket.read(&mut buffer)?;
-            match deconstruct_package(&buffer) {
-                Package::OK(val) => (),
-                Package::Error(e) => println!("{}", std::str::from_utf8(e).unwrap()),
-                _ => unreachable!(),
+            socket.write_all(&construct_package(Package::Set(key.as_bytes(), val.as_bytes())))?;
+            match reply(&mut socket) {
+                Ok(_) => (),
+                Err(KvsError::Io(err)) => return Err(err),
+                Err(err) => println!("{}", err),
             };
     },
     Command::Remove {key, addr} => {
         let mut socket = TcpStream::connect(addr.clone())?;
-        socket.write(&construct_package(Package::Remove(key.as_bytes())))?;
-        socket.read(&mut buffer)?;
-        match deconstruct_package(&buffer) {
-            Package::OK(_) => (),
-            Package::Error(e) => {
-                eprintln!("{}", std::str::from_utf8(e).unwrap().trim_matches(char::from(0)));
+        socket.write_all(&construct_package(Package::Remove(key.as_bytes())))?;
+        match reply(&mut socket) {
+            Ok(_) => (),
+            Err(KvsError::Io(err)) => return Err(err),
+            Err(err) => {
+                eprintln!("{}", err);
                 std::process::exit(1);
             },
-            _ => unreachable!(),
         };
     },
-    _ => unreachable!(),
+    Command::Watch {prefix, since, addr} => {
+        let mut socket = TcpStream::connect(addr.clone())?;
+        let subscription = Subscription { prefix, since };
+        socket.write_all(&construct_package(Package::Subscribe(&subscription.encode().unwrap())))?;
+        loop {
+            let body = match reply(&mut socket) {
+                Ok(body) => body,
+                // the server is gone
+                Err(KvsError::Io(ref err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(KvsError::Io(err)) => return Err(err),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                },
+            };
+            // the subscription is acknowledged with an empty body
+            if body.is_empty() {
+                continue;
+            }
+            let change: Change = rmp_serde::decode::from_slice(&body).expect("cannot decode change");
+            match change.value {
+                // values set by servers which stored the padding of their buffer carry it
+                Some(val) => println!("{} set {} {}", change.seq, change.key, val.trim_matches(char::from(0))),
+                None => println!("{} rm {}", change.seq, change.key),
+            }
+            std::io::stdout().flush()?;
+        }
+    },
+    Command::Wait {key, since, timeout, addr} => {
+        let mut socket = TcpStream::connect(addr.clone())?;
+        let watch = KeyWatch { key, since, timeout_ms: timeout * 1000 };
+        socket.write_all(&construct_package(Package::Watch(&watch.encode().unwrap())))?;
+        match reply(&mut socket) {
+            Ok(body) => {
+                let reply: KeyVersion = rmp_serde::decode::from_slice(&body).expect("cannot decode version");
+                match reply {
+                    KeyVersion { changed: false, .. } => {
+                        eprintln!("no change after version {}", since);
+                        std::process::exit(1);
+                    },
+                    KeyVersion { version, value: Some(val), .. } => println!("{} {}", version, val),
+                    KeyVersion { version, value: None, .. } => println!("{} Key not found", version),
+                }
+            },
+            Err(KvsError::Io(err)) => return Err(err),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            },
+        }
+    },
     };
 
     Ok(())
@@ -0,0 +1,253 @@
+use structopt::StructOpt;
+use log::{info, warn, error};
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use kvs::{
+    AdminCommand,
+    KvsClient,
+    KvsError,
+    Package,
+    Ring,
+    ok_package,
+    construct_package,
+    deconstruct_package,
+    error_package,
+    read_package,
+};
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    #[structopt(short, long = "addr")]
+    address: String,
+    /// addresses of the kvs-servers keys are spread over, comma separated
+    #[structopt(long = "backends", raw(use_delimiter = "true", required = "true"))]
+    backends: Vec<String>,
+    /// requests sent to a backend at once, the others wait for one to finish
+    #[structopt(long = "max-in-flight", default_value = "16")]
+    max_in_flight: usize,
+    /// milliseconds between health checks of the backends
+    #[structopt(long = "health-interval", default_value = "1000")]
+    health_interval: u64,
+}
+
+// a backend which doesn't answer within it is taken as failed
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn main() -> std::io::Result<()> {
+    stderrlog::new().module(module_path!()).init().unwrap();
+    let opt = Opt::from_args();
+
+    error!("{} version={}, address={}, backends={}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), opt.address, opt.backends.join(","));
+
+    let proxy = Arc::new(Proxy::new(&opt.backends, opt.max_in_flight));
+    let checked = proxy.clone();
+    let interval = Duration::from_millis(opt.health_interval);
+    thread::spawn(move || loop {
+        checked.check_health();
+        thread::sleep(interval);
+    });
+
+    let listener = TcpListener::bind(&opt.address)?;
+    for conn in listener.incoming() {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("cannot accept a connection: {}", err);
+                continue;
+            }
+        };
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            if let Err(err) = proxy.handle(conn) {
+                info!("connection failed: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Proxy routes every key to the backend the ring maps it to.
+///
+/// The ring holds every backend, healthy or not, so a key is always routed
+/// to the backend keeping it: the keys of an ejected backend fail fast
+/// until a health check takes it back.
+struct Proxy {
+    ring: Ring,
+    backends: Vec<Backend>,
+}
+
+/// Backend is a kvs-server along with the connections made to it.
+///
+/// A kvs-server answers a single request per connection and serves one
+/// connection at a time, so connections aren't kept open or reused:
+/// every request takes a connection of its own, and the permits bound
+/// how many of them are sent to the backend at once.
+struct Backend {
+    client: KvsClient,
+    healthy: AtomicBool,
+    in_flight: Permits,
+}
+
+/// Permits hands out up to a number of permits, waiting for one to be returned when none is left
+struct Permits {
+    free: Mutex<usize>,
+    returned: Condvar,
+}
+
+struct Permit<'a>(&'a Permits);
+
+impl Permits {
+    fn new(size: usize) -> Self {
+        Permits {
+            free: Mutex::new(size.max(1)),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Take a permit, `None` when none was returned within `timeout`
+    fn take(&self, timeout: Duration) -> Option<Permit<'_>> {
+        let deadline = Instant::now() + timeout;
+        let mut free = self.free.lock().expect("GG: poisoned permits");
+        while *free == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            free = self.returned.wait_timeout(free, deadline - now).expect("GG: poisoned permits").0;
+        }
+        *free -= 1;
+        Some(Permit(self))
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().expect("GG: poisoned permits") += 1;
+        self.0.returned.notify_one();
+    }
+}
+
+impl Backend {
+    /// Send a request once a permit is taken,
+    /// a backend which fails to answer is ejected
+    fn request<T>(&self, f: impl FnOnce(&KvsClient) -> kvs::Result<T>) -> kvs::Result<T> {
+        if !self.healthy.load(Ordering::SeqCst) {
+            let msg = format!("backend {} is down", self.client.addr());
+            return Err(KvsError::Unsupported(msg));
+        }
+        let _permit = self.in_flight.take(BACKEND_TIMEOUT).ok_or_else(|| {
+            KvsError::Unsupported(format!("backend {} is busy", self.client.addr()))
+        })?;
+
+        let res = f(&self.client);
+        // an error package comes back as `Remote` or `KeyNotFound`: the backend
+        // is alive, it's the request which failed
+        if let Err(KvsError::Io(ref err)) = res {
+            warn!("eject backend {}: {}", self.client.addr(), err);
+            self.healthy.store(false, Ordering::SeqCst);
+        }
+        res
+    }
+}
+
+impl Proxy {
+    fn new(addrs: &[String], max_in_flight: usize) -> Self {
+        Proxy {
+            ring: Ring::new(addrs.iter().cloned()),
+            backends: addrs
+                .iter()
+                .map(|addr| Backend {
+                    client: KvsClient::new(addr.clone()).timeout(BACKEND_TIMEOUT),
+                    healthy: AtomicBool::new(true),
+                    in_flight: Permits::new(max_in_flight),
+                })
+                .collect(),
+        }
+    }
+
+    fn backend(&self, key: &str) -> &Backend {
+        let node = self.ring.node(key).expect("GG: a proxy without backends");
+        self.backends
+            .iter()
+            .find(|backend| backend.client.addr() == node)
+            .expect("GG: a node of the ring without a backend")
+    }
+
+    /// Ask every backend for its status, ejecting the ones which don't answer
+    /// and taking back the ones which answer again
+    fn check_health(&self) {
+        for backend in &self.backends {
+            let healthy = backend.client.admin(AdminCommand::Status).is_ok();
+            if healthy != backend.healthy.swap(healthy, Ordering::SeqCst) {
+                match healthy {
+                    true => info!("backend {} is back", backend.client.addr()),
+                    false => warn!("eject backend {}", backend.client.addr()),
+                }
+            }
+        }
+    }
+
+    /// Merge a page of every backend into a page of the whole keyspace
+    fn scan(&self, after: Option<String>, limit: u32) -> kvs::Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for backend in &self.backends {
+            let command = AdminCommand::Scan { after: after.clone(), limit };
+            let page: Vec<(String, String)> =
+                rmp_serde::decode::from_slice(&backend.request(|client| client.admin(command))?)?;
+            pairs.extend(page);
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit as usize);
+        Ok(pairs)
+    }
+
+    fn handle(&self, mut socket: TcpStream) -> kvs::Result<()> {
+        let buffer = read_package(&mut socket);
+        let res = buffer
+            .as_deref()
+            .map_err(|err| KvsError::Protocol(err.to_string()))
+            .and_then(deconstruct_package)
+            .and_then(|pkg| {
+                info!("I got {}", pkg);
+                match pkg {
+                    Package::Get(key) => {
+                        let key = String::from_utf8(key.to_vec())?;
+                        self.backend(&key)
+                            .request(|client| client.get(key.clone()))
+                            .map(|value| value.unwrap_or_default().into_bytes())
+                    }
+                    Package::Set(key, val) => {
+                        let key = String::from_utf8(key.to_vec())?;
+                        let value = String::from_utf8(val.to_vec())?;
+                        self.backend(&key).request(|client| client.set(key.clone(), value)).map(|()| Vec::new())
+                    }
+                    Package::Remove(key) => {
+                        let key = String::from_utf8(key.to_vec())?;
+                        self.backend(&key).request(|client| client.remove(key.clone())).map(|()| Vec::new())
+                    }
+                    Package::Admin(body) => match AdminCommand::decode(body)? {
+                        AdminCommand::Scan { after, limit } => self
+                            .scan(after, limit)
+                            .and_then(|page| Ok(rmp_serde::encode::to_vec(&page)?)),
+                        command => Err(KvsError::Unsupported(format!("{:?} through kvs-proxy", command))),
+                    },
+                    _ => Err(KvsError::Unsupported("only get, set, rm and scans go through kvs-proxy".to_owned())),
+                }
+            });
+
+        match res {
+            Ok(body) if body.is_empty() => socket.write_all(&construct_package(ok_package()))?,
+            Ok(body) => socket.write_all(&construct_package(Package::OK(&body)))?,
+            Err(err) => {
+                warn!("send error {}", err);
+                socket.write_all(&error_package(&err))?
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,131 +1,1071 @@
 use structopt::StructOpt;
 use log::{info, warn, error};
+use std::collections::{HashMap, HashSet};
 use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
 use std::io::{
     Result,
     prelude::*,
 };
 use kvs::{
-    KvStore,
+    AdminCommand,
+    ClusterStatus,
+    Committed,
+    Compression,
+    EncryptionKey,
+    EngineKind,
+    EngineOptions,
+    FollowerStatus,
+    IndexMode,
+    KeyVersion,
+    KeyWatch,
     KvsEngine,
-    SledStorage,
+    KvsError,
+    Message,
+    Metadata,
+    Node,
+    Registry,
     Package, 
+    Proposal,
+    Replica,
+    Replicated,
+    ReplicationStatus,
+    ServerConfig,
+    Subscription,
+    Transport,
     ok_package,
     construct_package,
-    deconstruct_package
+    deconstruct_package,
+    error_package,
+    read_package,
 };
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+    /// address to listen at, 127.0.0.1:4000 by default
     #[structopt(short, long = "addr")]
-    address: String,
+    address: Option<String>,
+    /// engine of a new data directory, the one of its metadata is used otherwise
     #[structopt(short = "e", long = "engine")]
-    engine: String,
+    engine: Option<EngineKind>,
+    /// directory the store is kept in, the working directory by default
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// TOML file of settings, the flags given here override it
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(long = "compression")]
+    compression: Option<Compression>,
+    #[structopt(long = "key-file", parse(from_os_str))]
+    key_file: Option<std::path::PathBuf>,
+    #[structopt(long = "index")]
+    index: Option<IndexMode>,
+    #[structopt(long = "snapshot-interval")]
+    snapshot_interval: Option<u64>,
+    /// subscribers and watches held at once
+    #[structopt(long = "pool-size")]
+    pool_size: Option<usize>,
+    /// one of off, error, warn, info, debug and trace
+    #[structopt(long = "log-level")]
+    log_level: Option<log::LevelFilter>,
+    /// sync the store to disk after every write before it's answered
+    #[structopt(long = "sync-writes")]
+    sync_writes: bool,
+    /// follow the server at this address serving reads only
+    #[structopt(long = "replica-of")]
+    replica_of: Option<String>,
+    /// addresses of the servers of a Raft cluster this one is a member of,
+    /// comma separated and the address of this server among them
+    #[structopt(long = "cluster", conflicts_with = "replica_of", raw(use_delimiter = "true"))]
+    cluster: Vec<String>,
+    /// start as a server waiting to be added to a running cluster
+    #[structopt(long = "join", conflicts_with = "replica_of", conflicts_with = "cluster")]
+    join: bool,
+}
+
+// the encryption key is taken from here unless `--key-file` is given
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+// subscribers and watches held at once unless configured otherwise
+const POOL_SIZE: usize = 1024;
+
+// how long a stopping server waits for the requests it took to be answered
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings the server runs with besides the options of its engine
+struct Settings {
+    /// subscribers and watches held at once, a client past them is refused
+    pool_size: usize,
+    /// a key watch never waits longer than this
+    max_watch_timeout: Duration,
+    /// a subscriber which doesn't take a change for this long is dropped
+    subscriber_timeout: Duration,
+    /// how long a stopping server waits for the requests it took
+    shutdown_timeout: Duration,
+    /// sync the store after every write of a client before it's answered
+    sync_writes: bool,
+    /// the store is synced this often, which snapshots an idle memory engine
+    sync_interval: Option<Duration>,
+    /// set once the server is asked to stop
+    shutdown: Arc<AtomicBool>,
 }
 
 fn main() -> Result<()> {
-    stderrlog::new().module(module_path!()).init().unwrap();
     let opt = Opt::from_args();
+    let mut config = match &opt.config {
+        Some(path) => match ServerConfig::load(path) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => ServerConfig::default(),
+    };
+
+    // flags given on the command line take the place of the settings of the file
+    let level = opt.log_level.or(config.logging.level).unwrap_or(log::LevelFilter::Error);
+    stderrlog::new()
+        .module(module_path!())
+        .quiet(level == log::LevelFilter::Off)
+        .verbosity((level as usize).saturating_sub(1))
+        .init()
+        .unwrap();
+
+    let address = opt.address.or_else(|| config.addr.clone()).unwrap_or_else(|| DEFAULT_ADDR.to_owned());
+    let dir = match opt.data_dir.or_else(|| config.data_dir.clone()) {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir)?;
+            dir
+        }
+        None => std::env::current_dir()?,
+    };
+    let kind = match opt.engine.or(config.engine) {
+        Some(engine) => engine,
+        // a directory an engine owns already is opened with it
+        None => match Metadata::load(&dir) {
+            Ok(meta) => meta.map_or(EngineKind::Kvs, |meta| meta.engine),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+    };
 
-    error!("{} version={}, address={}, engine={}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), opt.address, opt.engine);
+    error!("{} version={}, address={}, engine={}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), address, kind);
 
-    if let Some(old_engine) = current_engine(std::env::current_dir()?){
-        if old_engine != opt.engine {
-            eprintln!("the storage was configured already using another engine");
+    let addr = address.parse::<std::net::SocketAddr>().expect("cannot parse socket address");
+    let encryption = match opt.key_file.or_else(|| config.kvs.key_file.clone()) {
+        Some(path) => Some(EncryptionKey::from_file(&path).expect("cannot read encryption key")),
+        None if std::env::var_os(KEY_ENV).is_some() => {
+            Some(EncryptionKey::from_env(KEY_ENV).expect("cannot read encryption key"))
+        }
+        None => None,
+    };
+    config.kvs.compression = opt.compression.or(config.kvs.compression);
+    config.kvs.index = opt.index.or(config.kvs.index);
+    config.memory.snapshot_interval = opt.snapshot_interval.or(config.memory.snapshot_interval);
+    let mut options = config.engine_options();
+    options.kvs.encryption = encryption;
+    let limits = &config.limits;
+    let settings = Settings {
+        pool_size: opt.pool_size.or(limits.pool_size).unwrap_or(POOL_SIZE),
+        max_watch_timeout: limits.max_watch_timeout.map_or(MAX_WATCH_TIMEOUT, Duration::from_millis),
+        subscriber_timeout: limits.subscriber_timeout.map_or(SUBSCRIBER_TIMEOUT, Duration::from_millis),
+        shutdown_timeout: limits.shutdown_timeout.map_or(SHUTDOWN_TIMEOUT, Duration::from_millis),
+        sync_writes: opt.sync_writes || config.durability.sync_writes.unwrap_or(false),
+        sync_interval: options.snapshot_interval,
+        shutdown: Arc::new(AtomicBool::new(false)),
+    };
+
+    let (engine, meta, lock) = match Registry::new().open_dir(kind, &dir, &options) {
+        Ok(opened) => opened,
+        Err(err) => {
+            eprintln!("cannot open {}: {}", dir.display(), err);
             std::process::exit(1);
         }
-    } else {
-        pin_engine(std::env::current_dir()?, opt.engine.clone())?;
+    };
+    let mut replication = Replication::new();
+    if let Some(leader) = opt.replica_of {
+        let (sender, receiver) = mpsc::sync_channel(REPLICATION_BATCH);
+        let replica = match kvs::follow(leader, address.clone(), sender) {
+            Ok(replica) => replica,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        };
+        replication.replica = Some((replica, receiver));
+    }
+    if !opt.cluster.is_empty() || opt.join {
+        if !opt.join && !opt.cluster.contains(&address) {
+            eprintln!("{} is not one of the cluster {}", address, opt.cluster.join(","));
+            std::process::exit(1);
+        }
+        let cluster = Cluster::open(address.clone(), dir.join(RAFT_DIR), opt.cluster, kind, options)
+            .expect("cannot open the raft log");
+        replication.cluster = Some(cluster);
     }
 
-    let addr = opt.address.parse::<std::net::SocketAddr>().expect("cannot parse socket address");
-    if opt.engine == "kvs" {
-        run(KvStore::open(std::env::current_dir()?).expect("cannot open kvs store"), addr)?;
-    } else if opt.engine == "sled" {
-        run(SledStorage::open(std::env::current_dir()?).expect("cannot open sled storage"), addr)?;
-    } else {
-        error!("wrong engine");
-        std::process::exit(1);
-    };
+    // SIGINT and SIGTERM stop the server the way a shutdown command does
+    let signaled = settings.shutdown.clone();
+    ctrlc::set_handler(move || signaled.store(true, Ordering::SeqCst)).expect("cannot handle signals");
+
+    run(engine, addr, &meta, replication, &settings)?;
+    drop(lock);
+    error!("stopped");
+
+    Ok(())
+}
+
+// a subscriber which doesn't take a change for this long is dropped unless configured otherwise
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Subscriber is a client the changes are streamed to
+struct Subscriber {
+    socket: TcpStream,
+    prefix: String,
+    /// sequence number of the last change looked at
+    seq: u64,
+}
+
+// a key watch never waits longer than this unless configured otherwise
+const MAX_WATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how often expired watches are looked for while no client connects
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Watches keeps the versions of keys along with the clients waiting for them to change.
+///
+/// The clock starts at the last version the engine handed out, so with engines
+/// which keep versions it goes on where the previous server left it. Keys which
+/// didn't change since the server started have the version it started at.
+struct Watches {
+    clock: u64,
+    /// version of the keys which didn't change since the server started
+    start: u64,
+    versions: HashMap<String, u64>,
+    waiters: HashMap<String, Vec<Waiter>>,
+}
+
+struct Waiter {
+    socket: TcpStream,
+    deadline: Instant,
+}
+
+impl Watches {
+    fn new(start: u64) -> Self {
+        Watches {
+            clock: start,
+            start,
+            versions: HashMap::new(),
+            waiters: HashMap::new(),
+        }
+    }
+
+    fn version(&self, key: &str) -> u64 {
+        self.versions.get(key).cloned().unwrap_or(self.start)
+    }
+
+    /// Bump the version of `key` waking up the clients which wait for it
+    fn changed(&mut self, key: &str, value: Option<&str>) {
+        self.clock += 1;
+        self.versions.insert(key.to_owned(), self.clock);
+
+        let reply = KeyVersion {
+            version: self.clock,
+            value: value.map(|value| value.trim_matches(char::from(0)).to_owned()),
+            changed: true,
+        };
+        for waiter in self.waiters.remove(key).unwrap_or_default() {
+            if let Err(err) = send_version(waiter.socket, &reply) {
+                info!("drop watch of {}: {}", key, err);
+            }
+        }
+    }
+
+    fn wait(&mut self, key: String, socket: TcpStream, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.waiters.entry(key).or_default().push(Waiter { socket, deadline });
+    }
+
+    /// Number of clients waiting on keys
+    fn len(&self) -> usize {
+        self.waiters.values().map(Vec::len).sum()
+    }
+
+    /// Reply to the watches whose deadline passed with the version they wait on
+    fn expire(&mut self, now: Instant) {
+        let (versions, start) = (&self.versions, self.start);
+        self.waiters.retain(|key, waiters| {
+            let (expired, waiting): (Vec<_>, Vec<_>) = waiters.drain(..).partition(|waiter| waiter.deadline <= now);
+            for waiter in expired {
+                let reply = KeyVersion {
+                    version: versions.get(key).cloned().unwrap_or(start),
+                    value: None,
+                    changed: false,
+                };
+                if let Err(err) = send_version(waiter.socket, &reply) {
+                    info!("drop watch of {}: {}", key, err);
+                }
+            }
+            *waiters = waiting;
+            !waiters.is_empty()
+        });
+    }
+}
+
+// replicated records applied at once before clients are served again
+const REPLICATION_BATCH: usize = 1024;
+
+/// Replication is the part the server takes in the replication
+struct Replication {
+    /// the leader whose changes a follower applies
+    replica: Option<(Arc<Replica>, Receiver<Replicated>)>,
+    /// keys of the snapshot being applied
+    snapshot: Option<HashSet<String>>,
+    /// followers which reported to a leader
+    followers: HashMap<String, Report>,
+    /// the member of a Raft cluster the server is
+    cluster: Option<Cluster>,
+}
+
+/// Report is what a follower told its leader last
+struct Report {
+    applied: u64,
+    lag_ms: u64,
+    at: Instant,
+}
+
+impl Replication {
+    fn new() -> Self {
+        Replication {
+            replica: None,
+            snapshot: None,
+            followers: HashMap::new(),
+            cluster: None,
+        }
+    }
+
+    /// Apply what was received from the leader, returning whether anything was
+    fn apply<E: KvsEngine>(&mut self, kvs: &mut E, watches: &mut Watches) -> kvs::Result<bool> {
+        let (replica, receiver) = match &self.replica {
+            Some(replica) => replica,
+            None => return Ok(false),
+        };
+
+        let mut applied = false;
+        for _ in 0..REPLICATION_BATCH {
+            let replicated = match receiver.try_recv() {
+                Ok(replicated) => replicated,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            };
+            applied = true;
+            match replicated {
+                Replicated::Snapshot => self.snapshot = Some(HashSet::new()),
+                Replicated::Pair(key, value) => {
+                    kvs.set(key.clone(), value.clone())?;
+                    watches.changed(&key, Some(&value));
+                    if let Some(snapshot) = &mut self.snapshot {
+                        snapshot.insert(key);
+                    }
+                }
+                Replicated::Synced(seq) => {
+                    let snapshot = self.snapshot.take().unwrap_or_default();
+                    let mut gone = Vec::new();
+                    for pair in kvs.pairs()? {
+                        let (key, _) = pair?;
+                        if !snapshot.contains(&key) {
+                            gone.push(key);
+                        }
+                    }
+                    for key in gone {
+                        kvs.remove(key.clone())?;
+                        watches.changed(&key, None);
+                    }
+                    replica.apply(seq);
+                    info!("synced with {} at {}", replica.leader(), seq);
+                }
+                Replicated::Change(change) => {
+                    match change.value {
+                        Some(value) => {
+                            kvs.set(change.key.clone(), value.clone())?;
+                            watches.changed(&change.key, Some(&value));
+                        }
+                        // the key can be gone already when the change is applied twice
+                        None => match kvs.remove(change.key.clone()) {
+                            Ok(()) | Err(kvs::KvsError::KeyNotFound) => watches.changed(&change.key, None),
+                            Err(err) => return Err(err),
+                        },
+                    }
+                    replica.apply(change.seq);
+                }
+            }
+        }
+
+        Ok(applied)
+    }
+
+    fn status<E: KvsEngine>(&self, kvs: &mut E) -> ReplicationStatus {
+        let followers = self
+            .followers
+            .iter()
+            .map(|(addr, report)| FollowerStatus {
+                addr: addr.clone(),
+                applied: report.applied,
+                lag_ms: report.lag_ms,
+                reported_ms: report.at.elapsed().as_millis() as u64,
+            })
+            .collect();
+
+        match &self.replica {
+            Some((replica, _)) => ReplicationStatus {
+                leader: Some(replica.leader().to_owned()),
+                applied: replica.applied(),
+                lag_ms: replica.lag().as_millis() as u64,
+                followers,
+            },
+            None => ReplicationStatus {
+                leader: None,
+                applied: kvs.seq().unwrap_or(0),
+                lag_ms: 0,
+                followers,
+            },
+        }
+    }
+}
+
+// the Raft state of a server in cluster mode is kept in this directory of its data
+const RAFT_DIR: &str = "raft";
+
+// the checkpoint of the store the latest snapshot of the log stands for
+const SNAPSHOT_DIR: &str = "snapshot";
+
+// the log is folded into a checkpoint once it holds more entries than this
+const RAFT_LOG_LIMIT: usize = 1000;
+
+// a client waits this long for its request to go through the cluster
+const CLUSTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cluster is the part the server takes in a Raft cluster.
+///
+/// Writes are answered once they are committed and applied,
+/// reads are served by the leader while its lease holds.
+struct Cluster {
+    node: Node,
+    transport: Transport,
+    dir: PathBuf,
+    engine: EngineKind,
+    options: EngineOptions,
+    /// clients waiting for the entry at an index to be applied
+    writes: HashMap<u64, Pending>,
+    /// clients waiting for the leader to serve reads, along with their keys
+    reads: Vec<(Pending, String)>,
+}
+
+/// Pending is a client waiting for the cluster
+struct Pending {
+    socket: TcpStream,
+    /// term of the leader which took the request
+    term: u64,
+    deadline: Instant,
+}
+
+impl Cluster {
+    fn open(id: String, dir: PathBuf, members: Vec<String>, engine: EngineKind, options: EngineOptions) -> kvs::Result<Self> {
+        let node = Node::open(id, dir.clone(), members)?;
+        info!("member {} of {:?} at term {}", node.id(), node.members(), node.term());
+        Ok(Cluster {
+            node,
+            transport: Transport::new(),
+            dir,
+            engine,
+            options,
+            writes: HashMap::new(),
+            reads: Vec::new(),
+        })
+    }
+
+    fn status(&self) -> ClusterStatus {
+        ClusterStatus {
+            id: self.node.id().to_owned(),
+            role: self.node.role().to_string(),
+            term: self.node.term(),
+            leader: self.node.leader().map(str::to_owned),
+            commit: self.node.commit(),
+            applied: self.node.applied(),
+            members: self.node.members().to_vec(),
+        }
+    }
+
+    /// Take a message of another member
+    fn step(&mut self, body: &[u8]) -> kvs::Result<()> {
+        self.node.step(Message::decode(body)?, Instant::now())?;
+        self.flush();
+        Ok(())
+    }
+
+    /// Propose a write on behalf of the client at `socket`
+    fn propose(&mut self, mut socket: TcpStream, proposal: Proposal) -> Result<()> {
+        let now = Instant::now();
+        match self.node.propose(proposal, now) {
+            Ok(index) => {
+                let pending = Pending { socket, term: self.node.term(), deadline: now + CLUSTER_TIMEOUT };
+                self.writes.insert(index, pending);
+                self.flush();
+            }
+            Err(err) => send_error(&mut socket, &err)?,
+        }
+        Ok(())
+    }
+
+    /// Add a member to the cluster or remove one
+    fn change_members(&mut self, socket: TcpStream, node: String, add: bool) -> Result<()> {
+        let mut members = self.node.members().to_vec();
+        members.retain(|member| *member != node);
+        if add {
+            members.push(node);
+        }
+        self.propose(socket, Proposal::Members(members))
+    }
+
+    /// Read `key` for the client at `socket` once the leader may serve it
+    fn read<E: KvsEngine>(&mut self, mut socket: TcpStream, kvs: &mut E, key: String) -> Result<()> {
+        let now = Instant::now();
+        if self.node.can_read(now) {
+            return send_value(&mut socket, kvs, &key);
+        }
+        match self.node.role() {
+            kvs::Role::Leader => {
+                let pending = Pending { socket, term: self.node.term(), deadline: now + CLUSTER_TIMEOUT };
+                self.reads.push((pending, key));
+            }
+            _ => send_error(&mut socket, &self.node.not_leader())?,
+        }
+        Ok(())
+    }
+
+    /// Drive the node: send its messages, apply what it committed and answer the clients,
+    /// returning whether anything was applied
+    fn poll<E: KvsEngine>(&mut self, kvs: &mut E, watches: &mut Watches) -> kvs::Result<bool> {
+        let now = Instant::now();
+        self.node.tick(now)?;
+        for follower in self.node.lagging() {
+            match self.load_snapshot() {
+                Ok((meta, pairs)) => self.node.send_snapshot(&follower, meta, pairs, now),
+                Err(err) => error!("cannot load the snapshot for {}: {}", follower, err),
+            }
+        }
+        self.flush();
+
+        let committed = self.node.committed();
+        let applied = !committed.is_empty();
+        for committed in committed {
+            match committed {
+                Committed::Entry(entry) => {
+                    let res = apply(kvs, watches, entry.proposal);
+                    if let Some(mut pending) = self.writes.remove(&entry.index) {
+                        let res = match pending.term == entry.term {
+                            true => res,
+                            false => Err(KvsError::NotLeader("the write was overwritten by another leader".to_owned())),
+                        };
+                        let sent = match res {
+                            Ok(()) => pending.socket.write_all(&construct_package(ok_package())),
+                            Err(err) => send_error(&mut pending.socket, &err),
+                        };
+                        if let Err(err) = sent {
+                            info!("cannot answer a write: {}", err);
+                        }
+                    }
+                }
+                Committed::Snapshot(pairs) => {
+                    replace(kvs, watches, pairs)?;
+                    self.checkpoint(kvs)?;
+                }
+            }
+        }
 
+        // writes folded into a snapshot or stuck without a leader have an unknown outcome
+        let (node, applied_index) = (&self.node, self.node.applied());
+        self.writes.retain(|&index, pending| {
+            if index > applied_index && pending.deadline > now {
+                return true;
+            }
+            if let Err(err) = send_error(&mut pending.socket, &node.not_leader()) {
+                info!("cannot answer a write: {}", err);
+            }
+            false
+        });
+
+        let can_read = self.node.can_read(now);
+        let term = self.node.term();
+        for (mut pending, key) in std::mem::take(&mut self.reads) {
+            let sent = if can_read && pending.term == term {
+                send_value(&mut pending.socket, kvs, &key)
+            } else if pending.deadline <= now || pending.term != term {
+                send_error(&mut pending.socket, &self.node.not_leader())
+            } else {
+                self.reads.push((pending, key));
+                continue;
+            };
+            if let Err(err) = sent {
+                info!("cannot answer a read: {}", err);
+            }
+        }
+
+        if self.node.log_len() > RAFT_LOG_LIMIT {
+            self.checkpoint(kvs)?;
+            self.node.compact(self.node.applied())?;
+        }
+        Ok(applied)
+    }
+
+    /// Whether clients still wait for the cluster
+    fn busy(&self) -> bool {
+        !self.writes.is_empty() || !self.reads.is_empty()
+    }
+
+    /// Answer every client still waiting, the outcome of its request is unknown
+    fn abort(&mut self) {
+        let writes = self.writes.drain().map(|(_, pending)| pending);
+        let reads = self.reads.drain(..).map(|(pending, _)| pending);
+        for mut pending in writes.chain(reads) {
+            if let Err(err) = send_error(&mut pending.socket, &KvsError::ShuttingDown) {
+                info!("cannot answer a request: {}", err);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for (to, msg) in self.node.outbox() {
+            if let Err(err) = self.transport.send(&to, &msg) {
+                warn!("cannot send a message to {}: {}", to, err);
+            }
+        }
+    }
+
+    /// Replace the checkpoint of the snapshot by one of the store as it is
+    fn checkpoint<E: KvsEngine>(&self, kvs: &mut E) -> kvs::Result<()> {
+        let dest = self.dir.join(SNAPSHOT_DIR);
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_DIR));
+        if tmp.exists() {
+            std::fs::remove_dir_all(&tmp)?;
+        }
+        kvs.checkpoint(&tmp)?;
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest)?;
+        }
+        std::fs::rename(&tmp, &dest)?;
+        Ok(())
+    }
+
+    /// Read the pairs of the checkpoint of the snapshot.
+    ///
+    /// An engine writes to the directory it opens, and the files of a checkpoint
+    /// may be linked to the ones of the store, so a copy of it is opened.
+    fn load_snapshot(&self) -> kvs::Result<(kvs::SnapshotMeta, Vec<(String, String)>)> {
+        let copy = self.dir.join(format!("{}.read", SNAPSHOT_DIR));
+        if copy.exists() {
+            std::fs::remove_dir_all(&copy)?;
+        }
+        kvs::copy_dir(&self.dir.join(SNAPSHOT_DIR), &copy)?;
+        let pairs = {
+            let options = EngineOptions { snapshot_interval: None, ..self.options.clone() };
+            Registry::new()
+                .open(self.engine.name(), &copy, &options)
+                .and_then(|mut snapshot| snapshot.pairs()?.collect::<kvs::Result<Vec<_>>>())
+        };
+        std::fs::remove_dir_all(&copy)?;
+        Ok((self.node.snapshot().clone(), pairs?))
+    }
+}
+
+/// Apply a committed entry to the store
+fn apply<E: KvsEngine>(kvs: &mut E, watches: &mut Watches, proposal: Proposal) -> kvs::Result<()> {
+    match proposal {
+        Proposal::Set { key, value } => {
+            kvs.set(key.clone(), value.clone())?;
+            watches.changed(&key, Some(&value));
+        }
+        Proposal::Remove { key } => {
+            kvs.remove(key.clone())?;
+            watches.changed(&key, None);
+        }
+        Proposal::Noop | Proposal::Members(_) => (),
+    }
+    Ok(())
+}
+
+/// Make the pairs of a snapshot the only ones of the store
+fn replace<E: KvsEngine>(kvs: &mut E, watches: &mut Watches, pairs: Vec<(String, String)>) -> kvs::Result<()> {
+    let keys: HashSet<&String> = pairs.iter().map(|(key, _)| key).collect();
+    let mut gone = Vec::new();
+    for pair in kvs.pairs()? {
+        let (key, _) = pair?;
+        if !keys.contains(&key) {
+            gone.push(key);
+        }
+    }
+    for key in gone {
+        kvs.remove(key.clone())?;
+        watches.changed(&key, None);
+    }
+    for (key, value) in pairs {
+        kvs.set(key.clone(), value.clone())?;
+        watches.changed(&key, Some(&value));
+    }
+    Ok(())
+}
+
+fn send_error(socket: &mut TcpStream, err: &KvsError) -> Result<()> {
+    socket.write_all(&error_package(err))?;
+    warn!("send error {}", err);
+    Ok(())
+}
+
+fn send_value<E: KvsEngine>(socket: &mut TcpStream, kvs: &mut E, key: &str) -> Result<()> {
+    match kvs.get(key.to_owned()) {
+        Ok(Some(val)) => {
+            // what is happening with size of the value?
+            socket.write_all(&construct_package(Package::OK(val.trim_matches(char::from(0)).as_ref())))?;
+            info!("send OK {}", val);
+        },
+        Ok(None) => {
+            socket.write_all(&construct_package(ok_package()))?;
+            info!("send ok with none");
+        },
+        Err(err) => send_error(socket, &err)?,
+    }
     Ok(())
 }
 
-fn pin_engine(path: std::path::PathBuf, engine_name: String) -> Result<()> {
-    let mut f = std::fs::File::create(path.join("engine"))?;
-    f.write(engine_name.as_ref())?;
-    f.flush()
+/// Sync the store after a write of a client when the settings ask for it
+fn synced<E: KvsEngine>(kvs: &mut E, settings: &Settings) -> kvs::Result<()> {
+    match settings.sync_writes {
+        true => kvs.sync(),
+        false => Ok(()),
+    }
 }
 
-fn current_engine(path: std::path::PathBuf) -> Option<String> {
-    let p = path.join("engine");
-    let engine_name = std::fs::read(p);
-    if engine_name.is_err() {
-        return None;
+/// Check the server may hold one more client, given it holds `held` already
+fn hold(held: usize, settings: &Settings) -> kvs::Result<()> {
+    match held < settings.pool_size {
+        true => Ok(()),
+        false => Err(KvsError::Busy(held)),
     }
+}
 
-    Some(String::from_utf8(engine_name.unwrap()).unwrap())
+fn send_version(mut socket: TcpStream, version: &KeyVersion) -> kvs::Result<()> {
+    let body = rmp_serde::encode::to_vec(version)?;
+    socket.write_all(&construct_package(Package::OK(&body)))?;
+    Ok(())
 }
 
-fn run<E: KvsEngine>(mut engine: E, addr: std::net::SocketAddr) -> Result<()> {
+/// Serve clients until the server is asked to stop, then answer the requests
+/// which were taken already and write the store through to disk
+fn run<E: KvsEngine>(mut engine: E, addr: std::net::SocketAddr, meta: &Metadata, mut replication: Replication, settings: &Settings) -> Result<()> {
     let listener = TcpListener::bind(addr)?;
-    for stream in listener.incoming() {
-        let mut conn = stream?;
-        info!("got connection to socket {}",  conn.peer_addr()?);
-        
-        handle(conn, &mut engine)?;
-    };
+    // connections are polled for so that watches expire while the server is idle
+    listener.set_nonblocking(true)?;
+    let mut subscribers = Vec::new();
+    // engines which keep no versions start counting from 0
+    let mut watches = Watches::new(engine.version().unwrap_or(0));
+    let mut synced_at = Instant::now();
+    while !settings.shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((conn, peer)) => {
+                conn.set_nonblocking(false)?;
+                info!("got connection to socket {}", peer);
+
+                let held = subscribers.len() + watches.len();
+                match handle(conn, &mut engine, meta, &mut watches, &mut replication, settings, held) {
+                    Ok(Some(subscriber)) => subscribers.push(subscriber),
+                    Ok(None) => (),
+                    // the client is gone, the server isn't
+                    Err(err) => warn!("cannot serve {}: {}", peer, err),
+                }
+                publish(&mut engine, &mut subscribers);
+            }
+            Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(err) => return Err(err),
+        }
+        match replication.apply(&mut engine, &mut watches) {
+            Ok(true) => publish(&mut engine, &mut subscribers),
+            Ok(false) => (),
+            Err(err) => error!("cannot apply a change of the leader: {}", err),
+        }
+        if let Some(cluster) = &mut replication.cluster {
+            match cluster.poll(&mut engine, &mut watches) {
+                Ok(true) => publish(&mut engine, &mut subscribers),
+                Ok(false) => (),
+                Err(err) => error!("cannot apply the raft log: {}", err),
+            }
+        }
+        watches.expire(Instant::now());
+        if settings.sync_interval.is_some_and(|interval| synced_at.elapsed() >= interval) {
+            if let Err(err) = engine.sync() {
+                error!("cannot sync the store: {}", err);
+            }
+            synced_at = Instant::now();
+        }
+    }
+
+    drop(listener);
+    error!("shutting down");
+    let deadline = Instant::now() + settings.shutdown_timeout;
+    if let Some(cluster) = &mut replication.cluster {
+        while cluster.busy() && Instant::now() < deadline {
+            if let Err(err) = cluster.poll(&mut engine, &mut watches) {
+                error!("cannot apply the raft log: {}", err);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        cluster.abort();
+    }
+    publish(&mut engine, &mut subscribers);
+    // clients waiting on keys are answered as if their watches expired
+    watches.expire(Instant::now() + settings.max_watch_timeout);
+
+    engine
+        .sync()
+        .map_err(|err| std::io::Error::other(err.to_string()))
+}
+
+/// Send every subscriber the changes it wasn't sent yet,
+/// the ones which disconnected are dropped
+fn publish<E: KvsEngine>(kvs: &mut E, subscribers: &mut Vec<Subscriber>) {
+    subscribers.retain_mut(|subscriber| match send_changes(kvs, subscriber) {
+        Ok(()) => true,
+        Err(err) => {
+            info!("drop subscriber: {}", err);
+            // one which fell behind a compaction may start over
+            let _ = subscriber.socket.write_all(&error_package(&err));
+            false
+        }
+    });
+}
+
+fn send_changes<E: KvsEngine>(kvs: &mut E, subscriber: &mut Subscriber) -> kvs::Result<()> {
+    for change in kvs.changes(subscriber.seq)? {
+        let change = change?;
+        subscriber.seq = change.seq;
+        if change.key.starts_with(&subscriber.prefix) {
+            let body = rmp_serde::encode::to_vec(&change)?;
+            subscriber.socket.write_all(&construct_package(Package::OK(&body)))?;
+        }
+    }
 
     Ok(())
 }
 
+/// Request is a package of a client with its keys, values and commands decoded
+enum Request<'a> {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+    Admin(AdminCommand),
+    Subscribe(Subscription),
+    Watch(KeyWatch),
+    Raft(&'a [u8]),
+}
+
+impl<'a> Request<'a> {
+    fn decode(pkg: Package<'a>) -> kvs::Result<Self> {
+        Ok(match pkg {
+            Package::Get(key) => Request::Get(text(key)?),
+            Package::Set(key, val) => Request::Set(text(key)?, text(val)?),
+            Package::Remove(key) => Request::Remove(text(key)?),
+            Package::Admin(body) => Request::Admin(AdminCommand::decode(body)?),
+            Package::Subscribe(body) => Request::Subscribe(Subscription::decode(body)?),
+            Package::Watch(body) => Request::Watch(KeyWatch::decode(body)?),
+            Package::Raft(body) => Request::Raft(body),
+            Package::OK(_) | Package::Error(_) => {
+                return Err(KvsError::Protocol("a reply isn't a request".to_owned()))
+            }
+        })
+    }
+}
+
+fn text(bytes: &[u8]) -> kvs::Result<String> {
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
 // read package
 // send ok
 // send responce
-fn handle<E: KvsEngine>(mut socket: TcpStream, kvs: &mut E) -> std::io::Result<()> {
-    let mut buffer = [0; 1024];
-    socket.read(&mut buffer)?;
-    let pkg = deconstruct_package(&buffer);
-    info!("I got {}", pkg);
-
-    match pkg {
-        Package::Remove(key) => {
-            if kvs.remove(std::str::from_utf8(key).unwrap().to_owned()).is_ok() {
-                socket.write(&construct_package(ok_package()))?;
-                info!("send blank OK");
-            } else {
-                    socket.write(&construct_package(Package::Error("Key not found".as_bytes())))?;
-                    warn!("send error");
-                };
+fn handle<E: KvsEngine>(mut socket: TcpStream, kvs: &mut E, meta: &Metadata, watches: &mut Watches, replication: &mut Replication, settings: &Settings, held: usize) -> std::io::Result<Option<Subscriber>> {
+    let buffer = read_package(&mut socket);
+    let request = buffer
+        .as_deref()
+        .map_err(|err| KvsError::Protocol(err.to_string()))
+        .and_then(deconstruct_package)
+        .and_then(|pkg| {
+            info!("I got {}", pkg);
+            Request::decode(pkg)
+        });
+    let request = match request {
+        Ok(request) => request,
+        // a client which sent garbage is told so, whatever it sent next is dropped
+        Err(err) => {
+            send_error(&mut socket, &err)?;
+            return Ok(None);
+        }
+    };
+
+    match request {
+        Request::Raft(body) => match &mut replication.cluster {
+            Some(cluster) => match cluster.step(body) {
+                Ok(()) => {
+                    socket.write_all(&construct_package(ok_package()))?;
+                }
+                Err(err) => send_error(&mut socket, &err)?,
             },
-        Package::Get(key) => {
-            match kvs.get(std::str::from_utf8(key).unwrap().to_owned()) {
-                Ok(Some(val)) => {
-                    // what is happening with size of the value?
-                    socket.write(&construct_package(Package::OK(val.trim_matches(char::from(0)).as_ref())))?;
-                    info!("send OK {}", val);
-                },
-                Ok(None) => {
-                    socket.write(&construct_package(ok_package()))?;
-                    info!("send ok with none");
+            None => send_error(&mut socket, &KvsError::Unsupported("not a member of a cluster".to_owned()))?,
+        },
+        Request::Set(key, value) if replication.cluster.is_some() => {
+            let cluster = replication.cluster.as_mut().unwrap();
+            cluster.propose(socket, Proposal::Set { key, value })?;
+        },
+        Request::Remove(key) if replication.cluster.is_some() => {
+            let cluster = replication.cluster.as_mut().unwrap();
+            cluster.propose(socket, Proposal::Remove { key })?;
+        },
+        Request::Get(key) if replication.cluster.is_some() => {
+            let cluster = replication.cluster.as_mut().unwrap();
+            cluster.read(socket, kvs, key)?;
+        },
+        // a follower takes writes from its leader only
+        Request::Set(..) | Request::Remove(..) if replication.replica.is_some() => {
+            let leader = replication.replica.as_ref().map_or("", |(replica, _)| replica.leader());
+            send_error(&mut socket, &KvsError::NotLeader(format!("read-only replica of {}", leader)))?;
+        },
+        Request::Remove(key) => match kvs.remove(key.clone()).and_then(|()| synced(kvs, settings)) {
+            Ok(()) => {
+                watches.changed(&key, None);
+                socket.write_all(&construct_package(ok_package()))?;
+                info!("send blank OK");
+            }
+            Err(err) => send_error(&mut socket, &err)?,
+        },
+        Request::Get(key) => send_value(&mut socket, kvs, &key)?,
+        Request::Set(key, val) => match kvs.set(key.clone(), val.clone()).and_then(|()| synced(kvs, settings)) {
+            Ok(()) => {
+                watches.changed(&key, Some(&val));
+                socket.write_all(&construct_package(ok_package()))?;
+                info!("send blank OK");
+            }
+            Err(err) => send_error(&mut socket, &err)?,
+        },
+        // a change of members is answered once it's committed
+        Request::Admin(AdminCommand::AddMember { node }) if replication.cluster.is_some() => {
+            replication.cluster.as_mut().unwrap().change_members(socket, node, true)?;
+        }
+        Request::Admin(AdminCommand::RemoveMember { node }) if replication.cluster.is_some() => {
+            replication.cluster.as_mut().unwrap().change_members(socket, node, false)?;
+        }
+        Request::Admin(command) => {
+            let res = match command {
+                // the checkpoint is pinned to the engine of this directory
+                AdminCommand::Backup { dest } => {
+                    let dest = std::path::Path::new(&dest);
+                    kvs.checkpoint(dest).and_then(|()| meta.save(dest)).map(|()| Vec::new())
+                }
+                AdminCommand::Scan { after, limit } => kvs
+                    .page(after.as_deref(), limit as usize)
+                    .and_then(|page| Ok(rmp_serde::encode::to_vec(&page)?)),
+                AdminCommand::Seq => kvs.seq().and_then(|seq| Ok(rmp_serde::encode::to_vec(&seq)?)),
+                AdminCommand::Report { follower, applied, lag_ms } => (|| {
+                    let caught_up = kvs.changes(applied)?.next().is_none();
+                    let report = Report { applied, lag_ms, at: Instant::now() };
+                    replication.followers.insert(follower, report);
+                    Ok(rmp_serde::encode::to_vec(&(caught_up, kvs.seq()?))?)
+                })(),
+                AdminCommand::Status => rmp_serde::encode::to_vec(&replication.status(kvs)).map_err(KvsError::from),
+                AdminCommand::Cluster => match &replication.cluster {
+                    Some(cluster) => rmp_serde::encode::to_vec(&cluster.status()).map_err(KvsError::from),
+                    None => Err(KvsError::Unsupported("not a member of a cluster".to_owned())),
                 },
-                Err(msg) => {
-                    socket.write(&construct_package(Package::Error("error happend".as_bytes())))?;
-                    info!("send error");
+                AdminCommand::AddMember { .. } | AdminCommand::RemoveMember { .. } => {
+                    Err(KvsError::Unsupported("not a member of a cluster".to_owned()))
                 }
+                AdminCommand::Shutdown => {
+                    settings.shutdown.store(true, Ordering::SeqCst);
+                    Ok(Vec::new())
+                }
+            };
+            match res {
+                Ok(body) => {
+                    socket.write_all(&construct_package(Package::OK(&body)))?;
+                    info!("send OK with {} bytes", body.len());
+                }
+                Err(err) => send_error(&mut socket, &err)?,
             }
         },
-        Package::Set(key, val) => {
-            if kvs.set(std::str::from_utf8(key).unwrap().to_owned(), std::str::from_utf8(val).unwrap().to_owned()).is_ok() {
-                socket.write(&construct_package(ok_package()))?;
-                info!("send blank OK");
-            } else {
-                    socket.write(&construct_package(Package::Error("something went wrong".as_bytes())))?;
-                    warn!("send error");
+        Request::Subscribe(subscription) => {
+            let subscribed = hold(held, settings).and_then(|()| {
+                let seq = match subscription.since {
+                    // a subscriber behind a compaction is told so right away
+                    Some(since) => kvs.changes(since).map(|_| since)?,
+                    None => kvs.seq()?,
                 };
+                Ok((subscription.prefix, seq))
+            });
+            match subscribed {
+                Ok((prefix, seq)) => {
+                    socket.write_all(&construct_package(ok_package()))?;
+                    socket.set_write_timeout(Some(settings.subscriber_timeout))?;
+                    info!("subscribed to {:?} after {}", prefix, seq);
+                    return Ok(Some(Subscriber { socket, prefix, seq }));
+                }
+                Err(err) => send_error(&mut socket, &err)?,
+            }
         },
-        _ => unreachable!(),
-        };
+        Request::Watch(watch) => {
+            let version = watches.version(&watch.key);
+            let value = (|| {
+                // a version the server didn't hand out comes from another store
+                // or from before a restart of an engine which keeps no versions
+                if watch.since > watches.clock {
+                    return Err(KvsError::UnknownVersion(watch.since, watches.clock));
+                }
+                // the key changed already, there is nothing to wait for
+                match version > watch.since {
+                    true => Ok(Some(kvs.get(watch.key.clone())?)),
+                    false => {
+                        hold(held, settings)?;
+                        Ok(None)
+                    }
+                }
+            })();
+            match value {
+                Ok(Some(value)) => {
+                    let reply = KeyVersion {
+                        version,
+                        value: value.map(|value| value.trim_matches(char::from(0)).to_owned()),
+                        changed: true,
+                    };
+                    if let Err(err) = send_version(socket, &reply) {
+                        warn!("cannot reply to watch: {}", err);
+                    }
+                }
+                Ok(None) => {
+                    socket.set_write_timeout(Some(settings.subscriber_timeout))?;
+                    info!("watch {:?} after {}", watch.key, watch.since);
+                    let timeout = Duration::from_millis(watch.timeout_ms).min(settings.max_watch_timeout);
+                    watches.wait(watch.key, socket, timeout);
+                }
+                Err(err) => send_error(&mut socket, &err)?,
+            }
+        },
+    };
 
-    Ok(()) 
+    Ok(None) 
 }
@@ -0,0 +1,146 @@
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use crate::{construct_package, deconstruct_package, read_package, AdminCommand, ErrorReply, KvsError, Package, Result};
+
+// pairs a scan asks a server for at once
+const PAGE_SIZE: u32 = 128;
+
+/// KvsClient sends requests to a single server, a connection per request
+#[derive(Clone, Debug)]
+pub struct KvsClient {
+    addr: String,
+    timeout: Option<Duration>,
+}
+
+impl KvsClient {
+    pub fn new(addr: impl Into<String>) -> Self {
+        KvsClient {
+            addr: addr.into(),
+            timeout: None,
+        }
+    }
+
+    /// Give up on connecting and on every read and write after `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Value of `key`, `None` when it isn't set
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let body = self.request(Package::Get(key.as_bytes()))?;
+        // the server replies with an empty body for a missing key
+        match body.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(String::from_utf8(body)?)),
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.request(Package::Set(key.as_bytes(), value.as_bytes())).map(drop)
+    }
+
+    /// Remove `key`, failing with `KeyNotFound` when it isn't set
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.request(Package::Remove(key.as_bytes())).map(drop)
+    }
+
+    /// Send an admin command returning the body of the reply
+    pub fn admin(&self, command: AdminCommand) -> Result<Vec<u8>> {
+        self.request(Package::Admin(&command.encode()?))
+    }
+
+    /// Every pair of the server in ascending order of keys
+    pub fn pairs(&self) -> RemotePairs<'_> {
+        RemotePairs::new(&self.addr)
+    }
+
+    fn request(&self, package: Package) -> Result<Vec<u8>> {
+        let mut socket = match self.timeout {
+            Some(timeout) => {
+                let addr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address for {}", self.addr))
+                })?;
+                let socket = TcpStream::connect_timeout(&addr, timeout)?;
+                socket.set_read_timeout(Some(timeout))?;
+                socket.set_write_timeout(Some(timeout))?;
+                socket
+            }
+            None => TcpStream::connect(&self.addr)?,
+        };
+        socket.write_all(&construct_package(package))?;
+        reply(&mut socket)
+    }
+}
+
+/// Send an admin command to the server at `addr` returning the body of its reply
+pub fn admin(addr: &str, command: AdminCommand) -> Result<Vec<u8>> {
+    let mut socket = TcpStream::connect(addr)?;
+    socket.write_all(&construct_package(Package::Admin(&command.encode()?)))?;
+    reply(&mut socket)
+}
+
+/// Read the reply to a request, an error package is turned into the error it tells
+pub fn reply(socket: &mut TcpStream) -> Result<Vec<u8>> {
+    let buffer = read_package(socket)?;
+    match deconstruct_package(&buffer)? {
+        Package::OK(body) => Ok(body.to_vec()),
+        Package::Error(body) => Err(ErrorReply::decode(body)?.into_error()),
+        _ => Err(KvsError::Protocol("a server replied with a request".to_owned())),
+    }
+}
+
+/// RemotePairs scans a server page by page in ascending order of keys
+pub struct RemotePairs<'a> {
+    addr: &'a str,
+    after: Option<String>,
+    page: std::vec::IntoIter<(String, String)>,
+    done: bool,
+}
+
+impl<'a> RemotePairs<'a> {
+    pub fn new(addr: &'a str) -> Self {
+        RemotePairs {
+            addr,
+            after: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    fn next_page(&mut self) -> Result<()> {
+        let command = AdminCommand::Scan {
+            after: self.after.clone(),
+            limit: PAGE_SIZE,
+        };
+        let page: Vec<(String, String)> = rmp_serde::decode::from_slice(&admin(self.addr, command)?)?;
+        self.done = page.len() < PAGE_SIZE as usize;
+        self.after = page.last().map(|(key, _)| key.clone());
+        self.page = page.into_iter();
+        Ok(())
+    }
+}
+
+impl Iterator for RemotePairs<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pair) = self.page.next() {
+            return Some(Ok(pair));
+        }
+        if self.done {
+            return None;
+        }
+
+        if let Err(err) = self.next_page() {
+            self.done = true;
+            return Some(Err(err));
+        }
+        self.page.next().map(Ok)
+    }
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::{
+    Compression, EngineKind, EngineOptions, IndexMode, KvStoreOptions, KvsError, LsmOptions, Result,
+};
+
+/// ServerConfig is the configuration file of a kvs-server, written in TOML.
+///
+/// Every setting may be left out, the server falls back to its default then.
+/// A flag given on the command line overrides the setting of the file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: Option<String>,
+    /// engine of a new data directory, the one of its metadata otherwise
+    pub engine: Option<EngineKind>,
+    pub data_dir: Option<PathBuf>,
+    pub kvs: KvsConfig,
+    pub lsm: LsmConfig,
+    pub memory: MemoryConfig,
+    pub limits: LimitsConfig,
+    pub logging: LoggingConfig,
+    pub durability: DurabilityConfig,
+}
+
+/// Options of the kvs engine, see `KvStoreOptions`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KvsConfig {
+    #[serde(deserialize_with = "parse")]
+    pub compression: Option<Compression>,
+    #[serde(deserialize_with = "parse")]
+    pub index: Option<IndexMode>,
+    pub key_file: Option<PathBuf>,
+    pub blob_threshold: Option<usize>,
+    pub cache_capacity: Option<usize>,
+    pub bloom_fp_rate: Option<f64>,
+    pub history: Option<usize>,
+}
+
+/// Options of the lsm engine, see `LsmOptions`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LsmConfig {
+    pub memtable_size: Option<usize>,
+    pub level0_tables: Option<usize>,
+    pub table_size: Option<u64>,
+    pub level1_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// seconds between snapshots
+    pub snapshot_interval: Option<u64>,
+}
+
+/// Limits bound the clients the server holds on to, durations are in milliseconds
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// subscribers and watches held at once, a client past them is refused
+    pub pool_size: Option<usize>,
+    pub max_watch_timeout: Option<u64>,
+    /// a subscriber which doesn't take a change for this long is dropped
+    pub subscriber_timeout: Option<u64>,
+    /// how long a stopping server waits for the requests it took
+    pub shutdown_timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// one of off, error, warn, info, debug and trace
+    #[serde(deserialize_with = "parse")]
+    pub level: Option<log::LevelFilter>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DurabilityConfig {
+    /// sync the store to disk after every write before it's answered
+    pub sync_writes: Option<bool>,
+}
+
+impl ServerConfig {
+    /// Read the configuration file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|err| KvsError::Config(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Options the engines are opened with, a setting left out takes its default.
+    ///
+    /// The encryption key is left to the caller, which reads `kvs.key_file`
+    /// or takes the key from elsewhere.
+    pub fn engine_options(&self) -> EngineOptions {
+        let (kvs, lsm) = (KvStoreOptions::default(), LsmOptions::default());
+        EngineOptions {
+            kvs: KvStoreOptions {
+                compression: self.kvs.compression.unwrap_or(kvs.compression),
+                index: self.kvs.index.unwrap_or(kvs.index),
+                blob_threshold: self.kvs.blob_threshold.or(kvs.blob_threshold),
+                cache_capacity: self.kvs.cache_capacity.unwrap_or(kvs.cache_capacity),
+                bloom_fp_rate: self.kvs.bloom_fp_rate.or(kvs.bloom_fp_rate),
+                history: self.kvs.history.unwrap_or(kvs.history),
+                ..kvs
+            },
+            lsm: LsmOptions {
+                memtable_size: self.lsm.memtable_size.unwrap_or(lsm.memtable_size),
+                level0_tables: self.lsm.level0_tables.unwrap_or(lsm.level0_tables),
+                table_size: self.lsm.table_size.unwrap_or(lsm.table_size),
+                level1_size: self.lsm.level1_size.unwrap_or(lsm.level1_size),
+            },
+            snapshot_interval: self.memory.snapshot_interval.map(Duration::from_secs),
+        }
+    }
+}
+
+/// Parse a setting the way its command line flag is parsed
+fn parse<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::Result;
+use super::Generation;
+
+/// BloomFilter tells whether a key may be in a generation,
+/// a negative answer is always right.
+#[derive(Serialize, Deserialize)]
+pub(super) struct BloomFilter {
+    fp_rate: f64,
+    hashes: u32,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Filter sized for `keys` keys with the false positive rate `fp_rate`
+    pub(super) fn new(keys: usize, fp_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let keys = keys.max(1) as f64;
+        let size = (-keys * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (size / keys * ln2).round().max(1.0) as u32;
+
+        BloomFilter {
+            fp_rate,
+            hashes,
+            bits: vec![0; (size as usize).div_ceil(64)],
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        for bit in bits_of(key, self.hashes, self.bits.len()) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub(super) fn contains(&self, key: &str) -> bool {
+        bits_of(key, self.hashes, self.bits.len())
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub(super) fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    /// Read a filter saved by `save`,
+    /// a missing or damaged file gives no filter so it gets rebuilt
+    pub(super) fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            // a filter without bits has no bit to map a key to
+            Ok(content) => Ok(rmp_serde::decode::from_slice(&content)
+                .ok()
+                .filter(|filter: &Self| !filter.bits.is_empty())),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the filter next to its generation.
+    ///
+    /// A filter can be rebuilt from its generation at any time,
+    /// so it is renamed in place but never synced.
+    pub(super) fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("bloom.tmp");
+        std::fs::File::create(&tmp)?.write_all(&rmp_serde::encode::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Bits of a key by double hashing, the hash must stay
+/// the same across runs as filters are persisted
+fn bits_of(key: &str, hashes: u32, words: usize) -> impl Iterator<Item = usize> {
+    let h1 = fnv1a(key.as_bytes());
+    let h2 = mix(h1) | 1;
+    let size = words as u64 * 64;
+    (0..u64::from(hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size) as usize)
+}
+
+fn fnv1a(b: &[u8]) -> u64 {
+    b.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// finalizer of splitmix64
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Keys the filter of the active generation is sized for at first,
+/// filters twice as large are added as it fills up
+const ACTIVE_KEYS: usize = 1024;
+
+/// Filters of the sealed generations of a store and of the active one
+/// along with the number of lookups they answered.
+pub(super) struct Filters {
+    fp_rate: Option<f64>,
+    filters: BTreeMap<Generation, BloomFilter>,
+    /// filters of the active generation, the last one takes new keys
+    active: Vec<BloomFilter>,
+    active_keys: usize,
+    negatives: AtomicU64,
+}
+
+impl Filters {
+    pub(super) fn new(fp_rate: Option<f64>) -> Self {
+        Filters {
+            fp_rate,
+            filters: BTreeMap::new(),
+            active: Vec::new(),
+            active_keys: 0,
+            negatives: AtomicU64::new(0),
+        }
+    }
+
+    /// Rate new filters are built with, `None` when filters are off
+    pub(super) fn fp_rate(&self) -> Option<f64> {
+        self.fp_rate
+    }
+
+    /// Whether `key` may be in `gen`, a generation without a filter may hold anything
+    pub(super) fn may_contain(&self, gen: Generation, key: &str) -> bool {
+        match self.filters.get(&gen) {
+            Some(filter) if !filter.contains(key) => {
+                self.negatives.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether `key` may be in any generation, a lookup of a key
+    /// which cannot be is answered without the index
+    pub(super) fn may_hold(&self, key: &str) -> bool {
+        if self.fp_rate.is_none() {
+            return true;
+        }
+
+        let held = self.filters.values().chain(&self.active).any(|filter| filter.contains(key));
+        if !held {
+            self.negatives.fetch_add(1, Ordering::Relaxed);
+        }
+        held
+    }
+
+    pub(super) fn insert(&mut self, gen: Generation, filter: BloomFilter) {
+        self.filters.insert(gen, filter);
+    }
+
+    /// Add a key written to the active generation
+    pub(super) fn insert_active(&mut self, key: &str) {
+        let fp_rate = match self.fp_rate {
+            Some(fp_rate) => fp_rate,
+            None => return,
+        };
+
+        let capacity = ACTIVE_KEYS * ((1 << self.active.len()) - 1);
+        if self.active_keys >= capacity {
+            self.active.push(BloomFilter::new(ACTIVE_KEYS << self.active.len(), fp_rate));
+        }
+        self.active.last_mut().expect("GG: no active filter").insert(key);
+        self.active_keys += 1;
+    }
+
+    /// Start the filter of a new active generation,
+    /// the sealed one gets a filter of its own
+    pub(super) fn reset_active(&mut self) {
+        self.active.clear();
+        self.active_keys = 0;
+    }
+
+    pub(super) fn remove(&mut self, gen: Generation) {
+        self.filters.remove(&gen);
+    }
+
+    /// Lookups the filters answered without reading disk
+    pub(super) fn negatives(&self) -> u64 {
+        self.negatives.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn memory(&self) -> usize {
+        self.filters.values().chain(&self.active).map(|filter| filter.bits.len() * 8).sum()
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// ValueCache keeps recently read values in memory,
+/// evicting the least recently used ones once it holds more than
+/// `capacity` bytes of keys and values.
+pub(super) struct ValueCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<String, Entry>,
+    // last use of an entry to its key, the first one is evicted next
+    order: BTreeMap<u64, String>,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    val: String,
+    used: u64,
+}
+
+impl ValueCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(super) fn get(&mut self, key: &str) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.hits += 1;
+                let key = self.order.remove(&entry.used).expect("GG: cache order is broken");
+                entry.used = self.tick;
+                self.order.insert(self.tick, key);
+                Some(entry.val.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: String, val: String) {
+        let size = key.len() + val.len();
+        if size > self.capacity {
+            return;
+        }
+
+        self.invalidate(&key);
+        while self.size + size > self.capacity {
+            self.evict();
+        }
+
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, Entry { val, used: self.tick });
+    }
+
+    pub(super) fn invalidate(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.size -= key.len() + entry.val.len();
+        }
+    }
+
+    pub(super) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub(super) fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    fn evict(&mut self) {
+        let key = self.order.values().next().cloned().expect("GG: evicting from an empty cache");
+        self.invalidate(&key);
+    }
+}
@@ -0,0 +1,150 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::HashMap;
+use std::path::Path;
+use crate::{KvsError, Result};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// Identifier of a key written in generation file headers,
+/// 0 marks a generation which isn't encrypted.
+pub(super) type KeyId = u64;
+
+pub(super) const PLAIN: KeyId = 0;
+
+/// EncryptionKey is a 256-bit key records are sealed with
+/// using ChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: ChaCha20Poly1305,
+    id: KeyId,
+}
+
+impl EncryptionKey {
+    /// Create a key from its raw bytes
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let id = key_id(&cipher);
+        EncryptionKey { cipher, id }
+    }
+
+    /// Parse a key from 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+            return Err(KvsError::InvalidKey(format!("expected {} hex digits", KEY_SIZE * 2)));
+        }
+
+        let mut key = [0; KEY_SIZE];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|err| KvsError::InvalidKey(err.to_string()))?;
+        }
+
+        Ok(EncryptionKey::new(key))
+    }
+
+    /// Read a hex encoded key from a file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        EncryptionKey::from_hex(&std::fs::read_to_string(path)?)
+    }
+
+    /// Read a hex encoded key from an environment variable
+    pub fn from_env(var: &str) -> Result<Self> {
+        let hex = std::env::var(var)
+            .map_err(|err| KvsError::InvalidKey(format!("{}: {}", var, err)))?;
+        EncryptionKey::from_hex(&hex)
+    }
+
+    pub(super) fn id(&self) -> KeyId {
+        self.id
+    }
+
+    /// Seal `plain` into `nonce || ciphertext`, authenticating `aad` along with it
+    pub(super) fn encrypt(&self, plain: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plain, aad })
+            .map_err(|_| KvsError::Crypto)?;
+
+        let mut payload = Vec::with_capacity(NONCE_SIZE + sealed.len());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&sealed);
+        Ok(payload)
+    }
+
+    /// Open a payload produced by `encrypt` with the same `aad`
+    pub(super) fn decrypt(&self, payload: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() < NONCE_SIZE {
+            return Err(KvsError::Crypto);
+        }
+
+        let (nonce, sealed) = payload.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .map_err(|_| KvsError::Crypto)
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EncryptionKey({:016x})", self.id)
+    }
+}
+
+/// Key id is the tag of an empty message under the all-zero nonce,
+/// it identifies a key without revealing it.
+fn key_id(cipher: &ChaCha20Poly1305) -> KeyId {
+    let tag = cipher
+        .encrypt(Nonce::from_slice(&[0; NONCE_SIZE]), &[][..])
+        .expect("sealing an empty message");
+    let mut id = [0; 8];
+    id.copy_from_slice(&tag[..8]);
+    // never collide with the marker of plain generations
+    u64::from_be_bytes(id).max(1)
+}
+
+/// Keyring keeps the key new records are sealed with
+/// and every key older generations may still be sealed with.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Keyring {
+    active: Option<EncryptionKey>,
+    keys: HashMap<KeyId, EncryptionKey>,
+}
+
+impl Keyring {
+    pub(super) fn new(active: Option<EncryptionKey>, retired: Vec<EncryptionKey>) -> Self {
+        let mut keyring = Keyring::default();
+        for key in retired {
+            keyring.keys.insert(key.id(), key);
+        }
+        keyring.rotate(active);
+        keyring
+    }
+
+    pub(super) fn active(&self) -> Option<&EncryptionKey> {
+        self.active.as_ref()
+    }
+
+    pub(super) fn active_id(&self) -> KeyId {
+        self.active.as_ref().map_or(PLAIN, EncryptionKey::id)
+    }
+
+    /// Make `key` the active one, the previous key stays usable for reading
+    pub(super) fn rotate(&mut self, key: Option<EncryptionKey>) {
+        if let Some(key) = &key {
+            self.keys.insert(key.id(), key.clone());
+        }
+        self.active = key;
+    }
+
+    pub(super) fn get(&self, id: KeyId) -> Result<Option<&EncryptionKey>> {
+        if id == PLAIN {
+            return Ok(None);
+        }
+
+        self.keys.get(&id).map(Some).ok_or(KvsError::UnknownKey(id))
+    }
+}
@@ -0,0 +1,104 @@
+use memmap::Mmap;
+use serde::Serialize;
+use std::path::Path;
+use crate::{KvsError, Result};
+use super::crypto::{EncryptionKey, Keyring};
+use super::record::Command;
+use super::verify::{map_sealed, Found, Records};
+use super::{FileHeader, Generation, KvStoreOptions};
+
+/// LogFile is a generation file opened to be inspected record by record
+pub struct LogFile {
+    map: Option<Mmap>,
+    header: FileHeader,
+    gen: Generation,
+    key: Option<EncryptionKey>,
+}
+
+/// Record of a generation file along with its place in the file
+#[derive(Clone, Debug, Serialize)]
+pub struct LogRecord {
+    pub offset: u64,
+    pub len: u64,
+    #[serde(flatten)]
+    pub entry: LogEntry,
+}
+
+/// What a record of a generation file holds
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum LogEntry {
+    Set { key: String, value: String },
+    /// the value is kept at `pos` of the blob file `file`
+    SetBlob { key: String, file: u64, pos: u64, len: u64 },
+    Remove { key: String },
+    /// bytes in which no record decodes
+    Corrupted,
+}
+
+impl LogEntry {
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            LogEntry::Set { key, .. } | LogEntry::SetBlob { key, .. } | LogEntry::Remove { key } => {
+                Some(key)
+            }
+            LogEntry::Corrupted => None,
+        }
+    }
+}
+
+impl LogFile {
+    /// Open the generation file `path`,
+    /// the keys of `options` open a sealed one
+    pub fn open(path: impl AsRef<Path>, options: &KvStoreOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let not_generation = || KvsError::Incompatible(format!("{} is not a generation file", path.display()));
+        // sealed records are bound to the generation, which names the file
+        let gen = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .ok_or_else(not_generation)?;
+        let (map, header) = map_sealed(path)?.map_err(|_| not_generation())?;
+        let keys = Keyring::new(options.encryption.clone(), options.retired_keys.clone());
+        let key = keys.get(header.key_id)?.cloned();
+
+        Ok(LogFile { map, header, gen, key })
+    }
+
+    /// Id of the key the file is sealed with, 0 for plain text
+    pub fn key_id(&self) -> u64 {
+        self.header.key_id
+    }
+
+    /// Records of the file in order of offsets
+    pub fn records(&self) -> impl Iterator<Item = LogRecord> + '_ {
+        let data = self.map.as_ref().map_or(&[][..], |map| &map[..]);
+        Records::new(data, self.key.as_ref(), self.header, self.gen).map(|found| {
+            let (range, entry) = match found {
+                Found::Record { range, command } => (range, command.into()),
+                Found::Corrupted(range) => (range, LogEntry::Corrupted),
+            };
+            LogRecord {
+                offset: range.start,
+                len: range.end - range.start,
+                entry,
+            }
+        })
+    }
+}
+
+impl From<Command> for LogEntry {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Set { key, val, .. } => LogEntry::Set { key, value: val },
+            Command::SetBlob { key, blob, .. } => LogEntry::SetBlob {
+                key,
+                file: blob.file,
+                pos: blob.pos,
+                len: blob.len,
+            },
+            Command::Remove { key, .. } => LogEntry::Remove { key },
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use crate::engines::Change;
+use crate::{KvsError, Result};
+use super::record::{self, Command};
+use super::{Generation, KvStore, PositionBufReader, FILE_HEADER_SIZE};
+
+// a sequence number is the position right past a record
+// |generation(24 bits)|offset(40 bits)|
+const OFFSET_BITS: u32 = 40;
+
+pub(super) fn seq(gen: Generation, offset: u64) -> u64 {
+    gen << OFFSET_BITS | offset
+}
+
+fn split(seq: u64) -> (Generation, u64) {
+    (seq >> OFFSET_BITS, seq & ((1 << OFFSET_BITS) - 1))
+}
+
+/// Feed reads the records of generations in order turning them into changes
+pub(super) struct Feed<'a> {
+    store: &'a KvStore,
+    /// generations left to read along with the offset to start at
+    plan: VecDeque<(Generation, u64)>,
+    current: Option<(Generation, PositionBufReader<File>)>,
+}
+
+impl<'a> Feed<'a> {
+    /// Feed of the changes made after `since`.
+    ///
+    /// When the generation of `since` was compacted away the feed starts
+    /// at the compacted generation, which holds the live pairs the changes left.
+    /// It's skipped for a reader which saw every change it replaced.
+    /// The removes it replaced are gone, so a reader which saw only some
+    /// of them has to start over from 0.
+    pub(super) fn new(store: &'a KvStore, since: u64) -> Result<Self> {
+        let (since_gen, since_offset) = split(since);
+        let base = store.readers.keys().next().cloned().unwrap_or(0);
+
+        let plan = if since_gen < base {
+            let restated = store.horizon > 0 && since >= store.horizon;
+            if since > 0 && !restated {
+                return Err(KvsError::ResyncRequired(since));
+            }
+            store
+                .readers
+                .keys()
+                .filter(|&&gen| !(restated && gen == base))
+                .map(|&gen| (gen, FILE_HEADER_SIZE))
+                .collect()
+        } else {
+            store
+                .readers
+                .keys()
+                .filter(|&&gen| gen >= since_gen)
+                .map(|&gen| match gen == since_gen {
+                    true => (gen, since_offset.max(FILE_HEADER_SIZE)),
+                    false => (gen, FILE_HEADER_SIZE),
+                })
+                .collect()
+        };
+
+        Ok(Feed {
+            store,
+            plan,
+            current: None,
+        })
+    }
+
+    fn next_change(&mut self) -> Result<Option<Change>> {
+        loop {
+            if self.current.is_none() {
+                let (gen, offset) = match self.plan.pop_front() {
+                    Some(next) => next,
+                    None => return Ok(None),
+                };
+                let mut reader = self.store.readers[&gen].scan()?;
+                reader.seek(SeekFrom::Start(offset))?;
+                self.current = Some((gen, reader));
+            }
+
+            let (gen, reader) = self.current.as_mut().expect("GG: no reader");
+            let file = &self.store.readers[gen];
+            let command = match record::read(reader, self.store.keys.get(file.key_id)?, file.place(reader.pos))? {
+                Some(command) => command,
+                None => {
+                    self.current = None;
+                    continue;
+                }
+            };
+
+            let seq = seq(*gen, reader.pos);
+            return Ok(Some(match command {
+                Command::Set { key, val, .. } => Change {
+                    seq,
+                    key,
+                    value: Some(val),
+                },
+                Command::SetBlob { key, blob, .. } => Change {
+                    seq,
+                    key,
+                    value: Some(String::from_utf8(self.store.read_blob(&blob)?)?),
+                },
+                Command::Remove { key, .. } => Change {
+                    seq,
+                    key,
+                    value: None,
+                },
+            }));
+        }
+    }
+}
+
+impl Iterator for Feed<'_> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
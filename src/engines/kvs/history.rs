@@ -0,0 +1,77 @@
+use std::collections::{HashMap, VecDeque};
+use crate::Result;
+use super::CommandPos;
+
+/// Value of a key at one of its versions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Versioned {
+    pub version: u64,
+    /// `None` when the key was removed at this version
+    pub value: Option<String>,
+}
+
+/// History keeps the positions of the latest records of every key,
+/// the ones of overwritten values and removes included.
+///
+/// Records it holds are live for compaction, the rest is garbage.
+pub(super) struct History {
+    retention: usize,
+    records: HashMap<String, VecDeque<CommandPos>>,
+}
+
+impl History {
+    /// History keeping up to `retention` records per key, 0 keeps none
+    pub(super) fn new(retention: usize) -> Self {
+        History {
+            retention,
+            records: HashMap::new(),
+        }
+    }
+
+    pub(super) fn is_kept(&self) -> bool {
+        self.retention > 0
+    }
+
+    /// Add the latest record of `key`, returning the one which fell out of the retention
+    pub(super) fn push(&mut self, key: &str, pos: CommandPos) -> Option<CommandPos> {
+        if !self.is_kept() {
+            return None;
+        }
+
+        let records = match self.records.get_mut(key) {
+            Some(records) => records,
+            None => self.records.entry(key.to_owned()).or_default(),
+        };
+        records.push_back(pos);
+        if records.len() > self.retention {
+            records.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Replace the latest record of `key` by a copy of it at `pos`,
+    /// returning the replaced one
+    pub(super) fn relocate(&mut self, key: &str, pos: CommandPos) -> Option<CommandPos> {
+        let latest = self.records.get_mut(key)?.back_mut()?;
+        Some(std::mem::replace(latest, pos))
+    }
+
+    /// Records of `key` from the oldest to the latest
+    pub(super) fn get(&self, key: &str) -> impl Iterator<Item = &CommandPos> {
+        self.records.get(key).into_iter().flatten()
+    }
+
+    pub(super) fn positions(&self) -> impl Iterator<Item = &CommandPos> {
+        self.records.values().flatten()
+    }
+
+    /// Call `f` with every position in the order the records were written
+    /// per key, letting it move the record
+    pub(super) fn update<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnMut(&mut CommandPos) -> Result<()>,
+    {
+        self.records.values_mut().flatten().try_for_each(f)
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::str::FromStr;
+use crate::Result;
+use super::{CommandPos, Generation};
+
+/// How a KvStore keeps its index in memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// every key is kept along with the position of its value
+    #[default]
+    Keys,
+    /// only hashes of keys are kept along with packed positions,
+    /// a hit is verified by reading the key from disk
+    Hashes,
+}
+
+impl FromStr for IndexMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keys" => Ok(IndexMode::Keys),
+            "hashes" => Ok(IndexMode::Hashes),
+            _ => Err(format!("unknown index mode {}", s)),
+        }
+    }
+}
+
+/// Verifier tells whether the record at a position holds a key
+pub(super) type Verifier<'a> = &'a dyn Fn(&CommandPos, &str) -> Result<bool>;
+
+/// Index maps keys to the positions of their latest records
+pub(super) enum Index {
+    Keys(HashMap<String, CommandPos>),
+    Hashes(HashIndex),
+}
+
+impl Index {
+    pub(super) fn new(mode: IndexMode) -> Self {
+        match mode {
+            IndexMode::Keys => Index::Keys(HashMap::new()),
+            IndexMode::Hashes => Index::Hashes(HashIndex::default()),
+        }
+    }
+
+    pub(super) fn get(&self, key: &str, verify: Verifier) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Keys(index) => Ok(index.get(key).cloned()),
+            Index::Hashes(index) => index.get(key, verify),
+        }
+    }
+
+    /// Point `key` to `pos`, returning the position it pointed to
+    pub(super) fn insert(&mut self, key: String, pos: CommandPos, verify: Verifier) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Keys(index) => Ok(index.insert(key, pos)),
+            Index::Hashes(index) => index.insert(key, pos, verify),
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &str, verify: Verifier) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Keys(index) => Ok(index.remove(key)),
+            Index::Hashes(index) => index.remove(key, verify),
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match self {
+            Index::Keys(index) => index.len(),
+            Index::Hashes(index) => index.hashes.len() + index.collisions.len(),
+        }
+    }
+
+    /// Call `f` with every position, letting it move the record
+    pub(super) fn update<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut CommandPos) -> Result<()>,
+    {
+        match self {
+            Index::Keys(index) => index.values_mut().try_for_each(f),
+            Index::Hashes(index) => {
+                for packed in index.hashes.values_mut() {
+                    let mut pos = packed.unpack();
+                    f(&mut pos)?;
+                    *packed = PackedPos::pack(&pos);
+                }
+                index.collisions.values_mut().try_for_each(f)
+            }
+        }
+    }
+
+    /// Positions of up to `limit` keys following `after` in ascending order of keys,
+    /// `None` for a hashed index which doesn't keep the keys
+    pub(super) fn page(&self, after: Option<&str>, limit: usize) -> Option<Vec<CommandPos>> {
+        let index = match self {
+            Index::Keys(index) => index,
+            Index::Hashes(_) => return None,
+        };
+        let mut page = BinaryHeap::with_capacity(limit + 1);
+        for key in index.keys() {
+            if after.is_some_and(|after| key.as_str() <= after) {
+                continue;
+            }
+
+            page.push(key);
+            if page.len() > limit {
+                page.pop();
+            }
+        }
+        Some(page.into_sorted_vec().into_iter().map(|key| index[key].clone()).collect())
+    }
+
+    pub(super) fn positions(&self) -> Vec<CommandPos> {
+        match self {
+            Index::Keys(index) => index.values().cloned().collect(),
+            Index::Hashes(index) => index
+                .hashes
+                .values()
+                .map(PackedPos::unpack)
+                .chain(index.collisions.values().cloned())
+                .collect(),
+        }
+    }
+
+    /// Estimate of the memory the index takes in bytes
+    pub(super) fn memory(&self) -> usize {
+        // hashbrown keeps a control byte per bucket
+        match self {
+            Index::Keys(index) => {
+                let keys: usize = index.keys().map(String::capacity).sum();
+                index.capacity() * (size_of::<(String, CommandPos)>() + 1) + keys
+            }
+            Index::Hashes(index) => {
+                let keys: usize = index.collisions.keys().map(String::capacity).sum();
+                index.hashes.capacity() * (size_of::<(u64, PackedPos)>() + 1)
+                    + index.collisions.capacity() * (size_of::<(String, CommandPos)>() + 1)
+                    + keys
+            }
+        }
+    }
+}
+
+/// HashIndex keeps only hashes of keys.
+///
+/// Keys whose hash is taken by another key are rare,
+/// they're kept in full in `collisions`.
+#[derive(Default)]
+pub(super) struct HashIndex {
+    hashes: HashMap<u64, PackedPos>,
+    collisions: HashMap<String, CommandPos>,
+    state: RandomState,
+}
+
+impl HashIndex {
+    fn hash(&self, key: &str) -> u64 {
+        self.state.hash_one(key)
+    }
+
+    fn get(&self, key: &str, verify: Verifier) -> Result<Option<CommandPos>> {
+        if let Some(pos) = self.collisions.get(key) {
+            return Ok(Some(pos.clone()));
+        }
+
+        match self.hashes.get(&self.hash(key)) {
+            Some(packed) => {
+                let pos = packed.unpack();
+                if verify(&pos, key)? {
+                    Ok(Some(pos))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, key: String, pos: CommandPos, verify: Verifier) -> Result<Option<CommandPos>> {
+        if let Some(old) = self.collisions.get_mut(&key) {
+            return Ok(Some(std::mem::replace(old, pos)));
+        }
+
+        let hash = self.hash(&key);
+        let old = match self.hashes.get(&hash) {
+            None => None,
+            Some(packed) => {
+                let old = packed.unpack();
+                if !verify(&old, &key)? {
+                    self.collisions.insert(key, pos);
+                    return Ok(None);
+                }
+                Some(old)
+            }
+        };
+        self.hashes.insert(hash, PackedPos::pack(&pos));
+
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str, verify: Verifier) -> Result<Option<CommandPos>> {
+        if let Some(old) = self.collisions.remove(key) {
+            return Ok(Some(old));
+        }
+
+        let hash = self.hash(key);
+        match self.hashes.get(&hash) {
+            Some(packed) if verify(&packed.unpack(), key)? => {
+                Ok(self.hashes.remove(&hash).as_ref().map(PackedPos::unpack))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// PackedPos is a CommandPos squeezed into 16 bytes,
+/// records are never larger than 4GiB as their size is a 32-bit number
+#[derive(Clone, Copy)]
+struct PackedPos {
+    pos: u64,
+    len: u32,
+    gen: u32,
+}
+
+impl PackedPos {
+    fn pack(pos: &CommandPos) -> Self {
+        PackedPos {
+            pos: pos.pos,
+            len: pos.len as u32,
+            gen: pos.gen as u32,
+        }
+    }
+
+    fn unpack(&self) -> CommandPos {
+        CommandPos {
+            pos: self.pos,
+            len: u64::from(self.len),
+            gen: Generation::from(self.gen),
+        }
+    }
+}
@@ -0,0 +1,1429 @@
+
+use memmap::Mmap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{prelude::*, BufReader, BufWriter, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use crate::{KvsError, Result};
+use super::{create_checkpoint_dir, link_or_copy, Changes, KvsEngine, Pairs};
+use bloom::{BloomFilter, Filters};
+use cache::ValueCache;
+use crypto::{KeyId, Keyring};
+use feed::Feed;
+use history::History;
+use index::Index;
+use record::{BlobPos, Command, Place};
+
+pub use crypto::EncryptionKey;
+pub use dump::{LogEntry, LogFile, LogRecord};
+pub use history::Versioned;
+pub use index::IndexMode;
+pub use record::Compression;
+pub use verify::{Corruption, GenerationInfo, RepairReport, VerifyReport};
+
+mod bloom;
+mod cache;
+mod crypto;
+mod dump;
+mod feed;
+mod history;
+mod index;
+mod record;
+mod verify;
+
+static COMPACT_BOUND: u64 = 1001;
+static MANIFEST_FILE: &str = "MANIFEST";
+
+// structure the generation file
+// |magic(4 bytes)|key_id(8 bytes)|records(unsized)|
+// the last byte of the magic is the version of the file,
+// version 1 files were written before records were sealed and bound to their place
+static FILE_MAGIC: &[u8; 4] = b"SIL\x02";
+const UNBOUND_VERSION: u8 = 1;
+const FILE_HEADER_SIZE: u64 = 4 + 8;
+
+type Generation = u64;
+
+/// Points of the compaction procedure at which a crash can be simulated.
+///
+/// A failure injected at a step leaves the directory exactly as a crash
+/// right after that step would, so tests can check the store recovers.
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStep {
+    /// compacted records are written to a temporary file but not synced
+    Write,
+    /// the temporary file is synced but not renamed yet
+    Sync,
+    /// the compacted generation is in place but the switch isn't recorded
+    Rename,
+    /// the switch is recorded and only one stale generation is removed
+    Cleanup,
+}
+
+/// Options tune how a KvStore writes its log
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreOptions {
+    /// compression of newly written records,
+    /// compaction recompresses the rest
+    pub compression: Compression,
+    /// key newly written generations are sealed with
+    pub encryption: Option<EncryptionKey>,
+    /// keys older generations may be sealed with,
+    /// needed only until a compaction re-encrypts them
+    pub retired_keys: Vec<EncryptionKey>,
+    /// values of this size and larger are kept in blob files
+    /// so compaction doesn't copy them
+    pub blob_threshold: Option<usize>,
+    /// bytes of keys and values the read cache holds,
+    /// 0 turns the cache off
+    pub cache_capacity: usize,
+    /// what the in-memory index keeps per key
+    pub index: IndexMode,
+    /// false positive rate of the bloom filters kept for generations,
+    /// `None` turns them off
+    pub bloom_fp_rate: Option<f64>,
+    /// number of latest versions `history` keeps per key,
+    /// compaction keeps their records along with the live ones.
+    /// 0 keeps the live versions only
+    pub history: usize,
+}
+
+/// Stats describe the state of a KvStore
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreStats {
+    /// number of live keys
+    pub keys: usize,
+    /// reads served by the cache
+    pub cache_hits: u64,
+    /// reads which went to disk while the cache was on
+    pub cache_misses: u64,
+    /// bytes held by the cache
+    pub cache_size: usize,
+    /// estimate of bytes taken by the index
+    pub index_memory: usize,
+    /// false positive rate bloom filters are built with
+    pub bloom_fp_rate: Option<f64>,
+    /// lookups bloom filters answered without reading disk
+    pub bloom_negatives: u64,
+    /// bytes taken by bloom filters
+    pub bloom_memory: usize,
+}
+
+/// KvStore represent simple key value storage
+pub struct KvStore {
+    index: Index,
+    readers: BTreeMap<Generation, GenerationReader>,
+    writer: PositionBufWriter<File>,
+    path: std::path::PathBuf,
+    untracked: u64,
+    generation: Generation,
+    options: KvStoreOptions,
+    keys: Keyring,
+    blobs: BTreeMap<u64, GenerationReader>,
+    blob_writer: Option<(u64, PositionBufWriter<File>)>,
+    /// id the next blob file gets
+    next_blob: u64,
+    cache: ValueCache,
+    filters: Filters,
+    /// sequence number of the last change folded into the base generation
+    horizon: u64,
+    history: History,
+    /// the last version handed out to a write
+    version: u64,
+    fail_at: Option<CompactionStep>,
+}
+
+impl KvStore {
+     /// Create new object of storage
+    pub fn open(folder: impl Into<PathBuf>) -> Result<Self> {
+        KvStore::open_with_options(folder, KvStoreOptions::default())
+    }
+
+    /// Create new object of storage configured by `options`
+    pub fn open_with_options(folder: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        let path = folder.into();
+        let mut readers = BTreeMap::new();
+        let keys = Keyring::new(options.encryption.clone(), options.retired_keys.clone());
+
+        let Manifest { base, horizon, mut version, blobs: mut next_blob } = read_manifest(&path)?;
+        remove_stale_files(&path, base)?;
+
+        let generations = state(&path)?;
+        for &gen in &generations {
+            upgrade_legacy(gen, &path, &keys, options.compression)?;
+            let reader = GenerationReader::open(gen, &path)?;
+            keys.get(reader.key_id)?;
+            readers.insert(gen, reader);
+        }
+
+        let mut filters = Filters::new(options.bloom_fp_rate);
+        if let Some(fp_rate) = options.bloom_fp_rate {
+            for (&gen, reader) in &readers {
+                let filter = load_filter(gen, &path, reader, keys.get(reader.key_id)?, fp_rate)?;
+                filters.insert(gen, filter);
+            }
+        }
+
+        let mut index = Index::new(options.index);
+        let mut history = History::new(options.history);
+        let mut untracked = 0;
+        for reader in readers.values() {
+            let key = keys.get(reader.key_id)?;
+            let verify = |pos: &CommandPos, k: &str| holds_key(&readers, &keys, &filters, pos, k);
+            let (cleaned, last) = upload_index(&mut index, &mut history, reader, key, &verify)?;
+            untracked += cleaned;
+            version = version.max(last);
+        }
+
+        let current_generation = generations.last().map_or(0, |&g| g + 1);
+        let (writer, reader) = create_generation(current_generation, &path, keys.active_id())?;
+
+        readers.insert(current_generation, reader);
+
+        let mut blobs = BTreeMap::new();
+        for id in file_ids(&path, "blob")? {
+            let reader = GenerationReader::sealed(File::open(blob_path(id, &path))?, id)?;
+            keys.get(reader.key_id)?;
+            blobs.insert(id, reader);
+            next_blob = next_blob.max(id + 1);
+        }
+
+        Ok(KvStore {
+            index,
+            writer,
+            readers,
+            path,
+            untracked,
+            generation: current_generation,
+            cache: ValueCache::new(options.cache_capacity),
+            filters,
+            horizon,
+            history,
+            version,
+            options,
+            keys,
+            blobs,
+            blob_writer: None,
+            next_blob,
+            fail_at: None,
+        })
+    }
+
+    /// Compact rewrites all live values into a new generation
+    /// and drops the files of the old ones.
+    ///
+    /// The compacted generation is written to a temporary file, synced
+    /// and renamed in place before the manifest records it as the base
+    /// generation. Until then the old generations stay authoritative,
+    /// so a crash at any point leaves the store with the committed data.
+    pub fn compact(&mut self) -> Result<()> {
+        let horizon = feed::seq(self.generation, self.writer.pos);
+        let compact_gen = self.generation + 1;
+        // writes go past the compacted generation from now on, so one left
+        // behind by a failed compaction is replayed before them
+        self.start_generation(compact_gen + 1)?;
+
+        // the index and the history keep pointing to the old generations
+        // until the manifest records the compacted one
+        let tmp = tmp_path(compact_gen, &self.path);
+        let mut compact_w = PositionBufWriter::new(create_file(&tmp)?)?;
+        write_header(&mut compact_w, self.keys.active_id())?;
+        let moved = compact_to(
+            &self.index,
+            &self.history,
+            &self.readers,
+            &mut compact_w,
+            compact_gen,
+            self.options.compression,
+            &self.keys,
+        )?;
+        self.fail_point(CompactionStep::Write)?;
+
+        compact_w.get_ref().sync_all()?;
+        self.fail_point(CompactionStep::Sync)?;
+
+        std::fs::rename(&tmp, gen_path(compact_gen, &self.path))?;
+        sync_dir(&self.path)?;
+        self.fail_point(CompactionStep::Rename)?;
+
+        let reader = GenerationReader::open(compact_gen, &self.path)?;
+        let filter = match self.filters.fp_rate() {
+            Some(fp_rate) => {
+                let key = self.keys.get(reader.key_id)?;
+                Some(load_filter(compact_gen, &self.path, &reader, key, fp_rate)?)
+            }
+            None => None,
+        };
+
+        // the versions of dropped removes are recorded so they aren't handed out again
+        let manifest = Manifest {
+            base: compact_gen,
+            horizon,
+            version: self.version,
+            blobs: self.next_blob,
+        };
+        write_manifest(&self.path, &manifest)?;
+
+        self.horizon = horizon;
+        self.readers.insert(compact_gen, reader);
+        if let Some(filter) = filter {
+            self.filters.insert(compact_gen, filter);
+        }
+        let relocate = |pos: &mut CommandPos| -> Result<()> {
+            if let Some(new) = moved.get(&(pos.gen, pos.pos)) {
+                *pos = new.clone();
+            }
+            Ok(())
+        };
+        self.history.update(relocate)?;
+        self.index.update(relocate)?;
+        self.untracked = 0;
+
+        // the map of a stale generation is dropped before its file is removed
+        let stale: Vec<Generation> = self.readers.range(..compact_gen).map(|(&gen, _)| gen).collect();
+        for (i, gen) in stale.into_iter().enumerate() {
+            if i == 1 {
+                self.fail_point(CompactionStep::Cleanup)?;
+            }
+            self.readers.remove(&gen);
+            self.filters.remove(gen);
+            std::fs::remove_file(gen_path(gen, &self.path))?;
+            remove_filter(gen, &self.path)?;
+        }
+
+        if !self.blobs.is_empty() {
+            self.collect_blobs()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rekey seals the whole store with `key`, or stores it in plain text
+    /// when `key` is `None`.
+    ///
+    /// Generations and blob files sealed with the previous key are
+    /// re-encrypted by a compaction, after which the previous key isn't needed anymore.
+    pub fn rekey(&mut self, key: Option<EncryptionKey>) -> Result<()> {
+        self.keys.rotate(key);
+        self.options.encryption = self.keys.active().cloned();
+        self.blob_writer = None;
+        self.compact()
+    }
+
+    /// Stats returns counters of the store
+    pub fn stats(&self) -> KvStoreStats {
+        KvStoreStats {
+            keys: self.index.len(),
+            cache_hits: self.cache.hits(),
+            cache_misses: self.cache.misses(),
+            cache_size: self.cache.size(),
+            index_memory: self.index.memory(),
+            bloom_fp_rate: self.filters.fp_rate(),
+            bloom_negatives: self.filters.negatives(),
+            bloom_memory: self.filters.memory(),
+        }
+    }
+
+    /// Write the value of `key` to `out` returning its size.
+    ///
+    /// Values kept in plain blob files are copied straight from the file
+    /// without being loaded into memory.
+    pub fn get_to<W: Write>(&mut self, key: &str, out: &mut W) -> Result<Option<u64>> {
+        let blob = match self.read_command(key)? {
+            None => return Ok(None),
+            Some(Command::Set { val, .. }) => {
+                out.write_all(val.as_bytes())?;
+                return Ok(Some(val.len() as u64));
+            }
+            Some(Command::SetBlob { blob, .. }) => blob,
+            Some(Command::Remove { .. }) => return Err(KvsError::AppropriateCommandNotFound),
+        };
+
+        let reader = self.blobs.get(&blob.file).expect("GG: cannot find");
+        if reader.key_id == crypto::PLAIN {
+            let copied = match &reader.map {
+                Some(map) => {
+                    let mut content = &map[blob.pos as usize..(blob.pos + blob.len) as usize];
+                    match record::read_header(&mut content)? {
+                        (Compression::None, _) => Some(std::io::copy(&mut content, out)?),
+                        _ => None,
+                    }
+                }
+                None => {
+                    let mut file = reader.file.try_clone()?;
+                    file.seek(SeekFrom::Start(blob.pos))?;
+                    let mut file = BufReader::new(file);
+                    match record::read_header(&mut file)? {
+                        (Compression::None, size) => {
+                            Some(std::io::copy(&mut file.take(u64::from(size)), out)?)
+                        }
+                        _ => None,
+                    }
+                }
+            };
+
+            if copied.is_some() {
+                return Ok(copied);
+            }
+        }
+
+        let val = self.read_blob(&blob)?;
+        out.write_all(&val)?;
+        Ok(Some(val.len() as u64))
+    }
+
+    /// Lookup reads the value of `key` bypassing the cache.
+    ///
+    /// It needs only a shared reference: sealed generations are read from
+    /// their memory maps and the active one with positioned reads,
+    /// so many threads can look keys up at once without locking.
+    pub fn lookup(&self, key: &str) -> Result<Option<String>> {
+        match self.read_command(key)? {
+            None => Ok(None),
+            Some(Command::Set { val, .. }) => Ok(Some(val)),
+            Some(Command::SetBlob { blob, .. }) => Ok(Some(String::from_utf8(self.read_blob(&blob)?)?)),
+            Some(Command::Remove { .. }) => Err(KvsError::AppropriateCommandNotFound),
+        }
+    }
+
+    /// Read the value of `key` along with the version it was written at,
+    /// values written before versions were recorded have version 0
+    pub fn get_with_version(&self, key: &str) -> Result<Option<(String, u64)>> {
+        match self.read_command(key)? {
+            None => Ok(None),
+            Some(Command::Set { val, version, .. }) => Ok(Some((val, version))),
+            Some(Command::SetBlob { blob, version, .. }) => {
+                Ok(Some((String::from_utf8(self.read_blob(&blob)?)?, version)))
+            }
+            Some(Command::Remove { .. }) => Err(KvsError::AppropriateCommandNotFound),
+        }
+    }
+
+    /// History returns the versions of `key` kept by `KvStoreOptions::history`
+    /// from the oldest to the latest.
+    ///
+    /// Without a retention only the live version is returned.
+    pub fn history(&self, key: &str) -> Result<Vec<Versioned>> {
+        if !self.history.is_kept() {
+            return Ok(self
+                .get_with_version(key)?
+                .map(|(value, version)| Versioned { version, value: Some(value) })
+                .into_iter()
+                .collect());
+        }
+
+        self.history
+            .get(key)
+            .map(|pos| {
+                Ok(match self.readers[&pos.gen].command(pos.pos, pos.len, &self.keys)? {
+                    Command::Set { val, version, .. } => Versioned { version, value: Some(val) },
+                    Command::SetBlob { blob, version, .. } => Versioned {
+                        version,
+                        value: Some(String::from_utf8(self.read_blob(&blob)?)?),
+                    },
+                    Command::Remove { version, .. } => Versioned { version, value: None },
+                })
+            })
+            .collect()
+    }
+
+    /// Read the pair of the record at `pos`
+    fn pair_at(&self, pos: &CommandPos) -> Result<(String, String)> {
+        match self.readers[&pos.gen].command(pos.pos, pos.len, &self.keys)? {
+            Command::Set { key, val, .. } => Ok((key, val)),
+            Command::SetBlob { key, blob, .. } => Ok((key, String::from_utf8(self.read_blob(&blob)?)?)),
+            Command::Remove { .. } => Err(KvsError::AppropriateCommandNotFound),
+        }
+    }
+
+    /// Collect garbage of blob files, compaction does it as well.
+    ///
+    /// Live values of blob files which are mostly garbage, or are sealed
+    /// with a key other than the active one, are moved to the active blob
+    /// file and the old files are removed.
+    /// Files holding values of older versions the history keeps stay.
+    pub fn collect_blobs(&mut self) -> Result<()> {
+        let mut live = Vec::new();
+        let mut latest = std::collections::HashSet::new();
+        for pos in self.index.positions() {
+            let command = self.readers[&pos.gen].command(pos.pos, pos.len, &self.keys)?;
+            if let Command::SetBlob { key, blob, version } = command {
+                latest.insert((pos.gen, pos.pos));
+                live.push((key, blob, version));
+            }
+        }
+
+        let mut kept = std::collections::HashSet::new();
+        for pos in self.history.positions().filter(|pos| !latest.contains(&(pos.gen, pos.pos))) {
+            if let Command::SetBlob { blob, .. } = self.readers[&pos.gen].command(pos.pos, pos.len, &self.keys)? {
+                kept.insert(blob.file);
+            }
+        }
+
+        let mut live_bytes = HashMap::new();
+        for (_, blob, _) in &live {
+            *live_bytes.entry(blob.file).or_insert(0) += blob.len;
+        }
+
+        let active = self.blob_writer.as_ref().map(|&(id, _)| id);
+        let mut collected = Vec::new();
+        for (&id, reader) in &self.blobs {
+            let size = std::fs::metadata(blob_path(id, &self.path))?.len();
+            let live = live_bytes.get(&id).cloned().unwrap_or(0);
+            let sealed_with_old_key = reader.key_id != self.keys.active_id();
+            if Some(id) != active && !kept.contains(&id) && (live * 2 < size || sealed_with_old_key) {
+                collected.push(id);
+            }
+        }
+
+        // the moved values keep their versions
+        for (key, blob, version) in live {
+            if collected.contains(&blob.file) {
+                let val = self.read_blob(&blob)?;
+                let blob = self.write_blob(&val)?;
+                self.append(Command::SetBlob { key, blob, version }, true)?;
+            }
+        }
+
+        if let Some((_, writer)) = &self.blob_writer {
+            writer.get_ref().sync_all()?;
+        }
+        self.writer.get_ref().sync_all()?;
+
+        for id in collected {
+            self.blobs.remove(&id);
+            std::fs::remove_file(blob_path(id, &self.path))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_command(&self, key: &str) -> Result<Option<Command>> {
+        // the index of keys answers a miss from memory already,
+        // a hashed one may need to read a record
+        if self.options.index == IndexMode::Hashes && !self.filters.may_hold(key) {
+            return Ok(None);
+        }
+
+        let verify = |pos: &CommandPos, k: &str| holds_key(&self.readers, &self.keys, &self.filters, pos, k);
+        match self.index.get(key, &verify)? {
+            None => Ok(None),
+            Some(op) => {
+                let reader = self.readers.get(&op.gen).expect("GG: cannot find");
+                Ok(Some(reader.command(op.pos, op.len, &self.keys)?))
+            }
+        }
+    }
+
+    /// Append a set command to the log and point the index to it.
+    ///
+    /// A `relocated` record is a copy of the latest one of its key,
+    /// it takes its place in the history rather than adding a version.
+    fn append(&mut self, command: Command, relocated: bool) -> Result<()> {
+        let key = match &command {
+            Command::Set { key, .. } | Command::SetBlob { key, .. } => key.clone(),
+            Command::Remove { .. } => unreachable!(),
+        };
+
+        let at = Place { file: self.generation, offset: self.writer.pos };
+        let b = record::encode(&command, self.options.compression, self.keys.active(), at)?;
+        let offset = write_to(&mut self.writer, &b)?;
+
+        let command = CommandPos::from((self.generation, offset.start..offset.end));
+        self.filters.insert_active(&key);
+        let dropped = match relocated {
+            true => self.history.relocate(&key, command.clone()),
+            false => self.history.push(&key, command.clone()),
+        };
+        let (readers, keys, filters) = (&self.readers, &self.keys, &self.filters);
+        let verify = |pos: &CommandPos, k: &str| holds_key(readers, keys, filters, pos, k);
+        let old = self.index.insert(key, command, &verify)?;
+        // records the history keeps aren't garbage until they fall out of it
+        self.untracked += match self.history.is_kept() {
+            true => dropped.map_or(0, |old| old.len),
+            false => old.map_or(0, |old| old.len),
+        };
+
+        Ok(())
+    }
+
+    fn write_blob(&mut self, val: &[u8]) -> Result<BlobPos> {
+        if self.blob_writer.is_none() {
+            // the id is recorded as taken before its file is created,
+            // so records of a collected file never point to a new one
+            let id = self.next_blob;
+            let mut manifest = read_manifest(&self.path)?;
+            manifest.blobs = id + 1;
+            write_manifest(&self.path, &manifest)?;
+            self.next_blob = id + 1;
+
+            let (writer, reader) = create_sealed_file(&blob_path(id, &self.path), id, self.keys.active_id())?;
+            self.blobs.insert(id, reader);
+            self.blob_writer = Some((id, writer));
+        }
+
+        let (id, writer) = self.blob_writer.as_mut().expect("GG: no blob writer");
+        let at = Place { file: *id, offset: writer.pos };
+        let b = record::encode_value(val, self.options.compression, self.keys.active(), at)?;
+        let offset = write_to(writer, &b)?;
+
+        Ok(BlobPos {
+            file: *id,
+            pos: offset.start,
+            len: offset.end - offset.start,
+        })
+    }
+
+    fn read_blob(&self, blob: &BlobPos) -> Result<Vec<u8>> {
+        // dead records of the log may point to a collected file
+        let reader = self.blobs.get(&blob.file).ok_or_else(|| {
+            let msg = format!("blob file {} was collected", blob.file);
+            std::io::Error::new(std::io::ErrorKind::NotFound, msg)
+        })?;
+        let b = reader.read(blob.pos, blob.len)?;
+        record::decode_value(&b, self.keys.get(reader.key_id)?, reader.place(blob.pos))
+    }
+
+    /// Seal the active generation, records written from now on go to `gen`
+    fn start_generation(&mut self, gen: Generation) -> Result<()> {
+        self.writer.get_ref().sync_all()?;
+        let (writer, reader) = create_generation(gen, &self.path, self.keys.active_id())?;
+        let sealed = GenerationReader::open(self.generation, &self.path)?;
+        if let Some(fp_rate) = self.filters.fp_rate() {
+            let key = self.keys.get(sealed.key_id)?;
+            let filter = load_filter(self.generation, &self.path, &sealed, key, fp_rate)?;
+            self.filters.insert(self.generation, filter);
+        }
+
+        self.readers.insert(self.generation, sealed);
+        self.readers.insert(gen, reader);
+        self.filters.reset_active();
+        self.generation = gen;
+        self.writer = writer;
+
+        Ok(())
+    }
+
+    /// Seal the active generation and blob file,
+    /// records written from now on go to new files
+    fn seal(&mut self) -> Result<()> {
+        if let Some((id, writer)) = self.blob_writer.take() {
+            writer.get_ref().sync_all()?;
+            let reader = GenerationReader::sealed(File::open(blob_path(id, &self.path))?, id)?;
+            self.blobs.insert(id, reader);
+        }
+
+        self.start_generation(self.generation + 1)
+    }
+
+    /// Make the next compaction fail right after `step`,
+    /// as if the process crashed there.
+    #[doc(hidden)]
+    pub fn fail_compaction_at(&mut self, step: CompactionStep) {
+        self.fail_at = Some(step);
+    }
+
+    fn fail_point(&self, step: CompactionStep) -> Result<()> {
+        if self.fail_at == Some(step) {
+            let msg = format!("injected failure at {:?}", step);
+            return Err(std::io::Error::other(msg).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Get method tries to find value with `key`
+    fn get(&mut self, k: String) -> Result<Option<String>> {
+        if let Some(val) = self.cache.get(&k) {
+            return Ok(Some(val));
+        }
+
+        let val = match self.read_command(&k) {
+            Ok(None) => return Ok(None),
+            Ok(Some(Command::Set { val, .. })) => val,
+            Ok(Some(Command::SetBlob { blob, .. })) => String::from_utf8(self.read_blob(&blob)?)?,
+            _ => return Err(KvsError::AppropriateCommandNotFound),
+        };
+        self.cache.insert(k, val.clone());
+
+        Ok(Some(val))
+    }
+
+    /// Set put new value in storage by key
+    /// it rewrite value if that alredy exists
+    fn set(&mut self, key: String, val: String) -> Result<()> {
+        // compacting before the write keeps the write out of the compacted generation,
+        // so a reader of the feed which saw every change before doesn't see it restated
+        if self.untracked > COMPACT_BOUND {
+            self.compact()?;
+        }
+
+        self.cache.invalidate(&key);
+        self.version += 1;
+        let version = self.version;
+        let command = match self.options.blob_threshold {
+            Some(threshold) if val.len() >= threshold => Command::SetBlob {
+                key,
+                blob: self.write_blob(val.as_bytes())?,
+                version,
+            },
+            _ => Command::Set { key, val, version },
+        };
+        self.append(command, false)
+    }
+
+    /// Delete key value pair from storage
+    fn remove(&mut self, key: String) -> Result<()> {
+        let (readers, keys, filters) = (&self.readers, &self.keys, &self.filters);
+        let verify = |pos: &CommandPos, k: &str| holds_key(readers, keys, filters, pos, k);
+        let old = match self.index.remove(&key, &verify)? {
+            Some(old) => old,
+            None => return Err(KvsError::KeyNotFound),
+        };
+
+        self.cache.invalidate(&key);
+        self.version += 1;
+        let command = Command::Remove {
+            key,
+            version: self.version,
+        };
+        let at = Place { file: self.generation, offset: self.writer.pos };
+        let b = record::encode(&command, self.options.compression, self.keys.active(), at)?;
+        let offset = write_to(&mut self.writer, &b)?;
+
+        let pos = CommandPos::from((self.generation, offset.start..offset.end));
+        self.untracked += match self.history.is_kept() {
+            true => self.history.push(command.key(), pos).map_or(0, |old| old.len),
+            false => old.len,
+        };
+
+        Ok(())
+    }
+
+    /// Changes are read from the log,
+    /// the sequence number of a change is the position right past its record
+    fn changes(&mut self, since: u64) -> Result<Changes<'_>> {
+        Ok(Box::new(Feed::new(self, since)?))
+    }
+
+    fn seq(&mut self) -> Result<u64> {
+        Ok(feed::seq(self.generation, self.writer.pos))
+    }
+
+    fn version(&mut self) -> Result<u64> {
+        Ok(self.version)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if let Some((_, writer)) = &mut self.blob_writer {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Pairs are read lazily in the order of the index
+    fn pairs(&mut self) -> Result<Pairs<'_>> {
+        let store = &*self;
+        let positions = store.index.positions();
+        Ok(Box::new(positions.into_iter().map(move |pos| store.pair_at(&pos))))
+    }
+
+    /// Keys of the page are picked first and only their values are read.
+    /// A hashed index doesn't keep the keys, they're read from the records.
+    fn page(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let positions = match self.index.page(after, limit) {
+            Some(positions) => positions,
+            None => {
+                let positions = self.index.positions();
+                let mut page = BinaryHeap::with_capacity(limit + 1);
+                for (i, pos) in positions.iter().enumerate() {
+                    let key = self.readers[&pos.gen].command(pos.pos, pos.len, &self.keys)?.key().to_owned();
+                    if after.is_some_and(|after| key.as_str() <= after) {
+                        continue;
+                    }
+
+                    page.push((key, i));
+                    if page.len() > limit {
+                        page.pop();
+                    }
+                }
+                page.into_sorted_vec().into_iter().map(|(_, i)| positions[i].clone()).collect()
+            }
+        };
+
+        positions.iter().map(|pos| self.pair_at(pos)).collect()
+    }
+
+    /// The active generation and blob file are sealed first, after that
+    /// every file of the store is immutable until compaction removes it,
+    /// so the files are hard linked into `dest`.
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        self.seal()?;
+
+        let manifest = self.path.join(MANIFEST_FILE);
+        if manifest.exists() {
+            link_or_copy(&manifest, &dest.join(MANIFEST_FILE))?;
+        }
+        for &gen in self.readers.keys().filter(|&&gen| gen != self.generation) {
+            link_or_copy(&gen_path(gen, &self.path), &gen_path(gen, dest))?;
+        }
+        for &id in self.blobs.keys() {
+            link_or_copy(&blob_path(id, &self.path), &blob_path(id, dest))?;
+        }
+
+        sync_dir(dest)?;
+        Ok(())
+    }
+}
+
+/// Write `pairs` as the only generation of the store at `path`.
+///
+/// The generation is committed the way a compacted one is,
+/// so a crash leaves either the previous snapshot or the new one.
+pub(super) fn write_snapshot<'a>(
+    path: &Path,
+    pairs: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Result<()> {
+    std::fs::create_dir_all(path)?;
+    let gen = state(path)?.last().map_or(0, |&g| g + 1);
+
+    let tmp = tmp_path(gen, path);
+    let mut writer = PositionBufWriter::new(create_file(&tmp)?)?;
+    write_header(&mut writer, crypto::PLAIN)?;
+    for (key, val) in pairs {
+        let command = Command::Set {
+            key: key.clone(),
+            val: val.clone(),
+            version: 0,
+        };
+        let at = Place { file: gen, offset: writer.pos };
+        writer.write_all(&record::encode(&command, Compression::None, None, at)?)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    std::fs::rename(&tmp, gen_path(gen, path))?;
+    sync_dir(path)?;
+    write_manifest(path, &Manifest { base: gen, horizon: 0, version: 0, blobs: 0 })?;
+
+    remove_stale_files(path, gen)
+}
+
+/// Read the pairs of the snapshot at `path` written by `write_snapshot`.
+///
+/// Unlike `KvStore::open` it writes nothing to the directory.
+pub(super) fn read_snapshot(path: &Path) -> Result<BTreeMap<String, String>> {
+    let keys = Keyring::new(None, Vec::new());
+    let base = read_manifest(path)?.base;
+
+    let mut pairs = BTreeMap::new();
+    for gen in state(path)?.into_iter().filter(|&gen| gen >= base) {
+        let file = GenerationReader::open(gen, path)?;
+        let key = keys.get(file.key_id)?;
+        let reader = &mut file.scan()?;
+        let mut start = reader.pos;
+        while let Some(command) = record::read(reader, key, file.place(start))? {
+            match command {
+                Command::Set { key, val, .. } => {
+                    pairs.insert(key, val);
+                }
+                Command::Remove { key, .. } => {
+                    pairs.remove(&key);
+                }
+                Command::SetBlob { key, .. } => {
+                    let msg = format!("value of {} is kept in a blob file, which snapshots don't read", key);
+                    return Err(KvsError::Incompatible(msg));
+                }
+            }
+            start = reader.pos;
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// Copy the records the history keeps and the live ones to `writer`,
+/// returning where the records at the old positions were moved.
+///
+/// Records of a key are copied in the order they were written,
+/// so reading the generation back leaves the key with its latest one.
+fn compact_to(
+    index: &Index,
+    history: &History,
+    readers: &BTreeMap<Generation, GenerationReader>,
+    writer: &mut PositionBufWriter<File>,
+    gen: Generation,
+    compression: Compression,
+    keys: &Keyring,
+) -> Result<HashMap<(Generation, u64), CommandPos>> {
+    let mut moved = HashMap::new();
+    let mut copy = |pos: &CommandPos| -> Result<()> {
+        if moved.contains_key(&(pos.gen, pos.pos)) {
+            return Ok(());
+        }
+
+        // sealed records are bound to their place so they're sealed again
+        let reader = readers.get(&pos.gen).expect("GG: cannot find");
+        let mut content = reader.read(pos.pos, pos.len)?;
+        let plain = reader.key_id == crypto::PLAIN && keys.active_id() == crypto::PLAIN;
+        if !plain || record::compression(&content) != compression {
+            let command = reader.command(pos.pos, pos.len, keys)?;
+            let at = Place { file: gen, offset: writer.pos };
+            content = Cow::Owned(record::encode(&command, compression, keys.active(), at)?);
+        }
+
+        let offset = write_to(writer, &content)?;
+        moved.insert((pos.gen, pos.pos), CommandPos::from((gen, offset.start..offset.end)));
+        Ok(())
+    };
+
+    // the live records are in the history as well when it's kept
+    history.positions().try_for_each(&mut copy)?;
+    index.positions().iter().try_for_each(&mut copy)?;
+
+    Ok(moved)
+}
+
+/// Whether the record at `pos` holds `key`,
+/// the bloom filter of its generation is asked before the record is read
+fn holds_key(
+    readers: &BTreeMap<Generation, GenerationReader>,
+    keys: &Keyring,
+    filters: &Filters,
+    pos: &CommandPos,
+    key: &str,
+) -> Result<bool> {
+    if !filters.may_contain(pos.gen, key) {
+        return Ok(false);
+    }
+
+    let reader = readers.get(&pos.gen).expect("GG: cannot find");
+    Ok(reader.command(pos.pos, pos.len, keys)?.key() == key)
+}
+
+/// Load the bloom filter of a sealed generation, it's built and saved
+/// when it's missing or was built for another false positive rate
+fn load_filter(
+    gen: Generation,
+    path: &Path,
+    reader: &GenerationReader,
+    key: Option<&EncryptionKey>,
+    fp_rate: f64,
+) -> Result<BloomFilter> {
+    let bloom = bloom_path(gen, path);
+    if let Some(filter) = BloomFilter::load(&bloom)? {
+        if filter.fp_rate() == fp_rate {
+            return Ok(filter);
+        }
+    }
+
+    let mut scan = reader.scan()?;
+    let mut stored = Vec::new();
+    loop {
+        let at = reader.place(scan.pos);
+        match record::read(&mut scan, key, at)? {
+            Some(Command::Set { key, .. }) | Some(Command::SetBlob { key, .. }) => stored.push(key),
+            Some(Command::Remove { .. }) => {}
+            None => break,
+        }
+    }
+
+    let mut filter = BloomFilter::new(stored.len(), fp_rate);
+    for k in &stored {
+        filter.insert(k);
+    }
+    filter.save(&bloom)?;
+
+    Ok(filter)
+}
+
+fn remove_filter(gen: Generation, path: &Path) -> Result<()> {
+    match std::fs::remove_file(bloom_path(gen, path)) {
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+fn write_to(writer: &mut PositionBufWriter<File>, b: &[u8]) -> Result<Range<u64>> {
+    let start_position = writer.pos;
+    writer.write_all(b)?;
+    writer.flush()?;
+    Ok(start_position..writer.pos)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, pos)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let size = file.seek_read(buf, pos)?;
+        if size == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[size..];
+        pos += size as u64;
+    }
+
+    Ok(())
+}
+
+/// Load the records of a generation into the index and the history,
+/// returning the bytes of garbage found and the latest version
+fn upload_index(
+    index: &mut Index,
+    history: &mut History,
+    file: &GenerationReader,
+    key: Option<&EncryptionKey>,
+    verify: index::Verifier,
+) -> Result<(u64, u64)> {
+    let reader = &mut file.scan()?;
+    let mut start = reader.pos;
+    let mut untracked = 0;
+    let mut version = 0;
+    while let Some(command) = record::read(reader, key, file.place(start))? {
+        let pos = CommandPos::from((file.id, start..reader.pos));
+        version = version.max(command.version());
+        let dropped = history.push(command.key(), pos.clone());
+        let cleaned_bytes = match command {
+            Command::Set { key, .. } | Command::SetBlob { key, .. } => {
+                index.insert(key, pos, verify)?.map_or(0, |old| old.len)
+            }
+            Command::Remove { key, .. } => {
+                let old_bytes = index.remove(&key, verify)?.map_or(0, |old| old.len);
+                old_bytes + pos.len
+            }
+        };
+        untracked += match history.is_kept() {
+            true => dropped.map_or(0, |old| old.len),
+            false => cleaned_bytes,
+        };
+        start = reader.pos;
+    }
+
+    Ok((untracked, version))
+}
+
+/// Rewrite a generation written before files had a header and records
+/// were framed, so it's read like the others.
+///
+/// The file is replaced atomically, a crash leaves either version of it.
+fn upgrade_legacy(gen: Generation, path: &Path, keys: &Keyring, compression: Compression) -> Result<()> {
+    let file = gen_path(gen, path);
+    let mut magic = Vec::new();
+    File::open(&file)?.take(3).read_to_end(&mut magic)?;
+    // a file shorter than a header holds no records either way
+    if magic.len() < 3 || magic[..] == FILE_MAGIC[..3] {
+        return Ok(());
+    }
+
+    let content = std::fs::read(&file)?;
+    let tmp = tmp_path(gen, path);
+    let mut writer = PositionBufWriter::new(create_file(&tmp)?)?;
+    write_header(&mut writer, keys.active_id())?;
+    let mut records = &content[..];
+    while let Some(command) = record::read_legacy(&mut records)? {
+        let at = Place { file: gen, offset: writer.pos };
+        writer.write_all(&record::encode(&command, compression, keys.active(), at)?)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    std::fs::rename(&tmp, &file)?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+fn state(path: &Path) -> Result<Vec<Generation>> {
+    file_ids(path, "sil")
+}
+
+/// Numbers of files named `<number>.<extension>` in ascending order
+fn file_ids(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut generations: Vec<u64> = std::fs::read_dir(path)?
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|fname| {
+            fname
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .map(str::parse::<Generation>)
+        })
+        .flatten()
+        .collect();
+
+    generations.sort_unstable();
+
+    Ok(generations)
+}
+
+/// Remove generations which were superseded by a committed compaction
+/// and temporary files left by an interrupted one.
+fn remove_stale_files(path: &Path, base: Generation) -> Result<()> {
+    for gen in state(path)?.into_iter().filter(|&gen| gen < base) {
+        std::fs::remove_file(gen_path(gen, path))?;
+    }
+
+    // a filter outliving its generation would lie about a new one with the same number
+    let generations = state(path)?;
+    for gen in file_ids(path, "bloom")? {
+        if !generations.contains(&gen) {
+            remove_filter(gen, path)?;
+        }
+    }
+
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.is_file() && file.extension() == Some("tmp".as_ref()) {
+            std::fs::remove_file(file)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    match std::fs::read(path.join(MANIFEST_FILE)) {
+        Ok(content) => Ok(rmp_serde::decode::from_slice(&content)?),
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Manifest { base: 0, horizon: 0, version: 0, blobs: 0 })
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the manifest atomically: write a temporary copy,
+/// sync it and rename it over the old one.
+fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp = path.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = create_file(&tmp)?;
+    file.write_all(&rmp_serde::encode::to_vec(manifest)?)?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp, path.join(MANIFEST_FILE))?;
+    sync_dir(path)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn create_file(path: &Path) -> std::io::Result<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+/// Create a generation whose records are sealed with `key_id`
+fn create_generation(
+    gen: Generation,
+    path: &Path,
+    key_id: KeyId,
+) -> Result<(PositionBufWriter<File>, GenerationReader)> {
+    create_sealed_file(&gen_path(gen, path), gen, key_id)
+}
+
+/// Create the file `id` of records sealed with `key_id`,
+/// generations and blob files share the layout
+fn create_sealed_file(path: &Path, id: u64, key_id: KeyId) -> Result<(PositionBufWriter<File>, GenerationReader)> {
+    let mut ops = std::fs::OpenOptions::new();
+    ops.read(true).write(true).create(true).append(true);
+
+    let mut writer = PositionBufWriter::new(ops.open(path)?)?;
+    write_header(&mut writer, key_id)?;
+
+    Ok((writer, GenerationReader::new(File::open(path)?, id)?))
+}
+
+fn write_header<W: Write + Seek>(writer: &mut PositionBufWriter<W>, key_id: KeyId) -> Result<()> {
+    writer.write_all(FILE_MAGIC)?;
+    writer.write_all(&key_id.to_be_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the header of a generation file,
+/// a file interrupted before its header was written holds no records.
+fn read_header<R: Read>(reader: &mut R) -> Result<FileHeader> {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(FileHeader::default()),
+        Err(err) => return Err(err.into()),
+    }
+
+    FileHeader::parse(&header).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "not a generation file").into()
+    })
+}
+
+/// Header of a file of sealed records
+#[derive(Clone, Copy, Debug)]
+struct FileHeader {
+    key_id: KeyId,
+    /// whether sealed records are bound to their place
+    bound: bool,
+}
+
+impl Default for FileHeader {
+    fn default() -> Self {
+        FileHeader {
+            key_id: crypto::PLAIN,
+            bound: true,
+        }
+    }
+}
+
+impl FileHeader {
+    fn parse(header: &[u8]) -> Option<Self> {
+        let header = header.get(..FILE_HEADER_SIZE as usize)?;
+        if header[..3] != FILE_MAGIC[..3] || !(UNBOUND_VERSION..=FILE_MAGIC[3]).contains(&header[3]) {
+            return None;
+        }
+
+        let mut key_id = [0; 8];
+        key_id.copy_from_slice(&header[4..]);
+        Some(FileHeader {
+            key_id: u64::from_be_bytes(key_id),
+            bound: header[3] != UNBOUND_VERSION,
+        })
+    }
+
+    /// Place a sealed record at `offset` of `file` is bound to
+    fn place(self, file: u64, offset: u64) -> Option<Place> {
+        match self.bound {
+            true => Some(Place { file, offset }),
+            false => None,
+        }
+    }
+}
+
+fn gen_path(gen: u64, dir: &Path) -> PathBuf {
+    dir.join(format!("{}.sil", gen))
+}
+
+fn tmp_path(gen: u64, dir: &Path) -> PathBuf {
+    dir.join(format!("{}.sil.tmp", gen))
+}
+
+fn blob_path(id: u64, dir: &Path) -> PathBuf {
+    dir.join(format!("{}.blob", id))
+}
+
+fn bloom_path(gen: Generation, dir: &Path) -> PathBuf {
+    dir.join(format!("{}.bloom", gen))
+}
+
+/// Manifest records the oldest generation which belongs to the store,
+/// everything below it was compacted away.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    base: Generation,
+    /// sequence number of the last change the base generation replaced,
+    /// 0 when it wasn't written by a compaction
+    #[serde(default)]
+    horizon: u64,
+    /// the last version handed out when the manifest was written
+    #[serde(default)]
+    version: u64,
+    /// id the next blob file gets, ids of collected files aren't handed out again
+    #[serde(default)]
+    blobs: u64,
+}
+
+/// Reader of a generation file which knows the key
+/// the generation is sealed with.
+///
+/// Sealed files, which are never written again, are memory mapped.
+struct GenerationReader {
+    file: File,
+    map: Option<Mmap>,
+    /// number of the generation or the blob file
+    id: u64,
+    key_id: KeyId,
+    /// whether sealed records are bound to their place
+    bound: bool,
+}
+
+impl GenerationReader {
+    fn open(gen: Generation, path: &Path) -> Result<Self> {
+        GenerationReader::sealed(File::open(gen_path(gen, path))?, gen)
+    }
+
+    /// Reader of the file being written now
+    fn new(file: File, id: u64) -> Result<Self> {
+        let header = read_header(&mut &file)?;
+
+        Ok(GenerationReader {
+            file,
+            map: None,
+            id,
+            key_id: header.key_id,
+            bound: header.bound,
+        })
+    }
+
+    /// Reader of a file which is never written again
+    fn sealed(file: File, id: u64) -> Result<Self> {
+        // an empty file cannot be mapped
+        let map = match file.metadata()?.len() {
+            0 => None,
+            // the file is immutable from now on, it is only removed
+            // by compaction once the reader is dropped
+            _ => Some(unsafe { Mmap::map(&file)? }),
+        };
+
+        Ok(GenerationReader {
+            map,
+            ..GenerationReader::new(file, id)?
+        })
+    }
+
+    fn place(&self, offset: u64) -> Option<Place> {
+        FileHeader { key_id: self.key_id, bound: self.bound }.place(self.id, offset)
+    }
+
+    /// Decode the record of `len` bytes at `pos`
+    fn command(&self, pos: u64, len: u64, keys: &Keyring) -> Result<Command> {
+        record::decode(&self.read(pos, len)?, keys.get(self.key_id)?, self.place(pos))
+    }
+
+    /// Read `len` bytes at `pos`,
+    /// from the map of a sealed file without copying
+    fn read(&self, pos: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        match &self.map {
+            Some(map) => map
+                .get(pos as usize..(pos + len) as usize)
+                .map(Cow::Borrowed)
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            None => {
+                let mut buffer = vec![0; len as usize];
+                read_at(&self.file, &mut buffer, pos)?;
+                Ok(Cow::Owned(buffer))
+            }
+        }
+    }
+
+    /// Buffered reader going through the records in order
+    fn scan(&self) -> Result<PositionBufReader<File>> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(FILE_HEADER_SIZE))?;
+        PositionBufReader::new(file)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CommandPos {
+    pos: u64,
+    len: u64,
+    gen: Generation,
+}
+
+impl From<(Generation, Range<u64>)> for CommandPos {
+    fn from((gen, range): (Generation, Range<u64>)) -> Self {
+        CommandPos {
+            pos: range.start,
+            len: range.end - range.start,
+            gen,
+        }
+    }
+}
+
+struct PositionBufReader<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> PositionBufReader<R> {
+    fn new(mut reader: R) -> Result<Self> {
+        let current_pos = reader.stream_position()?;
+
+        Ok(PositionBufReader {
+            reader: BufReader::new(reader),
+            pos: current_pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Seek for PositionBufReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+
+        Ok(self.pos)
+    }
+}
+
+impl<R: Read + Seek> Read for PositionBufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.pos += size as u64;
+
+        Ok(size)
+    }
+}
+
+struct PositionBufWriter<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> PositionBufWriter<W> {
+    fn new(mut writer: W) -> Result<Self> {
+        let current_pos = writer.stream_position()?;
+
+        Ok(PositionBufWriter {
+            writer: BufWriter::new(writer),
+            pos: current_pos,
+        })
+    }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+impl<W: Write + Seek> Seek for PositionBufWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+
+        Ok(self.pos)
+    }
+}
+
+impl<W: Write + Seek> Write for PositionBufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.writer.write(buf)?;
+        self.pos += size as u64;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
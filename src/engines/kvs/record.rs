@@ -0,0 +1,281 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::str::FromStr;
+use crate::Result;
+use super::crypto::EncryptionKey;
+
+// structure the record
+// |flags(1 byte)|size_of_payload(4 bytes)|payload(unsized)|
+const HEADER_SIZE: usize = 1 + 4;
+
+const FLAG_SNAPPY: u8 = 0b0000_0001;
+
+// versions were added to records later,
+// records written before them are read with version 0
+#[derive(Serialize, Deserialize)]
+pub(super) enum Command {
+    Remove {
+        key: String,
+        #[serde(default)]
+        version: u64,
+    },
+    Set {
+        key: String,
+        val: String,
+        #[serde(default)]
+        version: u64,
+    },
+    SetBlob {
+        key: String,
+        blob: BlobPos,
+        #[serde(default)]
+        version: u64,
+    },
+}
+
+impl Command {
+    pub(super) fn key(&self) -> &str {
+        match self {
+            Command::Remove { key, .. } | Command::Set { key, .. } | Command::SetBlob { key, .. } => key,
+        }
+    }
+
+    pub(super) fn version(&self) -> u64 {
+        match self {
+            Command::Remove { version, .. }
+            | Command::Set { version, .. }
+            | Command::SetBlob { version, .. } => *version,
+        }
+    }
+}
+
+/// Place of a record, a sealed record is bound to it along with its key
+/// so it doesn't open when it's moved to another file or offset
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Place {
+    /// number of the generation or the blob file
+    pub(super) file: u64,
+    pub(super) offset: u64,
+}
+
+impl Place {
+    fn aad(self, key: &EncryptionKey) -> [u8; 24] {
+        let mut aad = [0; 24];
+        aad[..8].copy_from_slice(&self.file.to_be_bytes());
+        aad[8..16].copy_from_slice(&self.offset.to_be_bytes());
+        aad[16..].copy_from_slice(&key.id().to_be_bytes());
+        aad
+    }
+}
+
+/// Location of a value moved out of the log to a blob file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct BlobPos {
+    pub(super) file: u64,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+}
+
+/// Compression applied to records written to the log
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Snappy,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            _ => Err(format!("unknown compression {}", s)),
+        }
+    }
+}
+
+impl Compression {
+    fn flags(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Snappy => FLAG_SNAPPY,
+        }
+    }
+
+    fn from_flags(flags: u8) -> Self {
+        if flags & FLAG_SNAPPY != 0 {
+            Compression::Snappy
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Encode a command into a record stored at `at`
+pub(super) fn encode(
+    c: &Command,
+    compression: Compression,
+    key: Option<&EncryptionKey>,
+    at: Place,
+) -> Result<Vec<u8>> {
+    seal(rmp_serde::encode::to_vec(c)?, compression, key, at)
+}
+
+/// Encode a raw value into a record of a blob file stored at `at`
+pub(super) fn encode_value(
+    val: &[u8],
+    compression: Compression,
+    key: Option<&EncryptionKey>,
+    at: Place,
+) -> Result<Vec<u8>> {
+    seal(val.to_vec(), compression, key, at)
+}
+
+/// Frame a payload into a record.
+///
+/// The payload stays uncompressed when compression doesn't make it smaller,
+/// so records of both kinds are mixed in a log.
+/// The payload is compressed before it's sealed with `key`.
+fn seal(payload: Vec<u8>, compression: Compression, key: Option<&EncryptionKey>, at: Place) -> Result<Vec<u8>> {
+    let (compression, payload) = match compression {
+        Compression::None => (Compression::None, payload),
+        Compression::Snappy => {
+            let compressed = snap::raw::Encoder::new().compress_vec(&payload)?;
+            if compressed.len() < payload.len() {
+                (Compression::Snappy, compressed)
+            } else {
+                (Compression::None, payload)
+            }
+        }
+    };
+    let payload = match key {
+        Some(key) => key.encrypt(&payload, &at.aad(key))?,
+        None => payload,
+    };
+
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+    record.write_u8(compression.flags())?;
+    record.write_u32::<BigEndian>(payload.len() as u32)?;
+    record.extend_from_slice(&payload);
+
+    Ok(record)
+}
+
+/// Decode a record read as a whole.
+///
+/// `at` is the place a sealed record is bound to,
+/// `None` for records of files written before records were bound.
+pub(super) fn decode(mut record: &[u8], key: Option<&EncryptionKey>, at: Option<Place>) -> Result<Command> {
+    read(&mut record, key, at)?.ok_or_else(torn)
+}
+
+/// Decode a record of a blob file
+pub(super) fn decode_value(mut record: &[u8], key: Option<&EncryptionKey>, at: Option<Place>) -> Result<Vec<u8>> {
+    unseal(&mut record, key, at)?.ok_or_else(torn)
+}
+
+/// Read the next record from a log, `None` at its end.
+///
+/// A record cut short by a crash ends the log as well,
+/// while a complete one which doesn't decode is an error.
+pub(super) fn read<R: Read>(
+    reader: &mut R,
+    key: Option<&EncryptionKey>,
+    at: Option<Place>,
+) -> Result<Option<Command>> {
+    match unseal(reader, key, at)? {
+        Some(payload) => Ok(Some(rmp_serde::decode::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+/// Read the next record of a log written before records were framed,
+/// they were bare commands of the versions with no version numbers
+pub(super) fn read_legacy(reader: &mut &[u8]) -> Result<Option<Command>> {
+    use rmp_serde::decode::Error;
+
+    if reader.is_empty() {
+        return Ok(None);
+    }
+
+    match Command::deserialize(&mut rmp_serde::Deserializer::new(reader)) {
+        Ok(command) => Ok(Some(command)),
+        Err(Error::InvalidMarkerRead(ref err)) | Err(Error::InvalidDataRead(ref err))
+            if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Read the header of a record, returning how its payload is compressed
+/// and the payload size
+pub(super) fn read_header<R: Read>(reader: &mut R) -> Result<(Compression, u32)> {
+    let flags = reader.read_u8()?;
+    if flags & !FLAG_SNAPPY != 0 {
+        let msg = format!("unknown record flags {:#010b}", flags);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg).into());
+    }
+
+    let size = reader.read_u32::<BigEndian>()?;
+    Ok((Compression::from_flags(flags), size))
+}
+
+/// Read the payload of the next record, `None` when the log ends before it does
+fn unseal<R: Read>(reader: &mut R, key: Option<&EncryptionKey>, at: Option<Place>) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_SIZE];
+    let (compression, size) = match read_full(reader, &mut header)? {
+        true => read_header(&mut &header[..])?,
+        false => return Ok(None),
+    };
+    let mut payload = vec![0; size as usize];
+    if !read_full(reader, &mut payload)? {
+        return Ok(None);
+    }
+
+    if let Some(key) = key {
+        payload = match at {
+            Some(at) => key.decrypt(&payload, &at.aad(key))?,
+            None => key.decrypt(&payload, &[])?,
+        };
+    }
+
+    Ok(Some(match compression {
+        Compression::None => payload,
+        Compression::Snappy => snap::raw::Decoder::new().decompress_vec(&payload)?,
+    }))
+}
+
+/// Fill `buf`, false when the reader ends first
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn torn() -> crate::KvsError {
+    std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+}
+
+/// Length of the record framed at the start of `data`,
+/// `None` when its header cannot be one of a record which fits in `data`
+pub(super) fn framed_len(data: &[u8]) -> Option<usize> {
+    let header = data.get(..HEADER_SIZE)?;
+    if header[0] & !FLAG_SNAPPY != 0 {
+        return None;
+    }
+
+    let size = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    Some(HEADER_SIZE + size).filter(|&len| len <= data.len())
+}
+
+/// Compression the record was written with
+pub(super) fn compression(record: &[u8]) -> Compression {
+    Compression::from_flags(record[0])
+}
@@ -0,0 +1,366 @@
+use memmap::Mmap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use crate::Result;
+use crate::engines::DirLock;
+use super::crypto::{self, EncryptionKey, Keyring};
+use super::record::{self, BlobPos, Command, Place};
+use super::{
+    blob_path, create_file, gen_path, read_manifest, remove_stale_files, state, sync_dir,
+    upgrade_legacy, write_header, write_manifest, FileHeader, Generation, KvStore,
+    KvStoreOptions, Manifest, PositionBufWriter, FILE_HEADER_SIZE,
+};
+
+// a repair writes the fresh generation here before it's renamed in place
+static REPAIR_DIR: &str = "repair.tmp";
+
+/// Layout of a generation file
+#[derive(Clone, Debug)]
+pub struct GenerationInfo {
+    pub generation: u64,
+    /// size of the file in bytes
+    pub size: u64,
+    /// number of records which decode
+    pub records: u64,
+    /// id of the key the generation is sealed with, 0 for plain text
+    pub key_id: u64,
+}
+
+/// Bytes of a file in which no record decodes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Corruption {
+    pub file: PathBuf,
+    pub range: Range<u64>,
+}
+
+/// VerifyReport describes the state of a KvStore directory
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// the oldest generation which belongs to the store
+    pub base: u64,
+    pub generations: Vec<GenerationInfo>,
+    /// number of live keys whose values can be read
+    pub keys: usize,
+    /// bytes of records holding live values
+    pub live_bytes: u64,
+    /// bytes of overwritten values and removes compaction would drop
+    pub dead_bytes: u64,
+    pub corrupted: Vec<Corruption>,
+    /// live keys whose values are kept in corrupted blobs
+    pub lost: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Share of the bytes of records which are dead
+    pub fn dead_ratio(&self) -> f64 {
+        match self.live_bytes + self.dead_bytes {
+            0 => 0.0,
+            total => self.dead_bytes as f64 / total as f64,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.corrupted.is_empty() && self.lost.is_empty()
+    }
+}
+
+/// RepairReport tells what a repair salvaged
+#[derive(Clone, Debug)]
+pub struct RepairReport {
+    /// generation the live records were written to
+    pub generation: u64,
+    /// number of keys salvaged
+    pub keys: usize,
+    /// live keys whose values are kept in corrupted blobs
+    pub lost: Vec<String>,
+    /// directory the replaced generation files were moved to
+    pub moved_to: PathBuf,
+}
+
+impl KvStore {
+    /// Verify walks every generation of the store in `folder` decoding each record.
+    ///
+    /// Unlike `open` it doesn't fail at the first record which doesn't decode,
+    /// it looks for the next one and reports the bytes in between.
+    pub fn verify(folder: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<VerifyReport> {
+        Ok(Survey::run(&folder.into(), options)?.report)
+    }
+
+    /// Repair writes every live record which can be read to a fresh generation
+    /// and makes it the base of the store.
+    ///
+    /// The generations it replaces are moved to a `repaired-<generation>`
+    /// directory inside `folder` rather than removed. The directory is locked
+    /// meanwhile, it's refused while a server holds it.
+    ///
+    /// The fresh generation is written to a temporary directory and renamed in
+    /// place, the replaced ones are moved before the manifest makes it the base,
+    /// so a crash at any point loses none of them.
+    pub fn repair(folder: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<RepairReport> {
+        let path = folder.into();
+        let _lock = DirLock::acquire(&path)?;
+        // generations written before records were framed aren't corrupted,
+        // the survey reports the ones which don't parse as legacy ones either
+        let keys = Keyring::new(options.encryption.clone(), options.retired_keys.clone());
+        for gen in state(&path)? {
+            if let Err(err) = upgrade_legacy(gen, &path, &keys, options.compression) {
+                log::warn!("cannot upgrade generation {}: {}", gen, err);
+            }
+        }
+        let survey = Survey::run(&path, options)?;
+
+        let gen = state(&path)?.last().map_or(0, |&g| g + 1);
+        let tmp_dir = path.join(REPAIR_DIR);
+        if tmp_dir.exists() {
+            std::fs::remove_dir_all(&tmp_dir)?;
+        }
+        std::fs::create_dir(&tmp_dir)?;
+        let tmp = gen_path(gen, &tmp_dir);
+        let mut file = PositionBufWriter::new(create_file(&tmp)?)?;
+        write_header(&mut file, survey.keys.active_id())?;
+
+        let mut live: Vec<_> = survey.live.values().collect();
+        live.sort_by_key(|live| (live.gen, live.range.start));
+        let mut keys = 0;
+        for live in live.into_iter().filter(|live| !live.lost) {
+            let command = survey.command(live)?;
+            let at = Place { file: gen, offset: file.pos };
+            let b = record::encode(&command, options.compression, survey.keys.active(), at)?;
+            file.write_all(&b)?;
+            keys += 1;
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        sync_dir(&tmp_dir)?;
+
+        std::fs::rename(&tmp, gen_path(gen, &path))?;
+        std::fs::remove_dir(&tmp_dir)?;
+        sync_dir(&path)?;
+
+        let moved_to = path.join(format!("repaired-{}", gen));
+        std::fs::create_dir_all(&moved_to)?;
+        for old in state(&path)?.into_iter().filter(|&old| old < gen) {
+            std::fs::rename(gen_path(old, &path), gen_path(old, &moved_to))?;
+        }
+        sync_dir(&moved_to)?;
+        sync_dir(&path)?;
+
+        // versions and blob ids handed out before stay taken
+        let Manifest { version, blobs, .. } = read_manifest(&path)?;
+        write_manifest(&path, &Manifest { base: gen, horizon: 0, version, blobs })?;
+        remove_stale_files(&path, gen)?;
+
+        Ok(RepairReport {
+            generation: gen,
+            keys,
+            lost: survey.report.lost,
+            moved_to,
+        })
+    }
+}
+
+/// Map of a file of sealed records with its header
+pub(super) type Mapped = (Option<Mmap>, FileHeader);
+
+/// Position of the latest record of a key
+struct Live {
+    gen: Generation,
+    range: Range<u64>,
+    blob: Option<BlobPos>,
+    lost: bool,
+}
+
+/// Survey keeps the files of a store mapped along with what was found in them
+struct Survey {
+    report: VerifyReport,
+    keys: Keyring,
+    maps: BTreeMap<Generation, Mapped>,
+    live: HashMap<String, Live>,
+}
+
+impl Survey {
+    fn run(path: &Path, options: &KvStoreOptions) -> Result<Self> {
+        let keys = Keyring::new(options.encryption.clone(), options.retired_keys.clone());
+        let base = read_manifest(path)?.base;
+        let mut report = VerifyReport {
+            base,
+            ..VerifyReport::default()
+        };
+
+        let mut maps = BTreeMap::new();
+        let mut live: HashMap<String, Live> = HashMap::new();
+        let mut total = 0;
+        for gen in state(path)?.into_iter().filter(|&gen| gen >= base) {
+            let file = gen_path(gen, path);
+            let (map, header) = match map_sealed(&file)? {
+                Ok(mapped) => mapped,
+                Err(range) => {
+                    report.corrupted.push(Corruption { file, range: range.clone() });
+                    report.generations.push(GenerationInfo {
+                        generation: gen,
+                        size: range.end,
+                        records: 0,
+                        key_id: crypto::PLAIN,
+                    });
+                    continue;
+                }
+            };
+            let data = map.as_ref().map_or(&[][..], |map| &map[..]);
+
+            let mut records = 0;
+            for found in Records::new(data, keys.get(header.key_id)?, header, gen) {
+                match found {
+                    Found::Record { range, command } => {
+                        records += 1;
+                        total += range.end - range.start;
+                        match command {
+                            Command::Set { key, .. } => {
+                                live.insert(key, Live { gen, range, blob: None, lost: false });
+                            }
+                            Command::SetBlob { key, blob, .. } => {
+                                live.insert(key, Live { gen, range, blob: Some(blob), lost: false });
+                            }
+                            Command::Remove { key, .. } => {
+                                live.remove(&key);
+                            }
+                        }
+                    }
+                    Found::Corrupted(range) => report.corrupted.push(Corruption {
+                        file: file.clone(),
+                        range,
+                    }),
+                }
+            }
+
+            report.generations.push(GenerationInfo {
+                generation: gen,
+                size: data.len() as u64,
+                records,
+                key_id: header.key_id,
+            });
+            maps.insert(gen, (map, header));
+        }
+
+        // values kept in blob files are read as well
+        let mut blobs = HashMap::new();
+        for (key, live) in &mut live {
+            if let Some(blob) = &live.blob {
+                let file = blob_path(blob.file, path);
+                let mapped = blobs
+                    .entry(blob.file)
+                    .or_insert_with(|| map_sealed(&file).ok().and_then(|mapped| mapped.ok()));
+
+                let value = mapped.as_ref().and_then(|(map, header)| {
+                    let record = map.as_ref()?.get(blob.pos as usize..(blob.pos + blob.len) as usize)?;
+                    let at = header.place(blob.file, blob.pos);
+                    record::decode_value(record, keys.get(header.key_id).ok()?, at).ok()
+                });
+                if value.is_none() {
+                    live.lost = true;
+                    report.lost.push(key.clone());
+                    report.corrupted.push(Corruption {
+                        file,
+                        range: blob.pos..blob.pos + blob.len,
+                    });
+                }
+            }
+        }
+        report.lost.sort();
+
+        report.keys = live.values().filter(|live| !live.lost).count();
+        report.live_bytes = live.values().map(|live| live.range.end - live.range.start).sum();
+        report.dead_bytes = total - report.live_bytes;
+
+        Ok(Survey { report, keys, maps, live })
+    }
+
+    /// Decode the record of a live key again
+    fn command(&self, live: &Live) -> Result<Command> {
+        let (map, header) = &self.maps[&live.gen];
+        let data = map.as_ref().map_or(&[][..], |map| &map[..]);
+        let record = &data[live.range.start as usize..live.range.end as usize];
+        record::decode(record, self.keys.get(header.key_id)?, header.place(live.gen, live.range.start))
+    }
+}
+
+/// Map a file of sealed records reading its header.
+///
+/// A file whose header is torn or isn't one of a generation
+/// is returned as a corrupted range.
+pub(super) fn map_sealed(file: &Path) -> Result<std::result::Result<Mapped, Range<u64>>> {
+    let f = File::open(file)?;
+    let size = f.metadata()?.len();
+    if size == 0 {
+        return Ok(Ok((None, FileHeader::default())));
+    }
+
+    let map = unsafe { Mmap::map(&f)? };
+    match FileHeader::parse(&map) {
+        Some(header) => Ok(Ok((Some(map), header))),
+        None => Ok(Err(0..size)),
+    }
+}
+
+/// What was found at an offset of a file of records
+pub(super) enum Found {
+    Record { range: Range<u64>, command: Command },
+    Corrupted(Range<u64>),
+}
+
+/// Records goes through the records of a mapped generation file.
+///
+/// Past bytes which don't decode to a record it moves on byte by byte
+/// until a record decodes again.
+pub(super) struct Records<'a> {
+    data: &'a [u8],
+    pos: usize,
+    key: Option<&'a EncryptionKey>,
+    header: FileHeader,
+    /// number of the generation
+    file: u64,
+}
+
+impl<'a> Records<'a> {
+    pub(super) fn new(data: &'a [u8], key: Option<&'a EncryptionKey>, header: FileHeader, file: u64) -> Self {
+        Records {
+            data,
+            pos: (FILE_HEADER_SIZE as usize).min(data.len()),
+            key,
+            header,
+            file,
+        }
+    }
+
+    fn record_at(&self, pos: usize) -> Option<(Command, usize)> {
+        let len = record::framed_len(&self.data[pos..])?;
+        let at = self.header.place(self.file, pos as u64);
+        let command = record::decode(&self.data[pos..pos + len], self.key, at).ok()?;
+        Some((command, len))
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Found;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.pos;
+        if start >= self.data.len() {
+            return None;
+        }
+
+        if let Some((command, len)) = self.record_at(start) {
+            self.pos += len;
+            let range = start as u64..self.pos as u64;
+            return Some(Found::Record { range, command });
+        }
+
+        self.pos += 1;
+        while self.pos < self.data.len() && self.record_at(self.pos).is_none() {
+            self.pos += 1;
+        }
+        Some(Found::Corrupted(start as u64..self.pos as u64))
+    }
+}
@@ -0,0 +1,51 @@
+use std::iter::Peekable;
+use crate::Result;
+use super::Entry;
+
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Merge joins sorted sources into one sorted stream.
+///
+/// Sources go from the newest to the oldest,
+/// so for a key found in several of them the first one wins.
+pub(super) struct Merge<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    pub(super) fn new(sources: Vec<Source<'a>>) -> Self {
+        Merge {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) if min.as_ref().is_none_or(|(_, min)| key < min) => {
+                    min = Some((i, key.clone()));
+                }
+                _ => {}
+            }
+        }
+
+        let (i, key) = min?;
+        let entry = self.sources[i].next();
+        // older entries of the key are shadowed
+        for source in &mut self.sources[i + 1..] {
+            if let Some(Ok((k, _))) = source.peek() {
+                if *k == key {
+                    source.next();
+                }
+            }
+        }
+
+        entry
+    }
+}
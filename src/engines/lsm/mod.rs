@@ -0,0 +1,514 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use crate::{KvsError, Result};
+use super::{create_checkpoint_dir, link_or_copy, KvsEngine, Pairs};
+use merge::{Merge, Source};
+use table::{Table, TableWriter};
+use wal::Wal;
+
+mod merge;
+mod table;
+mod wal;
+
+static MANIFEST_FILE: &str = "MANIFEST";
+static WAL_FILE: &str = "wal";
+
+/// Key with its value, `None` marks a removed key
+type Entry = (String, Option<String>);
+
+/// Options tune when an LsmStore flushes and compacts
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    /// bytes of keys and values the memtable holds before it's flushed
+    pub memtable_size: usize,
+    /// number of flushed tables which triggers a compaction
+    pub level0_tables: usize,
+    /// size the compacted tables are split at
+    pub table_size: u64,
+    /// bytes of tables level 1 holds before one is compacted to level 2,
+    /// every deeper level holds ten times more than the one above
+    pub level1_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 << 20,
+            level0_tables: 4,
+            table_size: 2 << 20,
+            level1_size: 10 << 20,
+        }
+    }
+}
+
+/// LsmStore is a log-structured merge tree.
+///
+/// Writes go to the write-ahead log and a sorted memtable, which is
+/// flushed to a table of level 0 once it's full. Tables of level 0 may
+/// overlap, a compaction merges them with the overlapping tables of
+/// level 1. Tables of deeper levels are sorted and never overlap, once
+/// a level outgrows its size one of its tables is merged into the next.
+pub struct LsmStore {
+    path: PathBuf,
+    options: LsmOptions,
+    memtable: BTreeMap<String, Option<String>>,
+    memtable_size: usize,
+    wal: Wal,
+    // level 0 from the oldest to the newest, the others ordered by keys
+    levels: Vec<Vec<Table>>,
+    // last key compacted out of each level, the next compaction goes on from it
+    compact_keys: Vec<String>,
+    next_table: u64,
+}
+
+impl LsmStore {
+    /// Create new object of storage
+    pub fn open(folder: impl Into<PathBuf>) -> Result<Self> {
+        LsmStore::open_with_options(folder, LsmOptions::default())
+    }
+
+    /// Create new object of storage configured by `options`
+    pub fn open_with_options(folder: impl Into<PathBuf>, options: LsmOptions) -> Result<Self> {
+        let path = folder.into();
+        let manifest = read_manifest(&path)?;
+        remove_stale_files(&path, &manifest)?;
+
+        let levels = manifest
+            .levels()
+            .map(|level| level.iter().map(|&id| Table::open(id, &path)).collect())
+            .collect::<Result<_>>()?;
+
+        let (wal, entries) = Wal::open(&path.join(WAL_FILE))?;
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        for (key, val) in entries {
+            memtable_size += key.len() + val.as_ref().map_or(0, String::len);
+            memtable.insert(key, val);
+        }
+
+        Ok(LsmStore {
+            path,
+            options,
+            memtable,
+            memtable_size,
+            wal,
+            levels,
+            compact_keys: Vec::new(),
+            next_table: manifest.next_table,
+        })
+    }
+
+    /// Scan returns the pairs whose keys fall into `range` in ascending order of keys
+    pub fn scan<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<Vec<(String, String)>> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let reversed = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        if reversed {
+            return Ok(Vec::new());
+        }
+
+        let mut pairs = Vec::new();
+        for entry in self.entries(start, end) {
+            let (key, val) = entry?;
+            let in_range = match end {
+                Bound::Included(end) => key.as_str() <= end,
+                Bound::Excluded(end) => key.as_str() < end,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                break;
+            }
+
+            if let Some(val) = val {
+                pairs.push((key, val));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    /// Entries of every level starting from `start`, newer ones shadow older ones.
+    ///
+    /// Only the memtable is cut at `end`, the tables are read up to their ends.
+    fn entries<'a>(&'a self, start: Bound<&'a str>, end: Bound<&'a str>) -> Merge<'a> {
+        let mut sources: Vec<Source> = Vec::new();
+        sources.push(Box::new(
+            self.memtable
+                .range::<str, _>((start, end))
+                .map(|(key, val)| Ok((key.clone(), val.clone()))),
+        ));
+        for table in self.levels[0].iter().rev() {
+            sources.push(Box::new(table.iter(start)));
+        }
+        for level in &self.levels[1..] {
+            sources.push(Box::new(level.iter().flat_map(move |table| table.iter(start))));
+        }
+
+        Merge::new(sources)
+    }
+
+    /// Flush writes the memtable to a new table of level 0
+    /// and empties the write-ahead log.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let id = self.next_table;
+        let mut writer = TableWriter::create(&table_path(id, &self.path))?;
+        for (key, val) in &self.memtable {
+            writer.add(key.clone(), val.clone())?;
+        }
+        writer.finish()?;
+        sync_dir(&self.path)?;
+
+        self.next_table += 1;
+        self.levels[0].push(Table::open(id, &self.path)?);
+        self.write_manifest()?;
+
+        self.wal.reset()?;
+        self.memtable.clear();
+        self.memtable_size = 0;
+
+        if self.levels[0].len() >= self.options.level0_tables {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Compact merges the tables of level 0 with the tables of level 1
+    /// they overlap.
+    ///
+    /// A level grown past its size merges its tables one by one
+    /// into the next level until it fits again.
+    pub fn compact(&mut self) -> Result<()> {
+        let level0: Vec<u64> = self.levels[0].iter().map(Table::id).collect();
+        if level0.is_empty() {
+            return Ok(());
+        }
+        self.merge_down(0, &level0)?;
+
+        let mut level = 1;
+        while level < self.levels.len() {
+            while self.levels[level].iter().map(Table::size).sum::<u64>() > self.level_size(level) {
+                let id = self.pick_table(level);
+                self.merge_down(level, &[id])?;
+            }
+            level += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Merge the tables `ids` of `level` with the tables of the next level they overlap.
+    ///
+    /// Removed keys are dropped for good once no deeper level may hold them.
+    fn merge_down(&mut self, level: usize, ids: &[u64]) -> Result<()> {
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+        }
+
+        let upper: Vec<&Table> = self.levels[level].iter().filter(|table| ids.contains(&table.id())).collect();
+        let first = upper.iter().map(|table| table.first_key()).min().unwrap_or_default().to_owned();
+        let last = upper.iter().map(|table| table.last_key()).max().unwrap_or_default().to_owned();
+        let overlaps =
+            |table: &Table| table.last_key() >= first.as_str() && table.first_key() <= last.as_str();
+        let bottom = self.levels[level + 2..].iter().all(Vec::is_empty);
+
+        let mut next_table = self.next_table;
+        let created = {
+            let mut sources: Vec<Source> = Vec::new();
+            for table in upper.iter().rev() {
+                sources.push(Box::new(table.iter(Bound::Unbounded)));
+            }
+            sources.push(Box::new(
+                self.levels[level + 1]
+                    .iter()
+                    .filter(|table| overlaps(table))
+                    .flat_map(|table| table.iter(Bound::Unbounded)),
+            ));
+
+            let entries = Merge::new(sources);
+            write_tables(&self.path, entries, &mut next_table, self.options.table_size, bottom)?
+        };
+        self.next_table = next_table;
+
+        let (mut stale, kept): (Vec<Table>, Vec<Table>) =
+            std::mem::take(&mut self.levels[level]).into_iter().partition(|table| ids.contains(&table.id()));
+        self.levels[level] = kept;
+        let (overlapped, mut lower): (Vec<Table>, Vec<Table>) =
+            std::mem::take(&mut self.levels[level + 1]).into_iter().partition(|table| overlaps(table));
+        lower.extend(created);
+        lower.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.levels[level + 1] = lower;
+        stale.extend(overlapped);
+        self.write_manifest()?;
+
+        // maps of the stale tables are dropped before their files are removed
+        let ids: Vec<u64> = stale.iter().map(Table::id).collect();
+        drop(stale);
+        for id in ids {
+            std::fs::remove_file(table_path(id, &self.path))?;
+        }
+
+        Ok(())
+    }
+
+    /// Bytes of tables `level` holds before it's compacted
+    fn level_size(&self, level: usize) -> u64 {
+        let scale = 10u64.saturating_pow(level as u32 - 1);
+        self.options.level1_size.saturating_mul(scale)
+    }
+
+    /// Table of `level` to compact next, levels are compacted round their keys
+    fn pick_table(&mut self, level: usize) -> u64 {
+        if self.compact_keys.len() <= level {
+            self.compact_keys.resize(level + 1, String::new());
+        }
+
+        let tables = &self.levels[level];
+        let table = tables
+            .iter()
+            .find(|table| table.first_key() > self.compact_keys[level].as_str())
+            .unwrap_or(&tables[0]);
+        self.compact_keys[level] = table.last_key().to_owned();
+        table.id()
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<String>> {
+        if let Some(val) = self.memtable.get(key) {
+            return Ok(val.clone());
+        }
+
+        for table in self.levels[0].iter().rev() {
+            if let Some(val) = table.get(key)? {
+                return Ok(val);
+            }
+        }
+
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|table| table.last_key() < key);
+            if let Some(val) = level.get(i).map(|table| table.get(key)).transpose()?.flatten() {
+                return Ok(val);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Put an entry to the log and the memtable
+    fn put(&mut self, key: String, val: Option<String>) -> Result<()> {
+        self.wal.append(&key, val.as_deref())?;
+        self.memtable_size += key.len() + val.as_ref().map_or(0, String::len);
+        self.memtable.insert(key, val);
+
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let mut levels = self.levels.iter().map(|level| level.iter().map(Table::id).collect());
+        let manifest = Manifest {
+            next_table: self.next_table,
+            level0: levels.next().unwrap_or_default(),
+            level1: levels.next().unwrap_or_default(),
+            deeper: levels.collect(),
+        };
+
+        let tmp = self.path.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&rmp_serde::encode::to_vec(&manifest)?)?;
+        file.sync_all()?;
+
+        std::fs::rename(&tmp, self.path.join(MANIFEST_FILE))?;
+        sync_dir(&self.path)?;
+
+        Ok(())
+    }
+}
+
+impl KvsEngine for LsmStore {
+    /// Get method tries to find value with `key`
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.lookup(&key)
+    }
+
+    /// Set put new value in storage by key
+    /// it rewrite value if that alredy exists
+    fn set(&mut self, key: String, val: String) -> Result<()> {
+        self.put(key, Some(val))
+    }
+
+    /// Delete key value pair from storage
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.lookup(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+
+        self.put(key, None)
+    }
+
+    /// Pairs come in ascending order of keys
+    fn pairs(&mut self) -> Result<Pairs<'_>> {
+        let entries = self.entries(Bound::Unbounded, Bound::Unbounded);
+        Ok(Box::new(entries.filter_map(|entry| match entry {
+            Ok((key, Some(val))) => Some(Ok((key, val))),
+            Ok((_, None)) => None,
+            Err(err) => Some(Err(err)),
+        })))
+    }
+
+    /// The memtable is flushed first, after that the tables
+    /// hold everything and they're hard linked into `dest`.
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        self.flush()?;
+
+        for table in self.levels.iter().flatten() {
+            link_or_copy(&table_path(table.id(), &self.path), &table_path(table.id(), dest))?;
+        }
+        let manifest = self.path.join(MANIFEST_FILE);
+        if manifest.exists() {
+            link_or_copy(&manifest, &dest.join(MANIFEST_FILE))?;
+        }
+
+        sync_dir(dest)?;
+        Ok(())
+    }
+
+    fn page(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        self.entries(start, Bound::Unbounded)
+            .filter_map(|entry| match entry {
+                Ok((key, Some(val))) => Some(Ok((key, val))),
+                Ok((_, None)) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// The memtable is kept by the write-ahead log, syncing it is enough
+    fn sync(&mut self) -> Result<()> {
+        self.wal.sync()
+    }
+}
+
+/// Write merged entries to tables of about `table_size` bytes each,
+/// removed keys are left out of the `bottom` level
+fn write_tables(
+    path: &Path,
+    entries: impl Iterator<Item = Result<Entry>>,
+    next_table: &mut u64,
+    table_size: u64,
+    bottom: bool,
+) -> Result<Vec<Table>> {
+    let mut created = Vec::new();
+    let mut writer: Option<(u64, TableWriter)> = None;
+    for entry in entries {
+        let (key, val) = match entry? {
+            (_, None) if bottom => continue,
+            entry => entry,
+        };
+
+        if writer.is_none() {
+            let id = *next_table;
+            *next_table += 1;
+            writer = Some((id, TableWriter::create(&table_path(id, path))?));
+        }
+        let (_, table) = writer.as_mut().expect("GG: no table writer");
+        table.add(key, val)?;
+
+        if table.size() >= table_size {
+            let (id, table) = writer.take().expect("GG: no table writer");
+            table.finish()?;
+            created.push(Table::open(id, path)?);
+        }
+    }
+
+    if let Some((id, table)) = writer {
+        table.finish()?;
+        created.push(Table::open(id, path)?);
+    }
+    sync_dir(path)?;
+
+    Ok(created)
+}
+
+/// Remove tables no level refers to, left by an interrupted flush or compaction
+fn remove_stale_files(path: &Path, manifest: &Manifest) -> Result<()> {
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if !file.is_file() {
+            continue;
+        }
+
+        let stale = match file.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("tmp") => true,
+            Some("sst") => file
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .is_some_and(|id| !manifest.levels().any(|level| level.contains(&id))),
+            _ => false,
+        };
+        if stale {
+            std::fs::remove_file(file)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    match std::fs::read(path.join(MANIFEST_FILE)) {
+        Ok(content) => Ok(rmp_serde::decode::from_slice(&content)?),
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn table_path(id: u64, dir: &Path) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Manifest records the tables of every level
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    next_table: u64,
+    level0: Vec<u64>,
+    level1: Vec<u64>,
+    /// levels past level 1, missing from the manifests of two levels
+    #[serde(default)]
+    deeper: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    fn levels(&self) -> impl Iterator<Item = &Vec<u64>> {
+        std::iter::once(&self.level0).chain(Some(&self.level1)).chain(&self.deeper)
+    }
+}
@@ -0,0 +1,242 @@
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use memmap::Mmap;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::ops::Bound;
+use std::path::Path;
+use crate::Result;
+use super::{table_path, Entry};
+
+// structure the table file
+// |blocks(unsized)|index(unsized)|index_offset(8 bytes)|magic(4 bytes)|
+// a block is a msgpack array of entries sorted by key
+static TABLE_MAGIC: &[u8; 4] = b"SST\x01";
+const FOOTER_SIZE: usize = 8 + 4;
+const BLOCK_SIZE: usize = 4096;
+
+/// Location of a block along with the last key it holds
+#[derive(Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableIndex {
+    first_key: String,
+    blocks: Vec<BlockHandle>,
+}
+
+/// TableWriter writes entries given in ascending order of keys to a new table
+pub(super) struct TableWriter {
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<Entry>,
+    block_size: usize,
+    index: TableIndex,
+}
+
+impl TableWriter {
+    pub(super) fn create(path: &Path) -> Result<Self> {
+        Ok(TableWriter {
+            writer: BufWriter::new(File::create(path)?),
+            offset: 0,
+            block: Vec::new(),
+            block_size: 0,
+            index: TableIndex {
+                first_key: String::new(),
+                blocks: Vec::new(),
+            },
+        })
+    }
+
+    pub(super) fn add(&mut self, key: String, val: Option<String>) -> Result<()> {
+        if self.offset == 0 && self.block.is_empty() {
+            self.index.first_key = key.clone();
+        }
+
+        self.block_size += key.len() + val.as_ref().map_or(0, String::len);
+        self.block.push((key, val));
+        if self.block_size >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+
+        Ok(())
+    }
+
+    /// Bytes the table takes so far
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block_size as u64
+    }
+
+    /// Write the index and sync the table
+    pub(super) fn finish(mut self) -> Result<()> {
+        self.flush_block()?;
+
+        let index = rmp_serde::encode::to_vec(&self.index)?;
+        self.writer.write_all(&index)?;
+        self.writer.write_u64::<BigEndian>(self.offset)?;
+        self.writer.write_all(TABLE_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        let last_key = match self.block.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+
+        let block = rmp_serde::encode::to_vec(&self.block)?;
+        self.writer.write_all(&block)?;
+        self.index.blocks.push(BlockHandle {
+            last_key,
+            offset: self.offset,
+            len: block.len() as u64,
+        });
+        self.offset += block.len() as u64;
+        self.block.clear();
+        self.block_size = 0;
+
+        Ok(())
+    }
+}
+
+/// Table is a sorted immutable file of entries,
+/// its block index is kept in memory and the blocks are memory mapped.
+pub(super) struct Table {
+    id: u64,
+    map: Mmap,
+    index: TableIndex,
+}
+
+impl Table {
+    pub(super) fn open(id: u64, path: &Path) -> Result<Self> {
+        let file = File::open(table_path(id, path))?;
+        // a table is never written again once it's finished
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < FOOTER_SIZE || &map[map.len() - 4..] != TABLE_MAGIC {
+            return Err(invalid("not a table file"));
+        }
+
+        let index_end = map.len() - FOOTER_SIZE;
+        let index_offset = BigEndian::read_u64(&map[index_end..]);
+        if index_offset > index_end as u64 {
+            return Err(invalid("index of the table is out of the file"));
+        }
+        let index: TableIndex = rmp_serde::decode::from_slice(&map[index_offset as usize..index_end])?;
+        let in_file = |block: &BlockHandle| {
+            block.offset.checked_add(block.len).is_some_and(|end| end <= index_offset)
+        };
+        if !index.blocks.iter().all(in_file) {
+            return Err(invalid("block of the table is out of the file"));
+        }
+
+        Ok(Table { id, map, index })
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Bytes the table takes on disk
+    pub(super) fn size(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    pub(super) fn first_key(&self) -> &str {
+        &self.index.first_key
+    }
+
+    pub(super) fn last_key(&self) -> &str {
+        self.index.blocks.last().map_or("", |block| &block.last_key)
+    }
+
+    /// Entry of `key`, `Some(None)` tells the key was removed
+    pub(super) fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key() || key > self.last_key() {
+            return Ok(None);
+        }
+
+        let i = self.index.blocks.partition_point(|block| block.last_key.as_str() < key);
+        let block = self.block(i)?;
+        Ok(block
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .ok()
+            .map(|pos| block[pos].1.clone()))
+    }
+
+    /// Entries starting from `start` in ascending order
+    pub(super) fn iter<'a>(&'a self, start: Bound<&str>) -> impl Iterator<Item = Result<Entry>> + 'a {
+        let next_block = match start {
+            Bound::Unbounded => 0,
+            Bound::Included(k) | Bound::Excluded(k) => {
+                self.index.blocks.partition_point(|block| block.last_key.as_str() < k)
+            }
+        };
+        let start = match start {
+            Bound::Included(k) => Bound::Included(k.to_owned()),
+            Bound::Excluded(k) => Bound::Excluded(k.to_owned()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        TableIter {
+            table: self,
+            next_block,
+            entries: Vec::new().into_iter(),
+        }
+        .skip_while(move |entry| match (entry, &start) {
+            (Ok((key, _)), Bound::Included(k)) => key < k,
+            (Ok((key, _)), Bound::Excluded(k)) => key <= k,
+            _ => false,
+        })
+    }
+
+    fn block(&self, i: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index.blocks[i];
+        let block = &self.map[handle.offset as usize..(handle.offset + handle.len) as usize];
+        Ok(rmp_serde::decode::from_slice(block)?)
+    }
+}
+
+/// Iterator reading the blocks of a table one by one
+struct TableIter<'a> {
+    table: &'a Table,
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+
+            if self.next_block >= self.table.index.blocks.len() {
+                return None;
+            }
+
+            match self.table.block(self.next_block) {
+                Ok(block) => {
+                    self.entries = block.into_iter();
+                    self.next_block += 1;
+                }
+                Err(err) => {
+                    self.next_block = self.table.index.blocks.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> crate::KvsError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg).into()
+}
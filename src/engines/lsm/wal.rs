@@ -0,0 +1,76 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{prelude::*, BufReader, BufWriter, SeekFrom};
+use std::path::Path;
+use crate::Result;
+use super::Entry;
+
+// structure the record
+// |size_of_payload(4 bytes)|payload(unsized)|
+// payload is a msgpack encoded entry
+const HEADER_SIZE: u64 = 4;
+
+/// Wal logs the changes held by the memtable
+/// so they survive a restart before they're flushed to a table.
+pub(super) struct Wal {
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    /// Open the log returning the entries it holds in order of writing.
+    ///
+    /// A record torn by a crash ends the log, it's cut off
+    /// so new records follow the last complete one.
+    pub(super) fn open(path: &Path) -> Result<(Self, Vec<Entry>)> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut entries = Vec::new();
+        let mut valid = 0;
+        let mut reader = BufReader::new(&file);
+        while let Ok(entry) = read(&mut reader) {
+            valid += HEADER_SIZE + entry.1;
+            entries.push(entry.0);
+        }
+
+        file.set_len(valid)?;
+        let mut writer = BufWriter::new(file);
+        writer.seek(SeekFrom::Start(valid))?;
+
+        Ok((Wal { writer }, entries))
+    }
+
+    pub(super) fn append(&mut self, key: &str, val: Option<&str>) -> Result<()> {
+        let payload = rmp_serde::encode::to_vec(&(key, val))?;
+        self.writer.write_u32::<BigEndian>(payload.len() as u32)?;
+        self.writer.write_all(&payload)?;
+        // the change is answered as written once it's on disk
+        self.sync()
+    }
+
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Drop every record once the memtable is flushed
+    pub(super) fn reset(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(0)?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+/// Read a record returning its entry and payload size
+fn read<R: Read>(reader: &mut R) -> Result<(Entry, u64)> {
+    let size = reader.read_u32::<BigEndian>()?;
+    let mut payload = vec![0; size as usize];
+    reader.read_exact(&mut payload)?;
+    Ok((rmp_serde::decode::from_slice(&payload)?, u64::from(size)))
+}
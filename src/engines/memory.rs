@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::{KvsError, Result};
+use super::kvs;
+use super::{create_checkpoint_dir, KvsEngine, Pairs};
+
+/// MemoryStore keeps every pair in an ordered map.
+///
+/// It may be snapshotted to a directory in the `KvStore` log format,
+/// the directory can then be opened by a `KvStore` as well.
+#[derive(Default)]
+pub struct MemoryStore {
+    map: BTreeMap<String, String>,
+    snapshots: Option<Snapshots>,
+}
+
+struct Snapshots {
+    path: PathBuf,
+    interval: Duration,
+    last: Instant,
+    dirty: bool,
+}
+
+impl MemoryStore {
+    /// Create an empty store which lives only in memory
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// Create a store warm started from the snapshot in `folder`.
+    ///
+    /// A new snapshot is taken on a write once `interval` passed
+    /// since the previous one, on `sync` and when the store is dropped.
+    /// An idle store is snapshotted by calling `sync` every `interval`.
+    pub fn with_snapshots(folder: impl Into<PathBuf>, interval: Duration) -> Result<Self> {
+        let path = folder.into();
+        let map = if path.exists() {
+            kvs::read_snapshot(&path)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(MemoryStore {
+            map,
+            snapshots: Some(Snapshots {
+                path,
+                interval,
+                last: Instant::now(),
+                dirty: false,
+            }),
+        })
+    }
+
+    /// Scan returns the pairs whose keys fall into `range` in ascending order of keys
+    pub fn scan<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<Vec<(String, String)>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self
+            .map
+            .range::<str, _>(range)
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect())
+    }
+
+    /// Snapshot writes every pair to the snapshot directory,
+    /// it does nothing for a store which lives only in memory.
+    pub fn snapshot(&mut self) -> Result<()> {
+        if let Some(snapshots) = &mut self.snapshots {
+            kvs::write_snapshot(&snapshots.path, &self.map)?;
+            snapshots.last = Instant::now();
+            snapshots.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    fn changed(&mut self) -> Result<()> {
+        let due = match &mut self.snapshots {
+            Some(snapshots) => {
+                snapshots.dirty = true;
+                snapshots.last.elapsed() >= snapshots.interval
+            }
+            None => false,
+        };
+
+        if due {
+            self.snapshot()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl KvsEngine for MemoryStore {
+    /// Get method tries to find value with `key`
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    /// Set put new value in storage by key
+    /// it rewrite value if that alredy exists
+    fn set(&mut self, key: String, val: String) -> Result<()> {
+        self.map.insert(key, val);
+        self.changed()
+    }
+
+    /// Delete key value pair from storage
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.map.remove(&key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+
+        self.changed()
+    }
+
+    fn pairs(&mut self) -> Result<Pairs<'_>> {
+        Ok(Box::new(self.map.iter().map(|(key, val)| Ok((key.clone(), val.clone())))))
+    }
+
+    /// The checkpoint is a snapshot in the `KvStore` log format
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        kvs::write_snapshot(dest, &self.map)
+    }
+
+    fn page(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(self
+            .map
+            .range::<str, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect())
+    }
+
+    /// A snapshot is taken when a write came after the previous one
+    fn sync(&mut self) -> Result<()> {
+        match self.snapshots.as_ref().is_some_and(|snapshots| snapshots.dirty) {
+            true => self.snapshot(),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Drop for MemoryStore {
+    fn drop(&mut self) {
+        if self.snapshots.as_ref().is_some_and(|snapshots| snapshots.dirty) {
+            if let Err(err) = self.snapshot() {
+                log::error!("cannot snapshot the memory store: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::{KvsError, Result};
+use super::EngineKind;
+
+pub(super) static METADATA_FILE: &str = "METADATA";
+// the plain engine name written by older servers
+static LEGACY_MARKER: &str = "engine";
+// whoever opens a data directory holds the lock of this file
+pub(super) static LOCK_FILE: &str = "LOCK";
+
+// how long the lock is waited for, a server which was just killed may not have released it yet
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+const LOCK_POLL: Duration = Duration::from_millis(10);
+
+/// Version of the data directory layout this build reads and writes.
+///
+/// 0 is a directory marked by the plain engine name, 1 one with metadata,
+/// since 2 generation files have a header and their sealed records are bound
+/// to their place. Engines upgrade the files of older versions on open.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Metadata describes which engine a data directory belongs to
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub engine: EngineKind,
+    pub format_version: u32,
+    /// seconds since the Unix epoch
+    pub created: u64,
+}
+
+impl Metadata {
+    /// Metadata of a directory created now for `engine`
+    pub fn new(engine: EngineKind) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        Metadata {
+            engine,
+            format_version: FORMAT_VERSION,
+            created,
+        }
+    }
+
+    /// Read the metadata of `path`, `None` for a directory no engine owns yet.
+    ///
+    /// A marker left by an older server is read as metadata of format version 0.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path.join(METADATA_FILE)) {
+            Ok(content) => return Ok(Some(serde_json::from_slice(&content)?)),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        match std::fs::read_to_string(path.join(LEGACY_MARKER)) {
+            Ok(name) => {
+                let engine = name.trim().parse().map_err(KvsError::Incompatible)?;
+                let created = std::fs::metadata(path.join(LEGACY_MARKER))?
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                Ok(Some(Metadata {
+                    engine,
+                    format_version: 0,
+                    created,
+                }))
+            }
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the metadata to `path`, replacing a legacy marker
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.join(format!("{}.tmp", METADATA_FILE));
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path.join(METADATA_FILE))?;
+
+        match std::fs::remove_file(path.join(LEGACY_MARKER)) {
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    /// Check `path` may be opened by `engine` without writing anything.
+    ///
+    /// The returned metadata is the one to `save` once the engine opened the
+    /// directory, a directory of an older format version is upgraded by then.
+    pub fn check(path: &Path, engine: EngineKind) -> Result<Self> {
+        let meta = match Metadata::load(path)? {
+            Some(meta) => meta,
+            None => return Ok(Metadata::new(engine)),
+        };
+
+        if meta.engine != engine {
+            return Err(KvsError::Incompatible(format!(
+                "the directory belongs to the {} engine, not {}",
+                meta.engine, engine
+            )));
+        }
+        if meta.format_version > FORMAT_VERSION {
+            return Err(KvsError::Incompatible(format!(
+                "format version {} is newer than the supported {}",
+                meta.format_version, FORMAT_VERSION
+            )));
+        }
+
+        Ok(Metadata {
+            format_version: FORMAT_VERSION,
+            ..meta
+        })
+    }
+
+    /// Check `path` may be opened by `engine` and pin it to `engine`,
+    /// it's called once the engine opened the directory
+    pub fn pin(path: &Path, engine: EngineKind) -> Result<Self> {
+        let meta = Metadata::check(path, engine)?;
+        if Metadata::load(path)?.as_ref() != Some(&meta) {
+            meta.save(path)?;
+        }
+
+        Ok(meta)
+    }
+}
+
+/// DirLock keeps a data directory to one process at a time,
+/// the directory is released once it's dropped
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Lock the directory `path`, failing when another process holds it
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(DirLock { file }),
+                Err(_) if Instant::now() < deadline => std::thread::sleep(LOCK_POLL),
+                Err(_) => return Err(KvsError::Locked(path.display().to_string())),
+            }
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.unlock() {
+            log::error!("cannot unlock the data directory: {}", err);
+        }
+    }
+}
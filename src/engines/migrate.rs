@@ -0,0 +1,145 @@
+use std::path::Path;
+use crate::{KvsError, Result};
+use super::meta::{LOCK_FILE, METADATA_FILE};
+use super::{DirLock, EngineKind, EngineOptions, KvsEngine, Metadata, Registry};
+
+/// MigrationReport tells how many pairs were moved
+/// along with their checksum
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub pairs: u64,
+    /// sum of hashes of every pair, it doesn't depend on their order
+    pub checksum: u64,
+}
+
+impl MigrationReport {
+    fn add(&mut self, key: &str, val: &str) {
+        self.pairs += 1;
+        self.checksum = self.checksum.wrapping_add(pair_hash(key, val));
+    }
+}
+
+/// Stream every live pair of `from` into `to`, which is expected to be empty.
+///
+/// Once all pairs are written `to` is read back,
+/// its count and checksum must match the ones of `from`.
+pub fn migrate(from: &mut dyn KvsEngine, to: &mut dyn KvsEngine) -> Result<MigrationReport> {
+    let mut written = MigrationReport::default();
+    for pair in from.pairs()? {
+        let (key, val) = pair?;
+        written.add(&key, &val);
+        to.set(key, val)?;
+    }
+
+    verify(to, written)?;
+    Ok(written)
+}
+
+/// Read every pair of `store` back, its count and checksum must be the ones of `written`
+fn verify(store: &mut dyn KvsEngine, written: MigrationReport) -> Result<()> {
+    let mut read = MigrationReport::default();
+    for pair in store.pairs()? {
+        let (key, val) = pair?;
+        read.add(&key, &val);
+    }
+
+    if read != written {
+        return Err(KvsError::Verification(format!(
+            "wrote {} pairs with checksum {:016x} but read back {} with checksum {:016x}",
+            written.pairs, written.checksum, read.pairs, read.checksum
+        )));
+    }
+
+    Ok(())
+}
+
+/// Migrate the store in `src` served by `from` to a new store in `dst` served by `to`.
+///
+/// Both directories are locked meanwhile. The source is opened from a copy
+/// so it's left as it was, whatever the engine writes when it opens a directory.
+/// `dst` gets its metadata only once it's opened again and verified. A failed
+/// migration leaves `dst` without metadata, which a server would take for a
+/// new store, so it's to be emptied before the migration is retried.
+pub fn migrate_dir(
+    from: EngineKind,
+    src: &Path,
+    to: EngineKind,
+    dst: &Path,
+    options: &EngineOptions,
+) -> Result<MigrationReport> {
+    std::fs::create_dir_all(dst)?;
+    let _locks = (DirLock::acquire(src)?, DirLock::acquire(dst)?);
+    if let Some(meta) = Metadata::load(src)? {
+        if meta.engine != from {
+            return Err(KvsError::Incompatible(format!(
+                "{} belongs to the {} engine, not {}",
+                src.display(),
+                meta.engine,
+                from
+            )));
+        }
+    }
+    if Metadata::load(dst)?.is_some() {
+        return Err(KvsError::Incompatible(format!("{} holds a store already", dst.display())));
+    }
+
+    let copy = match dst.file_name() {
+        Some(name) => dst.with_file_name(format!("{}.source", name.to_string_lossy())),
+        None => return Err(KvsError::Incompatible(format!("{} has no name", dst.display()))),
+    };
+    if copy.exists() {
+        std::fs::remove_dir_all(&copy)?;
+    }
+    let migrated = copy_dir(src, &copy).and_then(|()| {
+        let registry = Registry::new();
+        let report = {
+            let mut source = registry.open(from.name(), &copy, options)?;
+            let mut destination = registry.open(to.name(), dst, options)?;
+            let report = migrate(source.as_mut(), destination.as_mut())?;
+            destination.sync()?;
+            report
+        };
+
+        let mut destination = registry.open(to.name(), dst, options)?;
+        verify(destination.as_mut(), report)?;
+        Ok(report)
+    });
+    if copy.exists() {
+        std::fs::remove_dir_all(&copy)?;
+    }
+
+    let report = migrated?;
+    Metadata::new(to).save(dst)?;
+    Ok(report)
+}
+
+/// Copy the files of the directory `src` to the new directory `dst`,
+/// leaving out the ones which tell who owns `src`
+pub fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::create_dir(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == LOCK_FILE || name == METADATA_FILE {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dst.join(&name))?;
+        } else {
+            std::fs::copy(entry.path(), dst.join(&name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// FNV-1a of the key and the value split by a zero byte
+fn pair_hash(key: &str, val: &str) -> u64 {
+    key.bytes()
+        .chain(std::iter::once(0))
+        .chain(val.bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
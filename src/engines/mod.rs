@@ -1,13 +1,155 @@
-use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BinaryHeap;
+use std::path::Path;
+use crate::{KvsError, Result};
+
+/// Stream of live pairs of an engine
+pub type Pairs<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// Stream of changes of an engine in the order they were made
+pub type Changes<'a> = Box<dyn Iterator<Item = Result<Change>> + 'a>;
+
+/// Change is a write made to an engine
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// sequence number, it grows with every change
+    pub seq: u64,
+    pub key: String,
+    /// `None` for a removed key
+    pub value: Option<String>,
+}
 
 pub trait KvsEngine {
     fn set(&mut self, key: String, value: String) -> Result<()>;
     fn get(&mut self, key: String) -> Result<Option<String>>;
     fn remove(&mut self, key: String) -> Result<()>;
+    /// Every live pair, in no particular order
+    fn pairs(&mut self) -> Result<Pairs<'_>>;
+    /// Write a consistent copy of the store to `dest`,
+    /// which must be missing or empty
+    fn checkpoint(&mut self, dest: &Path) -> Result<()>;
+
+    /// Up to `limit` pairs whose keys follow `after`, in ascending order of keys.
+    ///
+    /// Engines which don't keep keys in order look through every pair,
+    /// holding only the `limit` smallest keys seen so far.
+    fn page(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut page = BinaryHeap::with_capacity(limit + 1);
+        for pair in self.pairs()? {
+            let pair = pair?;
+            if after.is_some_and(|after| pair.0.as_str() <= after) {
+                continue;
+            }
+
+            page.push(pair);
+            if page.len() > limit {
+                page.pop();
+            }
+        }
+
+        Ok(page.into_sorted_vec())
+    }
+
+    /// Changes made after the one numbered `since`, 0 reads the feed from its start.
+    /// It fails with `ResyncRequired` once some of them were compacted away.
+    fn changes(&mut self, _since: u64) -> Result<Changes<'_>> {
+        Err(KvsError::Unsupported("the engine keeps no change feed".to_owned()))
+    }
+
+    /// Sequence number of the latest change
+    fn seq(&mut self) -> Result<u64> {
+        Err(KvsError::Unsupported("the engine keeps no change feed".to_owned()))
+    }
+
+    /// The last version handed out to a write, it isn't reset when the store is opened again
+    fn version(&mut self) -> Result<u64> {
+        Err(KvsError::Unsupported("the engine keeps no versions".to_owned()))
+    }
+
+    /// Write every change made so far through to disk
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn pairs(&mut self) -> Result<Pairs<'_>> {
+        (**self).pairs()
+    }
+
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        (**self).checkpoint(dest)
+    }
+
+    fn page(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        (**self).page(after, limit)
+    }
+
+    fn changes(&mut self, since: u64) -> Result<Changes<'_>> {
+        (**self).changes(since)
+    }
+
+    fn seq(&mut self) -> Result<u64> {
+        (**self).seq()
+    }
+
+    fn version(&mut self) -> Result<u64> {
+        (**self).version()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+}
+
+/// Create the directory of a checkpoint, refusing to mix it with other files
+fn create_checkpoint_dir(dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    if std::fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::Incompatible(format!("{} is not empty", dest.display())));
+    }
+
+    Ok(())
+}
+
+/// Hard link an immutable file into a checkpoint,
+/// it's copied when the checkpoint is on another file system
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if std::fs::hard_link(src, dst).is_err() {
+        std::fs::copy(src, dst)?;
+    }
+
+    Ok(())
 }
 
 mod kvs;
+mod lsm;
+mod memory;
+mod meta;
+mod migrate;
+mod registry;
 mod sled;
 
-pub use kvs::KvStore;
+pub use kvs::{
+    CompactionStep, Compression, Corruption, EncryptionKey, GenerationInfo, IndexMode, KvStore,
+    KvStoreOptions, KvStoreStats, LogEntry, LogFile, LogRecord, RepairReport, VerifyReport,
+    Versioned,
+};
+pub use lsm::{LsmOptions, LsmStore};
+pub use memory::MemoryStore;
+pub use meta::{DirLock, Metadata, FORMAT_VERSION};
+pub use migrate::{copy_dir, migrate, migrate_dir, MigrationReport};
+pub use registry::{EngineConstructor, EngineKind, EngineOptions, Registry};
 pub use self::sled::SledStorage;
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::{KvsError, Result};
+use super::{
+    DirLock, KvStore, KvStoreOptions, KvsEngine, LsmOptions, LsmStore, MemoryStore, Metadata,
+    SledStorage,
+};
+
+/// Kinds of engines shipped with the library
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    Kvs,
+    Sled,
+    Lsm,
+    Memory,
+}
+
+impl EngineKind {
+    /// Name the engine is registered and selected by
+    pub fn name(self) -> &'static str {
+        match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Sled => "sled",
+            EngineKind::Lsm => "lsm",
+            EngineKind::Memory => "memory",
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            "lsm" => Ok(EngineKind::Lsm),
+            "memory" => Ok(EngineKind::Memory),
+            _ => Err(format!("unknown engine {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Options engines are opened with, each engine takes its own part
+#[derive(Clone, Debug, Default)]
+pub struct EngineOptions {
+    pub kvs: KvStoreOptions,
+    pub lsm: LsmOptions,
+    /// interval of snapshots of the memory engine,
+    /// it keeps nothing on disk without it
+    pub snapshot_interval: Option<Duration>,
+}
+
+/// Constructor opens an engine over a data directory
+pub type EngineConstructor = fn(&Path, &EngineOptions) -> Result<Box<dyn KvsEngine>>;
+
+/// Registry maps engine names to their constructors
+pub struct Registry {
+    engines: BTreeMap<String, EngineConstructor>,
+}
+
+impl Registry {
+    /// Registry of every engine shipped with the library
+    pub fn new() -> Self {
+        let mut registry = Registry::empty();
+        registry.register(EngineKind::Kvs.name(), open_kvs);
+        registry.register(EngineKind::Sled.name(), open_sled);
+        registry.register(EngineKind::Lsm.name(), open_lsm);
+        registry.register(EngineKind::Memory.name(), open_memory);
+        registry
+    }
+
+    /// Registry without any engine
+    pub fn empty() -> Self {
+        Registry {
+            engines: BTreeMap::new(),
+        }
+    }
+
+    /// Register `constructor` under `name`, replacing an engine registered before
+    pub fn register(&mut self, name: impl Into<String>, constructor: EngineConstructor) {
+        self.engines.insert(name.into(), constructor);
+    }
+
+    /// Open the engine registered under `name` over `path`
+    pub fn open(&self, name: &str, path: &Path, options: &EngineOptions) -> Result<Box<dyn KvsEngine>> {
+        match self.engines.get(name) {
+            Some(constructor) => constructor(path, options),
+            None => Err(KvsError::UnknownEngine(name.to_owned())),
+        }
+    }
+
+    /// Open the data directory `path` with `engine` holding its lock.
+    ///
+    /// The directory is checked against its metadata first, the metadata is
+    /// written once the engine opened it. The directory stays locked until
+    /// the returned lock is dropped, which has to outlive the engine.
+    pub fn open_dir(
+        &self,
+        engine: EngineKind,
+        path: &Path,
+        options: &EngineOptions,
+    ) -> Result<(Box<dyn KvsEngine>, Metadata, DirLock)> {
+        let lock = DirLock::acquire(path)?;
+        let meta = Metadata::check(path, engine)?;
+        let store = self.open(engine.name(), path, options)?;
+        meta.save(path)?;
+
+        Ok((store, meta, lock))
+    }
+
+    /// Names of the registered engines in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.engines.keys().map(String::as_str)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+fn open_kvs(path: &Path, options: &EngineOptions) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(KvStore::open_with_options(path, options.kvs.clone())?))
+}
+
+fn open_sled(path: &Path, _: &EngineOptions) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(SledStorage::open(path)?))
+}
+
+fn open_lsm(path: &Path, options: &EngineOptions) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(LsmStore::open_with_options(path, options.lsm.clone())?))
+}
+
+fn open_memory(path: &Path, options: &EngineOptions) -> Result<Box<dyn KvsEngine>> {
+    match options.snapshot_interval {
+        Some(interval) => Ok(Box::new(MemoryStore::with_snapshots(path, interval)?)),
+        None => Ok(Box::new(MemoryStore::new())),
+    }
+}
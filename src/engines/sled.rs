@@ -1,6 +1,7 @@
 use sled::{Db, Tree};
 use crate::{KvsError, Result};
-use super::KvsEngine;
+use super::{create_checkpoint_dir, KvsEngine, Pairs};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
 
 pub struct SledStorage(Db);
@@ -27,7 +28,7 @@ impl KvsEngine for SledStorage {
     /// Set put new value in storage by key
     /// it rewrite value if that alredy exists
     fn set(&mut self, key: String, val: String) -> Result<()> {
-        self.0.set(key, val.into_bytes())?;
+        self.0.insert(key, val.into_bytes())?;
         self.0.flush()?;
         Ok(())
     }
@@ -40,4 +41,40 @@ impl KvsEngine for SledStorage {
         self.0.flush()?;
         Ok(())
     }
+
+    fn sync(&mut self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn pairs(&mut self) -> Result<Pairs<'_>> {
+        Ok(Box::new(self.0.iter().map(|item| {
+            let (key, val) = item?;
+            Ok((String::from_utf8(key.to_vec())?, String::from_utf8(val.to_vec())?))
+        })))
+    }
+
+    /// Every pair is exported to a new database in `dest`
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        let db = Db::open(dest)?;
+        for item in self.0.iter() {
+            let (key, val) = item?;
+            db.insert(key, val)?;
+        }
+        db.flush()?;
+        Ok(())
+    }
+
+    fn page(&mut self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = after.map_or(Bound::Unbounded, |after| Bound::Excluded(after.as_bytes()));
+        self.0
+            .range::<&[u8], _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|item| {
+                let (key, val) = item?;
+                Ok((String::from_utf8(key.to_vec())?, String::from_utf8(val.to_vec())?))
+            })
+            .collect()
+    }
 }
\ No newline at end of file
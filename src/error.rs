@@ -1,3 +1,7 @@
+// the Fail derive implements its traits inside a constant
+#![allow(non_local_definitions)]
+
+use crate::protocol::ErrorKind;
 use failure::Fail;
 use std::io;
 
@@ -11,8 +15,49 @@ pub enum KvsError {
     SerdeEncode(#[cause] rmp_serde::encode::Error),
     #[fail(display = "{}", _0)]
     Sled(#[cause] sled::Error),
+    #[fail(display = "{}", _0)]
+    Compression(#[cause] snap::Error),
+    #[fail(display = "Cannot seal or open a record with the encryption key")]
+    Crypto,
+    #[fail(display = "Encryption key {:016x} is unknown", _0)]
+    UnknownKey(u64),
+    #[fail(display = "Invalid encryption key: {}", _0)]
+    InvalidKey(String),
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] std::string::FromUtf8Error),
+    #[fail(display = "{}", _0)]
+    Json(#[cause] serde_json::Error),
+    #[fail(display = "{}", _0)]
+    Csv(#[cause] csv::Error),
+    #[fail(display = "Malformed record: {}", _0)]
+    Malformed(String),
+    #[fail(display = "Unknown engine {}", _0)]
+    UnknownEngine(String),
+    #[fail(display = "Incompatible data directory: {}", _0)]
+    Incompatible(String),
+    #[fail(display = "Cannot lock {}: another process holds it", _0)]
+    Locked(String),
+    #[fail(display = "Unsupported: {}", _0)]
+    Unsupported(String),
+    #[fail(display = "Verification failed: {}", _0)]
+    Verification(String),
+    #[fail(display = "Not the leader: {}", _0)]
+    NotLeader(String),
+    #[fail(display = "The server is shutting down")]
+    ShuttingDown,
+    #[fail(display = "The server holds {} waiting clients already", _0)]
+    Busy(usize),
+    #[fail(display = "Invalid configuration: {}", _0)]
+    Config(String),
+    #[fail(display = "Version {} is newer than the latest one, {}", _0, _1)]
+    UnknownVersion(u64, u64),
+    #[fail(display = "Changes after {} were compacted away, read the feed from 0 again", _0)]
+    ResyncRequired(u64),
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// a server failed a request, the message is its own
+    #[fail(display = "{}", _1)]
+    Remote(ErrorKind, String),
     #[fail(display = "Key not found")]
     KeyNotFound, 
     #[fail(display = "Cannot find a command we involved in")]
@@ -43,6 +88,24 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<snap::Error> for KvsError {
+    fn from(err: snap::Error) -> KvsError {
+        KvsError::Compression(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::Json(err)
+    }
+}
+
+impl From<csv::Error> for KvsError {
+    fn from(err: csv::Error) -> KvsError {
+        KvsError::Csv(err)
+    }
+}
+
 impl From<std::string::FromUtf8Error> for KvsError {
     fn from(err: std::string::FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
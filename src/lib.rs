@@ -1,12 +1,46 @@
+mod client;
+mod config;
 mod engines;
 mod error;
 mod protocol;
+mod raft;
+mod replication;
+mod sharding;
+mod transfer;
 
-pub use engines::{KvStore, KvsEngine, SledStorage};
+pub use client::{admin, reply, KvsClient, RemotePairs};
+pub use config::{
+    DurabilityConfig, KvsConfig, LimitsConfig, LoggingConfig, LsmConfig, MemoryConfig,
+    ServerConfig,
+};
+pub use engines::{
+    copy_dir, migrate, migrate_dir, Change, Changes, Compression, Corruption, DirLock, EncryptionKey,
+    EngineConstructor, EngineKind, EngineOptions, GenerationInfo, IndexMode, KvStore,
+    KvStoreOptions, KvStoreStats, KvsEngine, LogEntry, LogFile, LogRecord, LsmOptions, LsmStore,
+    MemoryStore, Metadata, MigrationReport, Pairs, Registry, RepairReport, SledStorage,
+    VerifyReport, Versioned, FORMAT_VERSION,
+};
+#[doc(hidden)]
+pub use engines::CompactionStep;
 pub use error::{KvsError, Result};
 pub use protocol::{
+    AdminCommand,
+    ClusterStatus,
+    ErrorKind,
+    ErrorReply,
+    FollowerStatus,
+    KeyVersion,
+    KeyWatch,
     Package,
+    ReplicationStatus,
+    Subscription,
     deconstruct_package, 
+    read_package,
     construct_package,
+    error_package,
     ok_package,
 };
+pub use raft::{Committed, Message, Node, Proposal, Role, SnapshotMeta, Transport};
+pub use replication::{follow, Replica, Replicated};
+pub use sharding::{Ring, ShardedClient};
+pub use transfer::{export, import, Format};
@@ -1,5 +1,6 @@
-use std::io::Result;
-use std::net::TcpStream;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use crate::KvsError;
 
 pub enum Package<'a> {
     OK(&'a [u8]),
@@ -7,20 +8,244 @@ pub enum Package<'a> {
     Get(&'a [u8]),
     Set(&'a [u8], &'a [u8]),
     Remove(&'a [u8]),
+    Admin(&'a [u8]),
+    Subscribe(&'a [u8]),
+    Watch(&'a [u8]),
+    Raft(&'a [u8]),
+}
+
+/// AdminCommand is the body of an admin package
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// write a checkpoint of the store to a directory on the server
+    Backup { dest: String },
+    /// reply with up to `limit` pairs whose keys follow `after` in ascending order,
+    /// the body of the reply is a msgpack encoded list of pairs
+    Scan { after: Option<String>, limit: u32 },
+    /// reply with the msgpack encoded sequence number of the latest change
+    Seq,
+    /// a follower tells which change of the leader it applied last and how
+    /// far behind it is, the reply is a msgpack encoded pair of whether
+    /// it's caught up and the sequence number of the latest change
+    Report { follower: String, applied: u64, lag_ms: u64 },
+    /// reply with the msgpack encoded `ReplicationStatus` of the server
+    Status,
+    /// reply with the msgpack encoded `ClusterStatus` of a server in cluster mode
+    Cluster,
+    /// add the server at `node` to the cluster, the reply comes once it's committed
+    AddMember { node: String },
+    /// remove the server at `node` from the cluster, the reply comes once it's committed
+    RemoveMember { node: String },
+    /// stop the server the way a SIGTERM does, the reply comes before it stops
+    Shutdown,
+}
+
+/// ReplicationStatus tells how far behind the leader followers are
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    /// address of the leader, `None` for a server which isn't a follower
+    pub leader: Option<String>,
+    /// sequence number of the latest change of the leader a follower applied,
+    /// the one of the latest change of a leader
+    pub applied: u64,
+    /// milliseconds since a follower was last caught up with the leader
+    pub lag_ms: u64,
+    /// followers which reported to a leader
+    pub followers: Vec<FollowerStatus>,
+}
+
+/// State of a follower as it last reported to its leader
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FollowerStatus {
+    pub addr: String,
+    pub applied: u64,
+    pub lag_ms: u64,
+    /// milliseconds since the report
+    pub reported_ms: u64,
+}
+
+/// ClusterStatus is the state of a member of a cluster as it sees it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    pub id: String,
+    /// one of follower, candidate and leader
+    pub role: String,
+    pub term: u64,
+    pub leader: Option<String>,
+    /// index of the latest committed entry of the log
+    pub commit: u64,
+    /// index of the latest entry applied to the store
+    pub applied: u64,
+    pub members: Vec<String>,
+}
+
+impl AdminCommand {
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::encode::to_vec(self)?)
+    }
+
+    pub fn decode(body: &[u8]) -> crate::Result<Self> {
+        Ok(rmp_serde::decode::from_slice(body)?)
+    }
+}
+
+/// Subscription is the body of a subscribe package.
+///
+/// The server acknowledges it with an OK package and then sends
+/// an OK package per change, its body is a msgpack encoded `Change`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    /// only changes of keys starting with it are sent
+    pub prefix: String,
+    /// changes made after this sequence number are sent,
+    /// `None` sends the changes made from now on
+    pub since: Option<u64>,
+}
+
+impl Subscription {
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::encode::to_vec(self)?)
+    }
+
+    pub fn decode(body: &[u8]) -> crate::Result<Self> {
+        Ok(rmp_serde::decode::from_slice(body)?)
+    }
+}
+
+/// KeyWatch is the body of a watch package.
+///
+/// The server replies with an OK package whose body is a msgpack encoded
+/// `KeyVersion` as soon as the version of `key` is newer than `since`,
+/// or once `timeout_ms` passed without a change.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyWatch {
+    pub key: String,
+    pub since: u64,
+    pub timeout_ms: u64,
+}
+
+impl KeyWatch {
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::encode::to_vec(self)?)
+    }
+
+    pub fn decode(body: &[u8]) -> crate::Result<Self> {
+        Ok(rmp_serde::decode::from_slice(body)?)
+    }
+}
+
+/// KeyVersion is the reply to a key watch
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyVersion {
+    pub version: u64,
+    /// the value of the key, `None` once removed
+    pub value: Option<String>,
+    /// false when the watch timed out before the key changed
+    pub changed: bool,
+}
+
+/// Kind of the failure an error package reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    KeyNotFound,
+    /// the server takes no writes or reads of its own, its leader does
+    NotLeader,
+    Busy,
+    ShuttingDown,
+    UnknownVersion,
+    /// the changes asked for were compacted away
+    ResyncRequired,
+    Unsupported,
+    /// the request couldn't be read
+    Protocol,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn of(err: &KvsError) -> Self {
+        match err {
+            KvsError::KeyNotFound => ErrorKind::KeyNotFound,
+            KvsError::NotLeader(_) => ErrorKind::NotLeader,
+            KvsError::Busy(_) => ErrorKind::Busy,
+            KvsError::ShuttingDown => ErrorKind::ShuttingDown,
+            KvsError::UnknownVersion(..) => ErrorKind::UnknownVersion,
+            KvsError::ResyncRequired(_) => ErrorKind::ResyncRequired,
+            KvsError::Unsupported(_) => ErrorKind::Unsupported,
+            KvsError::Protocol(_) => ErrorKind::Protocol,
+            KvsError::Remote(kind, _) => *kind,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// ErrorReply is the body of an error package
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ErrorReply {
+    pub fn new(err: &KvsError) -> Self {
+        ErrorReply {
+            kind: ErrorKind::of(err),
+            message: err.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        Ok(rmp_serde::encode::to_vec(self)?)
+    }
+
+    pub fn decode(body: &[u8]) -> crate::Result<Self> {
+        Ok(rmp_serde::decode::from_slice(body)?)
+    }
+
+    /// The error the server failed with, as far as the client may tell
+    pub fn into_error(self) -> KvsError {
+        match self.kind {
+            ErrorKind::KeyNotFound => KvsError::KeyNotFound,
+            ErrorKind::ShuttingDown => KvsError::ShuttingDown,
+            kind => KvsError::Remote(kind, self.message),
+        }
+    }
+}
+
+/// Error package telling about `err`
+pub fn error_package(err: &KvsError) -> Vec<u8> {
+    // a reply of a kind and a string always encodes
+    let body = ErrorReply::new(err).encode().expect("GG: cannot encode an error reply");
+    construct_package(Package::Error(&body))
 }
 
 pub fn ok_package<'a>() -> Package<'a> {
-    return Package::OK(&[])
+    Package::OK(&[])
 }
 
 impl<'a> std::fmt::Display for Package<'a>{
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Package::OK(body) => writeln!(f, "package<OK> {}", std::str::from_utf8(body).unwrap()),
-            Package::Error(msg) => writeln!(f, "package<error> {}", std::str::from_utf8(msg).unwrap()),
-            Package::Remove(key) => writeln!(f, "package<remove> {}", std::str::from_utf8(key).unwrap()),
-            Package::Get(key) => writeln!(f, "package<get> {}", std::str::from_utf8(key).unwrap()),
-            Package::Set(key, val) => writeln!(f, "package<set> {} {}", std::str::from_utf8(key).unwrap(), std::str::from_utf8(val).unwrap()),
+            Package::OK(body) => writeln!(f, "package<OK> {}", String::from_utf8_lossy(body)),
+            Package::Error(body) => match ErrorReply::decode(body) {
+                Ok(reply) => writeln!(f, "package<error> {:?}: {}", reply.kind, reply.message),
+                Err(_) => writeln!(f, "package<error> malformed"),
+            },
+            Package::Remove(key) => writeln!(f, "package<remove> {}", String::from_utf8_lossy(key)),
+            Package::Get(key) => writeln!(f, "package<get> {}", String::from_utf8_lossy(key)),
+            Package::Set(key, val) => writeln!(f, "package<set> {} {}", String::from_utf8_lossy(key), String::from_utf8_lossy(val)),
+            Package::Admin(body) => match AdminCommand::decode(body) {
+                Ok(command) => writeln!(f, "package<admin> {:?}", command),
+                Err(_) => writeln!(f, "package<admin> malformed"),
+            },
+            Package::Subscribe(body) => match Subscription::decode(body) {
+                Ok(subscription) => writeln!(f, "package<subscribe> {:?}", subscription),
+                Err(_) => writeln!(f, "package<subscribe> malformed"),
+            },
+            Package::Watch(body) => match KeyWatch::decode(body) {
+                Ok(watch) => writeln!(f, "package<watch> {:?}", watch),
+                Err(_) => writeln!(f, "package<watch> malformed"),
+            },
+            Package::Raft(body) => writeln!(f, "package<raft> {} bytes", body.len()),
         }
     }
 }
@@ -31,58 +256,127 @@ enum PackageType {
     Get,
     Set,
     Remove,
+    Admin,
+    Subscribe,
+    Watch,
+    Raft,
 }
 
-impl Into<PackageType> for u8 {
-    fn into(self) -> PackageType {
-        match self {
-            0 => PackageType::OK,
-            1 => PackageType::Error,
-            2 => PackageType::Get,
-            3 => PackageType::Set,
-            4 => PackageType::Remove,
-            _ => unimplemented!(),
+impl TryFrom<u8> for PackageType {
+    type Error = KvsError;
+
+    fn try_from(b: u8) -> crate::Result<PackageType> {
+        match b {
+            0 => Ok(PackageType::OK),
+            1 => Ok(PackageType::Error),
+            2 => Ok(PackageType::Get),
+            3 => Ok(PackageType::Set),
+            4 => Ok(PackageType::Remove),
+            5 => Ok(PackageType::Admin),
+            6 => Ok(PackageType::Subscribe),
+            7 => Ok(PackageType::Watch),
+            8 => Ok(PackageType::Raft),
+            _ => Err(KvsError::Protocol(format!("unknown package type {}", b))),
         }
     }
 }
 
 // structure the package
 // |type_of_message(1 byte)|double package(1 byte)|size_of_main_part(4 bytes)|body(unsized)|
+// a double package carries a second part after the main one
+// |size_of_second_part(4 bytes)|body(unsized)|
 pub fn construct_package(p: Package) -> Vec<u8> {
-    let bsize = body_size(&p);
-    let psize = package_size(&p) as usize;
-    let mut buffer = vec![0; psize];
+    let mut buffer = Vec::with_capacity(package_size(&p) as usize);
 
     match p {
-        Package::Error(mss) => fill_single_buffer(&mut buffer, PackageType::Error, bsize, mss),
-        Package::Get(key) => fill_single_buffer(&mut buffer, PackageType::Get, bsize, key),
-        Package::Remove(key) => fill_single_buffer(&mut buffer, PackageType::Remove, bsize, key),
-        Package::OK(body) => fill_single_buffer(&mut buffer, PackageType::OK, bsize, body),
-        Package::Set(key, val) => fill_double_buffer(&mut buffer, PackageType::Set, bsize, key, val),
+        Package::Error(mss) => fill_single_buffer(&mut buffer, PackageType::Error, mss),
+        Package::Get(key) => fill_single_buffer(&mut buffer, PackageType::Get, key),
+        Package::Remove(key) => fill_single_buffer(&mut buffer, PackageType::Remove, key),
+        Package::OK(body) => fill_single_buffer(&mut buffer, PackageType::OK, body),
+        Package::Set(key, val) => fill_double_buffer(&mut buffer, PackageType::Set, key, val),
+        Package::Admin(body) => fill_single_buffer(&mut buffer, PackageType::Admin, body),
+        Package::Subscribe(body) => fill_single_buffer(&mut buffer, PackageType::Subscribe, body),
+        Package::Watch(body) => fill_single_buffer(&mut buffer, PackageType::Watch, body),
+        Package::Raft(body) => fill_single_buffer(&mut buffer, PackageType::Raft, body),
     };
 
     buffer
 }
 
-// check the old version
-pub fn deconstruct_package(b: &[u8]) -> Package {
-    let default_part = prelude_size as usize;
-    let b_size =  u32::from_be_bytes([b[2], b[3], b[4], b[5]]) as usize;
-    let finish_body = default_part + b_size;
-    match b[0].into() {
-        PackageType::OK => Package::OK(&b[default_part..finish_body]),
-        PackageType::Error => Package::Error(&b[default_part..finish_body]),
-        PackageType::Remove => Package::Remove(&b[default_part..finish_body]),
-        PackageType::Get => Package::Get(&b[default_part..finish_body]),
-        PackageType::Set => Package::Set(&b[default_part..finish_body], &b[finish_body ..]),
-        _ => unimplemented!(),
+/// Take apart the package `b` holds, failing on a malformed one
+pub fn deconstruct_package(b: &[u8]) -> crate::Result<Package<'_>> {
+    if b.len() < 2 {
+        return Err(KvsError::Protocol("empty package".to_owned()));
+    }
+    let pt = PackageType::try_from(b[0])?;
+    let (body, rest) = split_part(&b[2..])?;
+    Ok(match pt {
+        PackageType::OK => Package::OK(body),
+        PackageType::Error => Package::Error(body),
+        PackageType::Remove => Package::Remove(body),
+        PackageType::Get => Package::Get(body),
+        PackageType::Set => Package::Set(body, split_part(rest)?.0),
+        PackageType::Admin => Package::Admin(body),
+        PackageType::Subscribe => Package::Subscribe(body),
+        PackageType::Watch => Package::Watch(body),
+        PackageType::Raft => Package::Raft(body),
+    })
+}
+
+// split a part off the start of `b`, the rest of `b` follows it
+fn split_part(b: &[u8]) -> crate::Result<(&[u8], &[u8])> {
+    if b.len() < SIZE_LEN {
+        return Err(KvsError::Protocol("truncated package".to_owned()));
+    }
+    let size = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+    if b.len() - SIZE_LEN < size {
+        return Err(KvsError::Protocol("truncated package".to_owned()));
+    }
+    Ok(b[SIZE_LEN..].split_at(size))
+}
+
+const SIZE_LEN: usize = 4;
+
+const PRELUDE_SIZE: u32 = 1 + 1 + SIZE_LEN as u32;
+
+/// Largest package a peer is trusted to send, the size it tells isn't
+pub const MAX_PACKAGE_SIZE: usize = 64 << 20;
+
+/// Read a whole single package from `reader`,
+/// unlike a fixed buffer it holds a body of any size up to `MAX_PACKAGE_SIZE`
+pub fn read_package<R: std::io::Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0; PRELUDE_SIZE as usize];
+    reader.read_exact(&mut buffer)?;
+    read_part(reader, &mut buffer)?;
+    if buffer[1] == 1 {
+        let at = buffer.len();
+        buffer.resize(at + SIZE_LEN, 0);
+        reader.read_exact(&mut buffer[at..])?;
+        read_part(reader, &mut buffer)?;
     }
+    Ok(buffer)
 }
 
-const prelude_size: u32 = 1 + 1 + 4;
+// read the part whose size the last bytes of `buffer` tell
+fn read_part<R: std::io::Read>(reader: &mut R, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+    let at = buffer.len();
+    let size = u32::from_be_bytes([buffer[at - 4], buffer[at - 3], buffer[at - 2], buffer[at - 1]]) as usize;
+    if at + size > MAX_PACKAGE_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("package is larger than {} bytes", MAX_PACKAGE_SIZE),
+        ));
+    }
+    buffer.resize(at + size, 0);
+    reader.read_exact(&mut buffer[at..])
+}
 
 pub fn package_size(p: &Package) -> u32 {
-    prelude_size + body_size(p)
+    let second = match p {
+        Package::Set(..) => SIZE_LEN as u32,
+        _ => 0,
+    };
+    PRELUDE_SIZE + second + body_size(p)
 }
 
 fn body_size(p: &Package) -> u32 {
@@ -92,27 +386,29 @@ fn body_size(p: &Package) -> u32 {
         Package::Remove(key) => key.len(),
         Package::Set(key, val) => key.len() + val.len(),
         Package::OK(b) => b.len(),
+        Package::Admin(body) => body.len(),
+        Package::Subscribe(body) => body.len(),
+        Package::Watch(body) => body.len(),
+        Package::Raft(body) => body.len(),
     }) as u32
 }
 
-fn fill_double_buffer(dst: &mut [u8], pt: PackageType, size: u32, src1: &[u8], src2: &[u8]) {
-    let col = src1.iter().chain(src2).map(|e| *e).collect::<Vec<u8>>();
-    fill_buffer(dst, pt, true, size - src2.len() as u32, &col);
+fn fill_double_buffer(dst: &mut Vec<u8>, pt: PackageType, src1: &[u8], src2: &[u8]) {
+    fill_buffer(dst, pt, true, src1);
+    fill_part(dst, src2);
 }
 
-fn fill_single_buffer(dst: &mut [u8], pt: PackageType, size: u32, src: &[u8]) {
-    fill_buffer(dst, pt, false, size , src);
+fn fill_single_buffer(dst: &mut Vec<u8>, pt: PackageType, src: &[u8]) {
+    fill_buffer(dst, pt, false, src);
 }
 
-fn fill_buffer(dst: &mut [u8], pt: PackageType, is_double: bool, size: u32, src: &[u8]) {
-    dst[0] = pt as u8;
-    dst[1] = is_double as u8;
-    fill_bytes(&mut dst[2..6], &size.to_be_bytes());
-    fill_bytes(&mut dst[6..], src);
+fn fill_buffer(dst: &mut Vec<u8>, pt: PackageType, is_double: bool, src: &[u8]) {
+    dst.push(pt as u8);
+    dst.push(is_double as u8);
+    fill_part(dst, src);
 }
 
-fn fill_bytes(dst: &mut [u8], src: &[u8]) {
-    for i in 0..src.len() {
-        dst[i] = src[i]
-    }
-}
\ No newline at end of file
+fn fill_part(dst: &mut Vec<u8>, src: &[u8]) {
+    dst.extend_from_slice(&(src.len() as u32).to_be_bytes());
+    dst.extend_from_slice(src);
+}
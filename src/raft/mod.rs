@@ -0,0 +1,742 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::{KvsError, Result};
+use storage::Storage;
+
+pub use storage::SnapshotMeta;
+pub use transport::Transport;
+
+mod storage;
+mod transport;
+
+// a leader sends entries or an empty append this often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+// a follower which doesn't hear from a leader for a random time
+// between these starts an election
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+
+// a leader serves reads alone while a quorum acknowledged it within this time,
+// no other leader can be elected before a quorum's election timeout passes
+const LEASE: Duration = Duration::from_millis(150);
+
+// entries sent in one append
+const MAX_BATCH: usize = 64;
+
+// a snapshot which made no progress for this long is sent to the follower again
+const SNAPSHOT_RESEND: Duration = Duration::from_secs(1);
+
+// pairs of a snapshot are sent in chunks of about this many bytes
+const SNAPSHOT_CHUNK: usize = 1 << 20;
+
+/// What an entry of the log asks the state machine to do
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Proposal {
+    /// appended by a new leader to commit the entries of earlier terms
+    Noop,
+    Set { key: String, value: String },
+    Remove { key: String },
+    /// the members of the cluster from this entry on
+    Members(Vec<String>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub proposal: Proposal,
+}
+
+/// Message nodes of a cluster send each other, none of them waits for a reply
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub from: String,
+    pub term: u64,
+    pub body: MessageBody,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MessageBody {
+    Vote { last_index: u64, last_term: u64 },
+    VoteReply { granted: bool },
+    /// `sent` is echoed in the reply so the leader knows since when it's acknowledged
+    Append { prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64, sent: u64 },
+    /// `matched` is the last index the follower agrees on when it succeeded,
+    /// a hint where to look for it otherwise
+    AppendReply { success: bool, matched: u64, sent: u64 },
+    /// a chunk of a snapshot holding its pairs from `offset` on,
+    /// the follower installs the snapshot once it took the `last` one
+    Snapshot { meta: SnapshotMeta, offset: u64, pairs: Vec<(String, String)>, last: bool, sent: u64 },
+    /// the follower holds the first `received` pairs of the snapshot at `index`
+    SnapshotReply { index: u64, received: u64, sent: u64 },
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(rmp_serde::encode::to_vec(self)?)
+    }
+
+    pub fn decode(body: &[u8]) -> Result<Self> {
+        Ok(rmp_serde::decode::from_slice(body)?)
+    }
+}
+
+/// What the state machine is asked to apply, in order
+#[derive(Debug)]
+pub enum Committed {
+    Entry(Entry),
+    /// the pairs are the whole state at the index of the snapshot
+    Snapshot(Vec<(String, String)>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        })
+    }
+}
+
+/// Progress of a follower as its leader knows it
+struct Peer {
+    next: u64,
+    matched: u64,
+    /// send time of the latest message the follower acknowledged
+    acked: u64,
+    /// when the leader started to send the follower messages
+    added: u64,
+    /// when the latest chunk of a snapshot was sent
+    snapshot_sent: Option<Instant>,
+    snapshot: Option<Outgoing>,
+}
+
+/// Snapshot a leader sends a follower chunk by chunk
+struct Outgoing {
+    meta: SnapshotMeta,
+    pairs: Vec<(String, String)>,
+    /// pairs sent so far
+    sent: usize,
+}
+
+/// Node is a member of a Raft cluster.
+///
+/// It doesn't do any IO but its storage: messages to send are taken with
+/// `outbox` and the entries to apply with `committed`, the server moves
+/// messages between nodes with `step`.
+pub struct Node {
+    id: String,
+    storage: Storage,
+    role: Role,
+    leader: Option<String>,
+    commit: u64,
+    applied: u64,
+    /// when a follower or a candidate starts the next election
+    deadline: Instant,
+    heard_from_leader: Option<Instant>,
+    next_heartbeat: Instant,
+    votes: HashSet<String>,
+    peers: HashMap<String, Peer>,
+    outbox: Vec<(String, Message)>,
+    committed: Vec<Committed>,
+    /// followers so far behind that they need a snapshot
+    lagging: Vec<String>,
+    /// chunks of a snapshot a follower took so far
+    incoming: Option<(SnapshotMeta, Vec<(String, String)>)>,
+    /// messages are timestamped in milliseconds since it
+    epoch: Instant,
+    random: RandomState,
+}
+
+impl Node {
+    /// Open the node `id` keeping its state in `dir`.
+    ///
+    /// A node without a state yet starts as one of `members`,
+    /// a node joining a cluster starts with no members and waits
+    /// for the leader to add it.
+    pub fn open(id: String, dir: PathBuf, members: Vec<String>) -> Result<Self> {
+        let storage = Storage::open(dir, members)?;
+        let snapshot = storage.snapshot().index;
+        let now = Instant::now();
+        let mut node = Node {
+            id,
+            storage,
+            role: Role::Follower,
+            leader: None,
+            commit: snapshot,
+            applied: snapshot,
+            deadline: now,
+            heard_from_leader: None,
+            next_heartbeat: now,
+            votes: HashSet::new(),
+            peers: HashMap::new(),
+            outbox: Vec::new(),
+            committed: Vec::new(),
+            lagging: Vec::new(),
+            incoming: None,
+            epoch: now,
+            random: RandomState::new(),
+        };
+        node.reset_deadline(now);
+        Ok(node)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.storage.term()
+    }
+
+    /// The leader of the current term when it's known
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn commit(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    pub fn members(&self) -> &[String] {
+        self.storage.members()
+    }
+
+    pub fn snapshot(&self) -> &SnapshotMeta {
+        self.storage.snapshot()
+    }
+
+    /// Term of the entry at `index`, `None` once it's folded into the snapshot
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        self.storage.term_at(index)
+    }
+
+    /// Number of entries kept in the log past the snapshot
+    pub fn log_len(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// Messages to be sent along with the address of their receiver
+    pub fn outbox(&mut self) -> Vec<(String, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Entries and snapshots committed since the last call, in order
+    pub fn committed(&mut self) -> Vec<Committed> {
+        while self.applied < self.commit {
+            match self.storage.entry(self.applied + 1) {
+                Some(entry) => self.committed.push(Committed::Entry(entry.clone())),
+                None => break,
+            }
+            self.applied += 1;
+        }
+        std::mem::take(&mut self.committed)
+    }
+
+    /// Followers which need a snapshot, it's sent with `send_snapshot`
+    pub fn lagging(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.lagging)
+    }
+
+    /// Drive the timers of the node
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        match self.role {
+            Role::Leader => {
+                // a leader cut off from a quorum steps down
+                if !self.acknowledged_within(now, ELECTION_TIMEOUT_MAX, true) {
+                    log::warn!("{} lost the quorum in term {}", self.id, self.term());
+                    self.become_follower(now, self.term(), None)?;
+                } else if now >= self.next_heartbeat {
+                    self.broadcast(now);
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.deadline && self.is_member() {
+                    self.campaign(now)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a message of another node
+    pub fn step(&mut self, msg: Message, now: Instant) -> Result<()> {
+        if msg.term > self.term() {
+            let leader = match msg.body {
+                MessageBody::Append { .. } | MessageBody::Snapshot { .. } => Some(msg.from.clone()),
+                _ => None,
+            };
+            // a node which heard from its leader lately ignores candidates,
+            // so a removed member can't disrupt the cluster
+            if let MessageBody::Vote { .. } = msg.body {
+                if self.leader_is_alive(now) {
+                    return Ok(());
+                }
+            }
+            self.become_follower(now, msg.term, leader)?;
+        }
+        if msg.term < self.term() {
+            let body = match msg.body {
+                MessageBody::Vote { .. } => MessageBody::VoteReply { granted: false },
+                MessageBody::Append { sent, .. } | MessageBody::Snapshot { sent, .. } => {
+                    MessageBody::AppendReply { success: false, matched: 0, sent }
+                }
+                _ => return Ok(()),
+            };
+            self.send(msg.from, body);
+            return Ok(());
+        }
+
+        match msg.body {
+            MessageBody::Vote { last_index, last_term } => {
+                let free = self.storage.voted_for().is_none_or(|voted| voted == msg.from);
+                let up_to_date = (last_term, last_index) >= (self.storage.last_term(), self.storage.last_index());
+                let granted = free && up_to_date && !self.leader_is_alive(now);
+                if granted {
+                    self.storage.set_vote(self.term(), Some(msg.from.clone()))?;
+                    self.reset_deadline(now);
+                }
+                self.send(msg.from, MessageBody::VoteReply { granted });
+            }
+            MessageBody::VoteReply { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.has_quorum(&self.votes) {
+                        self.become_leader(now)?;
+                    }
+                }
+            }
+            MessageBody::Append { prev_index, prev_term, entries, commit, sent } => {
+                self.follow(now, msg.from.clone());
+                let body = self.append(prev_index, prev_term, entries, commit, sent)?;
+                self.send(msg.from, body);
+            }
+            MessageBody::AppendReply { success, matched, sent } => {
+                if self.role == Role::Leader {
+                    self.on_append_reply(now, msg.from, success, matched, sent);
+                }
+            }
+            MessageBody::Snapshot { meta, offset, pairs, last, sent } => {
+                self.follow(now, msg.from.clone());
+                let index = meta.index;
+                if index <= self.commit {
+                    self.incoming = None;
+                    let body = MessageBody::AppendReply { success: true, matched: self.commit, sent };
+                    self.send(msg.from, body);
+                    return Ok(());
+                }
+
+                // chunks are taken in order, the reply tells the leader which one is next
+                if offset == 0 {
+                    self.incoming = Some((meta, Vec::new()));
+                }
+                let (taken, received) = match &mut self.incoming {
+                    Some((held, held_pairs)) if held.index == index && held_pairs.len() as u64 == offset => {
+                        held_pairs.extend(pairs);
+                        (true, held_pairs.len() as u64)
+                    }
+                    Some((held, held_pairs)) if held.index == index => (false, held_pairs.len() as u64),
+                    _ => (false, 0),
+                };
+                if !(taken && last) {
+                    self.send(msg.from, MessageBody::SnapshotReply { index, received, sent });
+                    return Ok(());
+                }
+
+                let (meta, pairs) = self.incoming.take().expect("GG: no snapshot taken");
+                log::info!("{} installs a snapshot at {}", self.id, index);
+                self.storage.install(meta)?;
+                self.commit = index;
+                self.applied = index;
+                self.committed.push(Committed::Snapshot(pairs));
+                let body = MessageBody::AppendReply { success: true, matched: index, sent };
+                self.send(msg.from, body);
+            }
+            MessageBody::SnapshotReply { index, received, sent } => {
+                if self.role == Role::Leader {
+                    self.on_snapshot_reply(now, msg.from, index, received, sent);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a proposal to the log of the leader returning its index,
+    /// it's applied once `committed` returns it
+    pub fn propose(&mut self, proposal: Proposal, now: Instant) -> Result<u64> {
+        if self.role != Role::Leader {
+            return Err(self.not_leader());
+        }
+        if let Proposal::Members(_) = proposal {
+            // members change one at a time so any two quorums overlap
+            let pending = (self.commit + 1..=self.storage.last_index()).any(|index| {
+                matches!(self.storage.entry(index), Some(Entry { proposal: Proposal::Members(_), .. }))
+            });
+            if pending {
+                return Err(KvsError::Unsupported("a change of members is in progress".to_owned()));
+            }
+        }
+
+        let entry = Entry {
+            index: self.storage.last_index() + 1,
+            term: self.term(),
+            proposal,
+        };
+        let index = entry.index;
+        self.storage.append(&[entry])?;
+        self.advance_commit(now)?;
+        self.broadcast(now);
+        Ok(index)
+    }
+
+    /// Whether the leader can serve a read from its state machine alone:
+    /// an entry of its term is committed and a quorum acknowledged it lately
+    pub fn can_read(&self, now: Instant) -> bool {
+        self.role == Role::Leader
+            && self.storage.term_at(self.commit) == Some(self.term())
+            && self.applied == self.commit
+            && self.acknowledged_within(now, LEASE, false)
+    }
+
+    /// Error telling a client where the leader is
+    pub fn not_leader(&self) -> KvsError {
+        match &self.leader {
+            Some(leader) => KvsError::NotLeader(format!("the leader is {}", leader)),
+            None => KvsError::NotLeader("there is no leader".to_owned()),
+        }
+    }
+
+    /// Fold the log up to `index` into a snapshot,
+    /// the state machine must have applied it and be checkpointed
+    pub fn compact(&mut self, index: u64) -> Result<()> {
+        self.storage.compact(index.min(self.applied))
+    }
+
+    /// Send the snapshot described by `meta` to a lagging follower,
+    /// a chunk at a time as the follower takes them
+    pub fn send_snapshot(&mut self, to: &str, meta: SnapshotMeta, pairs: Vec<(String, String)>, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(to) {
+            peer.snapshot = Some(Outgoing { meta, pairs, sent: 0 });
+            self.send_chunk(to, now);
+        }
+    }
+
+    /// Send a follower the next chunk of its snapshot
+    fn send_chunk(&mut self, to: &str, now: Instant) {
+        let sent = self.millis(now);
+        let peer = match self.peers.get_mut(to) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let outgoing = match &mut peer.snapshot {
+            Some(outgoing) => outgoing,
+            None => return,
+        };
+
+        let (offset, mut end, mut size) = (outgoing.sent, outgoing.sent, 0);
+        while end < outgoing.pairs.len() && size < SNAPSHOT_CHUNK {
+            size += outgoing.pairs[end].0.len() + outgoing.pairs[end].1.len();
+            end += 1;
+        }
+        outgoing.sent = end;
+        peer.snapshot_sent = Some(now);
+        let body = MessageBody::Snapshot {
+            meta: outgoing.meta.clone(),
+            offset: offset as u64,
+            pairs: outgoing.pairs[offset..end].to_vec(),
+            last: end == outgoing.pairs.len(),
+            sent,
+        };
+        self.send(to.to_owned(), body);
+    }
+
+    /// Go on with the snapshot once the follower took every chunk sent,
+    /// a follower which lost one gets the snapshot again when it stalls
+    fn on_snapshot_reply(&mut self, now: Instant, from: String, index: u64, received: u64, sent: u64) {
+        let peer = match self.peers.get_mut(&from) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.acked = peer.acked.max(sent);
+
+        let next = peer.snapshot.as_ref().is_some_and(|outgoing| {
+            outgoing.meta.index == index && outgoing.sent as u64 == received && outgoing.sent < outgoing.pairs.len()
+        });
+        if next {
+            self.send_chunk(&from, now);
+        }
+    }
+
+    fn is_member(&self) -> bool {
+        self.storage.members().contains(&self.id)
+    }
+
+    fn has_quorum(&self, voters: &HashSet<String>) -> bool {
+        let members = self.storage.members();
+        let votes = members.iter().filter(|member| voters.contains(*member)).count();
+        votes > members.len() / 2
+    }
+
+    fn leader_is_alive(&self, now: Instant) -> bool {
+        self.heard_from_leader
+            .is_some_and(|heard| now.duration_since(heard) < ELECTION_TIMEOUT_MIN)
+    }
+
+    /// Whether a quorum, the leader counted in, acknowledged it within `within`,
+    /// followers added within it count as well when `lenient` so a new member
+    /// gets the time to answer
+    fn acknowledged_within(&self, now: Instant, within: Duration, lenient: bool) -> bool {
+        let since = self.millis(now).saturating_sub(within.as_millis() as u64);
+        let mut acked = HashSet::new();
+        if self.is_member() {
+            acked.insert(self.id.clone());
+        }
+        for (id, peer) in &self.peers {
+            let heard = match lenient {
+                true => peer.acked.max(peer.added),
+                false => peer.acked,
+            };
+            if heard >= since && heard > 0 {
+                acked.insert(id.clone());
+            }
+        }
+        self.has_quorum(&acked)
+    }
+
+    fn millis(&self, now: Instant) -> u64 {
+        now.duration_since(self.epoch).as_millis() as u64 + 1
+    }
+
+    fn reset_deadline(&mut self, now: Instant) {
+        let spread = (ELECTION_TIMEOUT_MAX - ELECTION_TIMEOUT_MIN).as_millis() as u64;
+        let jitter = self.random.hash_one((&self.id, now)) % spread.max(1);
+        self.deadline = now + ELECTION_TIMEOUT_MIN + Duration::from_millis(jitter);
+    }
+
+    fn follow(&mut self, now: Instant, leader: String) {
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+            self.peers.clear();
+        }
+        self.leader = Some(leader);
+        self.heard_from_leader = Some(now);
+        self.reset_deadline(now);
+    }
+
+    fn become_follower(&mut self, now: Instant, term: u64, leader: Option<String>) -> Result<()> {
+        if term != self.term() {
+            self.storage.set_vote(term, None)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.peers.clear();
+        self.reset_deadline(now);
+        Ok(())
+    }
+
+    fn campaign(&mut self, now: Instant) -> Result<()> {
+        let term = self.term() + 1;
+        log::info!("{} starts an election for term {}", self.id, term);
+        self.storage.set_vote(term, Some(self.id.clone()))?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.heard_from_leader = None;
+        self.votes = HashSet::new();
+        self.votes.insert(self.id.clone());
+        self.reset_deadline(now);
+
+        if self.has_quorum(&self.votes) {
+            return self.become_leader(now);
+        }
+        let (last_index, last_term) = (self.storage.last_index(), self.storage.last_term());
+        for member in self.others() {
+            self.send(member, MessageBody::Vote { last_index, last_term });
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Result<()> {
+        log::info!("{} is the leader of term {}", self.id, self.term());
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.peers.clear();
+        self.propose(Proposal::Noop, now)?;
+        Ok(())
+    }
+
+    /// Members other than the node itself
+    fn others(&self) -> Vec<String> {
+        self.storage
+            .members()
+            .iter()
+            .filter(|member| **member != self.id)
+            .cloned()
+            .collect()
+    }
+
+    /// Send every follower the entries it misses, an empty append at least
+    fn broadcast(&mut self, now: Instant) {
+        let others = self.others();
+        self.peers.retain(|id, _| others.contains(id));
+        let added = self.millis(now);
+        for id in others {
+            let next = self.storage.last_index() + 1;
+            self.peers.entry(id.clone()).or_insert(Peer {
+                next,
+                matched: 0,
+                acked: 0,
+                added,
+                snapshot_sent: None,
+                snapshot: None,
+            });
+            self.send_append(&id, now);
+        }
+        self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+    }
+
+    fn send_append(&mut self, id: &str, now: Instant) {
+        let sent = self.millis(now);
+        let peer = match self.peers.get(id) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        let prev_index = peer.next - 1;
+        let prev_term = match self.storage.term_at(prev_index) {
+            Some(term) => term,
+            None => {
+                let resend = peer.snapshot_sent.is_none_or(|at| now.duration_since(at) >= SNAPSHOT_RESEND);
+                if resend && !self.lagging.iter().any(|lagging| lagging == id) {
+                    self.lagging.push(id.to_owned());
+                }
+                return;
+            }
+        };
+        let entries = self.storage.entries(peer.next, MAX_BATCH).to_vec();
+        let body = MessageBody::Append {
+            prev_index,
+            prev_term,
+            entries,
+            commit: self.commit,
+            sent,
+        };
+        self.send(id.to_owned(), body);
+    }
+
+    /// Append the entries of the leader when the log agrees with it at `prev_index`
+    fn append(&mut self, prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64, sent: u64) -> Result<MessageBody> {
+        let snapshot = self.storage.snapshot().index;
+        let agrees = prev_index < snapshot || self.storage.term_at(prev_index) == Some(prev_term);
+        if !agrees {
+            let hint = self.storage.last_index().min(prev_index.saturating_sub(1));
+            return Ok(MessageBody::AppendReply { success: false, matched: hint, sent });
+        }
+
+        let matched = prev_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for entry in entries.into_iter().filter(|entry| entry.index > snapshot) {
+            if !new.is_empty() {
+                new.push(entry);
+                continue;
+            }
+            match self.storage.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.storage.truncate(entry.index)?;
+                    new.push(entry);
+                }
+                None => new.push(entry),
+            }
+        }
+        self.storage.append(&new)?;
+
+        if commit > self.commit {
+            self.commit = commit.min(matched).max(self.commit);
+        }
+        Ok(MessageBody::AppendReply { success: true, matched, sent })
+    }
+
+    fn on_append_reply(&mut self, now: Instant, from: String, success: bool, matched: u64, sent: u64) {
+        let last = self.storage.last_index();
+        let peer = match self.peers.get_mut(&from) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.acked = peer.acked.max(sent);
+
+        if success {
+            peer.matched = peer.matched.max(matched);
+            peer.next = peer.matched + 1;
+            peer.snapshot_sent = None;
+            peer.snapshot = None;
+            if let Err(err) = self.advance_commit(now) {
+                log::error!("cannot commit: {}", err);
+            }
+        } else {
+            peer.next = (matched + 1).min(peer.next.saturating_sub(1)).max(1);
+        }
+
+        let behind = self.peers.get(&from).is_some_and(|peer| peer.next <= last);
+        if behind || !success {
+            self.send_append(&from, now);
+        }
+    }
+
+    /// Commit the latest entry of the term a quorum stores
+    fn advance_commit(&mut self, now: Instant) -> Result<()> {
+        let last = self.storage.last_index();
+        for index in (self.commit + 1..=last).rev() {
+            if self.storage.term_at(index) != Some(self.term()) {
+                break;
+            }
+            let mut stored: HashSet<String> = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.matched >= index)
+                .map(|(id, _)| id.clone())
+                .collect();
+            stored.insert(self.id.clone());
+            if self.has_quorum(&stored) {
+                self.commit = index;
+                break;
+            }
+        }
+
+        // a leader which isn't a member anymore leaves once that's committed
+        if !self.is_member() && self.storage.members_at(self.commit) == self.storage.members() {
+            log::info!("{} left the cluster", self.id);
+            self.become_follower(now, self.term(), None)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, to: String, body: MessageBody) {
+        let msg = Message {
+            from: self.id.clone(),
+            term: self.term(),
+            body,
+        };
+        self.outbox.push((to, msg));
+    }
+}
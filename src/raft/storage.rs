@@ -0,0 +1,241 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use crate::Result;
+use super::{Entry, Proposal};
+
+static STATE_FILE: &str = "STATE";
+static LOG_FILE: &str = "LOG";
+
+/// Last entry folded into a snapshot along with the members it left
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    pub index: u64,
+    pub term: u64,
+    pub members: Vec<String>,
+}
+
+/// State which must survive a restart before a message is answered
+#[derive(Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+    snapshot: SnapshotMeta,
+}
+
+/// Storage keeps the Raft state and the entries after the snapshot
+/// in a directory.
+///
+/// The log is a file of entries, each is
+/// |size(4 bytes)|msgpack encoded entry(unsized)|
+/// it's appended to and rewritten as a whole when entries are dropped.
+pub(super) struct Storage {
+    dir: PathBuf,
+    state: HardState,
+    entries: Vec<Entry>,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    /// Open the storage in `dir`, a new one starts with `members`
+    pub(super) fn open(dir: PathBuf, members: Vec<String>) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let state = match std::fs::read(dir.join(STATE_FILE)) {
+            Ok(content) => rmp_serde::decode::from_slice(&content)?,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => HardState {
+                snapshot: SnapshotMeta { members, ..SnapshotMeta::default() },
+                ..HardState::default()
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        // a torn entry at the end was never acknowledged
+        let mut entries = Vec::new();
+        if let Ok(file) = File::open(dir.join(LOG_FILE)) {
+            let mut reader = BufReader::new(file);
+            while let Ok(entry) = read_entry(&mut reader) {
+                entries.push(entry);
+            }
+        }
+        let first = state.snapshot.index + 1;
+        entries.retain(|entry: &Entry| entry.index >= first);
+        if let Some(gap) = entries.iter().zip(first..).position(|(entry, index)| entry.index != index) {
+            entries.truncate(gap);
+        }
+
+        let storage = Storage {
+            log: write_log(&dir, &entries)?,
+            dir,
+            state,
+            entries,
+        };
+        storage.save_state()?;
+        Ok(storage)
+    }
+
+    pub(super) fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub(super) fn voted_for(&self) -> Option<&str> {
+        self.state.voted_for.as_deref()
+    }
+
+    pub(super) fn set_vote(&mut self, term: u64, voted_for: Option<String>) -> Result<()> {
+        self.state.term = term;
+        self.state.voted_for = voted_for;
+        self.save_state()
+    }
+
+    pub(super) fn snapshot(&self) -> &SnapshotMeta {
+        &self.state.snapshot
+    }
+
+    pub(super) fn last_index(&self) -> u64 {
+        self.state.snapshot.index + self.entries.len() as u64
+    }
+
+    pub(super) fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.state.snapshot.term, |entry| entry.term)
+    }
+
+    /// Term of the entry at `index`, `None` once it's folded into the snapshot
+    pub(super) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot.index {
+            return Some(self.state.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub(super) fn entry(&self, index: u64) -> Option<&Entry> {
+        let snapshot = self.state.snapshot.index;
+        if index <= snapshot {
+            return None;
+        }
+        self.entries.get((index - snapshot - 1) as usize)
+    }
+
+    /// Entries from `from` on, up to `limit` of them
+    pub(super) fn entries(&self, from: u64, limit: usize) -> &[Entry] {
+        let start = (from.max(self.state.snapshot.index + 1) - self.state.snapshot.index - 1) as usize;
+        let start = start.min(self.entries.len());
+        let end = (start + limit).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    /// Number of entries which aren't folded into the snapshot
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Members of the latest configuration, committed or not
+    pub(super) fn members(&self) -> &[String] {
+        self.members_at(self.last_index())
+    }
+
+    /// Members of the configuration in effect at `index`
+    pub(super) fn members_at(&self, index: u64) -> &[String] {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.proposal {
+                Proposal::Members(members) => Some(&members[..]),
+                _ => None,
+            })
+            .unwrap_or(&self.state.snapshot.members)
+    }
+
+    /// Append entries which follow the last one and sync them
+    pub(super) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            write_entry(&mut self.log, entry)?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// Drop the entries from `index` on
+    pub(super) fn truncate(&mut self, index: u64) -> Result<()> {
+        let keep = index.saturating_sub(self.state.snapshot.index + 1) as usize;
+        self.entries.truncate(keep);
+        self.rewrite()
+    }
+
+    /// Fold the entries up to `index` into a snapshot
+    pub(super) fn compact(&mut self, index: u64) -> Result<()> {
+        let snapshot = SnapshotMeta {
+            index,
+            term: self.term_at(index).unwrap_or(self.state.snapshot.term),
+            members: self.members_at(index).to_vec(),
+        };
+        let folded = (index.saturating_sub(self.state.snapshot.index) as usize).min(self.entries.len());
+        self.entries.drain(..folded);
+        self.state.snapshot = snapshot;
+        self.save_state()?;
+        self.rewrite()
+    }
+
+    /// Replace the whole log by a snapshot of the leader
+    pub(super) fn install(&mut self, snapshot: SnapshotMeta) -> Result<()> {
+        // entries past the snapshot are kept when they agree with it
+        let keeps = self.term_at(snapshot.index) == Some(snapshot.term);
+        let folded = match keeps {
+            true => (snapshot.index - self.state.snapshot.index) as usize,
+            false => self.entries.len(),
+        };
+        self.entries.drain(..folded.min(self.entries.len()));
+        self.state.snapshot = snapshot;
+        self.save_state()?;
+        self.rewrite()
+    }
+
+    /// Replace the state file atomically
+    fn save_state(&self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&rmp_serde::encode::to_vec(&self.state)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        Ok(())
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        self.log = write_log(&self.dir, &self.entries)?;
+        Ok(())
+    }
+}
+
+/// Write `entries` to a new log file replacing the old one,
+/// returning the writer appending to it
+fn write_log(dir: &Path, entries: &[Entry]) -> Result<BufWriter<File>> {
+    let tmp = dir.join(format!("{}.tmp", LOG_FILE));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for entry in entries {
+        write_entry(&mut writer, entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(&tmp, dir.join(LOG_FILE))?;
+
+    let file = std::fs::OpenOptions::new().append(true).open(dir.join(LOG_FILE))?;
+    Ok(BufWriter::new(file))
+}
+
+fn write_entry<W: Write>(writer: &mut W, entry: &Entry) -> Result<()> {
+    let b = rmp_serde::encode::to_vec(entry)?;
+    writer.write_u32::<BigEndian>(b.len() as u32)?;
+    writer.write_all(&b)?;
+    Ok(())
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<Entry> {
+    let size = reader.read_u32::<BigEndian>()?;
+    let mut b = vec![0; size as usize];
+    reader.read_exact(&mut b)?;
+    Ok(rmp_serde::decode::from_slice(&b)?)
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use crate::client::reply;
+use crate::{construct_package, Package, Result};
+use super::Message;
+
+// messages waiting for a peer, newer ones are dropped once it's full
+const QUEUE_SIZE: usize = 256;
+
+// a peer which doesn't answer within it is taken as down
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Transport delivers messages to the other nodes from a thread per peer.
+///
+/// Delivery is best effort: Raft resends whatever is lost,
+/// so a message to a peer which is down or too slow is dropped.
+pub struct Transport {
+    peers: HashMap<String, SyncSender<Vec<u8>>>,
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Transport { peers: HashMap::new() }
+    }
+
+    pub fn send(&mut self, to: &str, msg: &Message) -> Result<()> {
+        let package = construct_package(Package::Raft(&msg.encode()?));
+        let sender = self.peers.entry(to.to_owned()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+            let peer = to.to_owned();
+            thread::spawn(move || deliver(&peer, receiver));
+            sender
+        });
+        match sender.try_send(package) {
+            Err(TrySendError::Full(_)) => log::debug!("dropped a message to {}", to),
+            Err(TrySendError::Disconnected(_)) => {
+                self.peers.remove(to);
+            }
+            Ok(()) => (),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn deliver(peer: &str, receiver: Receiver<Vec<u8>>) {
+    for package in receiver {
+        if let Err(err) = send_package(peer, &package) {
+            log::debug!("cannot reach {}: {}", peer, err);
+        }
+    }
+}
+
+fn send_package(peer: &str, package: &[u8]) -> Result<()> {
+    let addr = peer.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address for {}", peer))
+    })?;
+    let mut socket = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.write_all(package)?;
+    reply(&mut socket)?;
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::client::{admin, reply, RemotePairs};
+use crate::{construct_package, AdminCommand, Change, ErrorKind, KvsError, Package, Result, Subscription};
+
+// how often a follower tells the leader how far it got
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// a follower which lost the leader starts over after this long
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a follower receives from its leader, to be applied in order
+#[derive(Debug)]
+pub enum Replicated {
+    /// a snapshot of the leader begins
+    Snapshot,
+    /// a pair of the snapshot
+    Pair(String, String),
+    /// the snapshot is complete, keys it didn't hold are gone from the leader.
+    /// It holds every change up to this sequence number
+    Synced(u64),
+    /// a change the leader made after the snapshot
+    Change(Change),
+}
+
+/// Replica is the state of a follower shared with the threads replicating the leader
+pub struct Replica {
+    leader: String,
+    applied: AtomicU64,
+    progress: Mutex<Progress>,
+}
+
+/// Progress keeps when the follower was last caught up
+/// along with the latest changes the leader had when it wasn't
+struct Progress {
+    caught_up: Instant,
+    pending: VecDeque<(u64, Instant)>,
+}
+
+impl Replica {
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    /// Sequence number of the latest change of the leader applied
+    pub fn applied(&self) -> u64 {
+        self.applied.load(Ordering::SeqCst)
+    }
+
+    /// Record that the changes of the leader up to `seq` are applied
+    pub fn apply(&self, seq: u64) {
+        self.applied.store(seq, Ordering::SeqCst);
+
+        let mut progress = self.progress.lock().expect("GG: poisoned progress");
+        while let Some(&(pending, at)) = progress.pending.front() {
+            if pending > seq {
+                break;
+            }
+            progress.caught_up = at;
+            progress.pending.pop_front();
+        }
+    }
+
+    /// Time since the follower last held every change the leader had
+    pub fn lag(&self) -> Duration {
+        self.progress.lock().expect("GG: poisoned progress").caught_up.elapsed()
+    }
+
+    fn reported(&self, at: Instant, caught_up: bool, seq: u64) {
+        let mut progress = self.progress.lock().expect("GG: poisoned progress");
+        if caught_up {
+            progress.caught_up = at;
+            progress.pending.clear();
+        } else if progress.pending.back().is_none_or(|&(pending, _)| pending < seq) {
+            progress.pending.push_back((seq, at));
+        }
+    }
+}
+
+/// Follow the leader at `leader` from background threads.
+///
+/// The leader is copied by a snapshot and then its changes are tailed,
+/// both are sent to `sender` to be applied in order.
+/// Whenever the leader is lost, or it compacted away changes the follower
+/// didn't get yet, it starts over with a new snapshot.
+/// The follower reports how far it got to the leader as `follower`.
+///
+/// It fails right away when the leader keeps no change feed to tail.
+pub fn follow(leader: String, follower: String, sender: SyncSender<Replicated>) -> Result<Arc<Replica>> {
+    match admin(&leader, AdminCommand::Seq) {
+        Err(KvsError::Remote(ErrorKind::Unsupported, msg)) => {
+            return Err(KvsError::Unsupported(format!("leader {} cannot be followed: {}", leader, msg)));
+        }
+        // the leader may come up later
+        Err(err) => log::warn!("cannot reach leader {}: {}", leader, err),
+        Ok(_) => (),
+    }
+
+    let replica = Arc::new(Replica {
+        leader,
+        applied: AtomicU64::new(0),
+        progress: Mutex::new(Progress {
+            caught_up: Instant::now(),
+            pending: VecDeque::new(),
+        }),
+    });
+
+    let tail = replica.clone();
+    thread::spawn(move || loop {
+        match replicate(tail.leader(), &sender) {
+            // nobody applies the changes anymore
+            Ok(()) => return,
+            // a new snapshot holds what the compacted changes left
+            Err(KvsError::Remote(ErrorKind::ResyncRequired, msg)) => {
+                log::info!("copy leader {} again: {}", tail.leader(), msg);
+                continue;
+            }
+            Err(err) => log::warn!("lost leader {}: {}", tail.leader(), err),
+        }
+        thread::sleep(RETRY_INTERVAL);
+    });
+
+    let reporter = replica.clone();
+    thread::spawn(move || loop {
+        thread::sleep(REPORT_INTERVAL);
+        if let Err(err) = report(&reporter, &follower) {
+            log::info!("cannot report to leader {}: {}", reporter.leader(), err);
+        }
+    });
+
+    Ok(replica)
+}
+
+/// Copy the leader and tail its changes until the leader is lost,
+/// it returns `Ok` only once `sender` is disconnected
+fn replicate(leader: &str, sender: &SyncSender<Replicated>) -> Result<()> {
+    // changes made while the snapshot is taken are applied twice,
+    // which leaves the same pairs as applying them once
+    let since: u64 = rmp_serde::decode::from_slice(&admin(leader, AdminCommand::Seq)?)?;
+    if sender.send(Replicated::Snapshot).is_err() {
+        return Ok(());
+    }
+    for pair in RemotePairs::new(leader) {
+        let (key, value) = pair?;
+        if sender.send(Replicated::Pair(key, value)).is_err() {
+            return Ok(());
+        }
+    }
+    if sender.send(Replicated::Synced(since)).is_err() {
+        return Ok(());
+    }
+
+    let mut socket = TcpStream::connect(leader)?;
+    let subscription = Subscription {
+        prefix: String::new(),
+        since: Some(since),
+    };
+    socket.write_all(&construct_package(Package::Subscribe(&subscription.encode()?)))?;
+    reply(&mut socket)?;
+    log::info!("following {} after {}", leader, since);
+
+    loop {
+        let change: Change = rmp_serde::decode::from_slice(&reply(&mut socket)?)?;
+        if sender.send(Replicated::Change(change)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn report(replica: &Replica, follower: &str) -> Result<()> {
+    let at = Instant::now();
+    let command = AdminCommand::Report {
+        follower: follower.to_owned(),
+        applied: replica.applied(),
+        lag_ms: replica.lag().as_millis() as u64,
+    };
+    let (caught_up, seq): (bool, u64) = rmp_serde::decode::from_slice(&admin(replica.leader(), command)?)?;
+    replica.reported(at, caught_up, seq);
+    Ok(())
+}
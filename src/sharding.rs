@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::{KvsClient, KvsError, Result};
+
+// points every node takes on the ring, more of them spread the keys more evenly
+const VIRTUAL_NODES: u32 = 128;
+
+/// Ring maps keys to nodes by consistent hashing.
+///
+/// Every node takes `VIRTUAL_NODES` points of the ring and a key belongs
+/// to the node of the first point at or after its hash, so adding a node
+/// moves only the keys which fall right before its points.
+/// The hash is stable, so every client routes a key the same way.
+#[derive(Clone, Debug, Default)]
+pub struct Ring {
+    points: BTreeMap<u64, String>,
+}
+
+impl Ring {
+    pub fn new<I, S>(nodes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut ring = Ring::default();
+        for node in nodes {
+            ring.add(node.into());
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: String) {
+        for point in 0..VIRTUAL_NODES {
+            self.points.insert(hash(format!("{}#{}", node, point).as_bytes()), node.clone());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|_, owner| owner != node);
+    }
+
+    /// The node `key` belongs to, `None` when the ring is empty
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let hash = hash(key.as_bytes());
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// Nodes of the ring in ascending order
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes: Vec<&str> = self.points.values().map(String::as_str).collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+}
+
+/// 64 bit FNV-1a followed by the finalizer of splitmix64,
+/// FNV alone leaves similar names close on the ring
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// ShardedClient spreads the keys over several servers,
+/// each key is kept by the server the ring maps it to.
+pub struct ShardedClient {
+    ring: Ring,
+    clients: HashMap<String, KvsClient>,
+}
+
+impl ShardedClient {
+    pub fn new<I, S>(addrs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let addrs: Vec<String> = addrs.into_iter().map(Into::into).collect();
+        ShardedClient {
+            ring: Ring::new(addrs.clone()),
+            clients: addrs.into_iter().map(|addr| (addr.clone(), KvsClient::new(addr))).collect(),
+        }
+    }
+
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.client(&key)?.get(key)
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.client(&key)?.set(key, value)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.client(&key)?.remove(key)
+    }
+
+    /// Add the server at `addr` moving the keys it takes over from the other servers,
+    /// returning how many were moved.
+    ///
+    /// A key is written to the new server before it's removed from the old one,
+    /// so it stays readable from one of them while it's moved.
+    pub fn add_node(&mut self, addr: String) -> Result<u64> {
+        if self.clients.contains_key(&addr) {
+            return Ok(0);
+        }
+        self.ring.add(addr.clone());
+        self.clients.insert(addr.clone(), KvsClient::new(addr.clone()));
+        self.rebalance()
+    }
+
+    /// Move every key which isn't kept by the server the ring maps it to,
+    /// returning how many were moved
+    pub fn rebalance(&self) -> Result<u64> {
+        let mut moved = 0;
+        for (addr, client) in &self.clients {
+            for pair in client.pairs() {
+                let (key, value) = pair?;
+                let owner = self.client(&key)?;
+                if owner.addr() == addr {
+                    continue;
+                }
+
+                owner.set(key.clone(), value)?;
+                match client.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => moved += 1,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(moved)
+    }
+
+    fn client(&self, key: &str) -> Result<&KvsClient> {
+        self.ring
+            .node(key)
+            .and_then(|node| self.clients.get(node))
+            .ok_or_else(|| KvsError::Unsupported("a sharded client without servers".to_owned()))
+    }
+}
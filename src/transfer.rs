@@ -0,0 +1,112 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io::{prelude::*, BufReader};
+use std::str::FromStr;
+use crate::{KvsError, Pairs, Result};
+
+// a CSV field holding a control character is written
+// in base64 behind this prefix, so every record takes one line
+static BASE64_PREFIX: &str = "base64:";
+
+/// Format of exported pairs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// a JSON object `{"key": .., "value": ..}` per line
+    Jsonl,
+    /// `key,value` records after a header
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// Write `pairs` to `writer` one by one returning how many were written
+pub fn export<W: Write>(
+    pairs: impl IntoIterator<Item = Result<(String, String)>>,
+    writer: W,
+    format: Format,
+) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        Format::Jsonl => {
+            let mut writer = std::io::BufWriter::new(writer);
+            for pair in pairs {
+                let (key, value) = pair?;
+                serde_json::to_writer(&mut writer, &Record { key, value })?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value"])?;
+            for pair in pairs {
+                let (key, value) = pair?;
+                writer.write_record(&[encode_field(&key), encode_field(&value)])?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Read pairs written by `export` lazily
+pub fn import<'a, R: Read + 'a>(reader: R, format: Format) -> Pairs<'a> {
+    match format {
+        Format::Jsonl => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| {
+                    let record: Record = serde_json::from_str(&line?)?;
+                    Ok((record.key, record.value))
+                }),
+        ),
+        Format::Csv => Box::new(csv::Reader::from_reader(reader).into_records().map(|record| {
+            let record = record?;
+            match (record.get(0), record.get(1), record.len()) {
+                (Some(key), Some(value), 2) => Ok((decode_field(key)?, decode_field(value)?)),
+                _ => Err(KvsError::Malformed(format!("expected a key and a value, got {:?}", record))),
+            }
+        })),
+    }
+}
+
+fn encode_field(field: &str) -> String {
+    if field.starts_with(BASE64_PREFIX) || field.chars().any(char::is_control) {
+        format!("{}{}", BASE64_PREFIX, STANDARD.encode(field))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn decode_field(field: &str) -> Result<String> {
+    match field.strip_prefix(BASE64_PREFIX) {
+        Some(encoded) => {
+            let bytes = STANDARD
+                .decode(encoded)
+                .map_err(|err| KvsError::Malformed(format!("bad base64 field: {}", err)))?;
+            Ok(String::from_utf8(bytes)?)
+        }
+        None => Ok(field.to_owned()),
+    }
+}
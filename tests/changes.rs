@@ -0,0 +1,265 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use kvs::{Change, KvStore, KvStoreOptions, KvsEngine, KvsError, MemoryStore, Result};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn changes(store: &mut KvStore, since: u64) -> Result<Vec<Change>> {
+    store.changes(since)?.collect()
+}
+
+// The feed should hold every write in order, also after a reopen
+#[test]
+fn change_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(16),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let middle = store.seq()?;
+    store.remove("key1".to_owned())?;
+    store.set("large".to_owned(), "a value kept in a blob file".to_owned())?;
+
+    let all = changes(&mut store, 0)?;
+    let found: Vec<_> = all.iter().map(|change| (change.key.as_str(), change.value.as_deref())).collect();
+    assert_eq!(
+        found,
+        vec![
+            ("key1", Some("value1")),
+            ("key2", Some("value2")),
+            ("key1", None),
+            ("large", Some("a value kept in a blob file")),
+        ]
+    );
+    assert!(all.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+    assert_eq!(all[1].seq, middle);
+    assert_eq!(all[3].seq, store.seq()?);
+    assert_eq!(changes(&mut store, middle)?, all[2..].to_vec());
+    let last = store.seq()?;
+    assert!(changes(&mut store, last)?.is_empty());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(changes(&mut store, middle)?, all[2..].to_vec());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let last = changes(&mut store, all[3].seq)?;
+    assert_eq!(last.len(), 1);
+    assert!(last[0].seq > all[3].seq);
+
+    Ok(())
+}
+
+// A reader which keeps up shouldn't see compacted pairs again,
+// one which fell behind should catch up with the live pairs
+// or start over when it missed a remove
+#[test]
+fn change_feed_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    let before_remove = store.seq()?;
+    store.remove("removed".to_owned())?;
+
+    let mut seq = store.seq()?;
+    for iter in 0..2000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        let new = changes(&mut store, seq)?;
+        assert_eq!(new.len(), 1, "{:?}", new);
+        assert_eq!(new[0].value, Some(format!("{}", iter)));
+        seq = new[0].seq;
+    }
+
+    // the compacted generation stands for the changes it replaced
+    let behind = changes(&mut store, 0)?;
+    assert!(behind.len() < 2000);
+    assert!(behind.iter().all(|change| change.key != "removed"));
+    let mut state = std::collections::BTreeMap::new();
+    for change in behind {
+        state.insert(change.key, change.value);
+    }
+    assert_eq!(state["key"], Some("1999".to_owned()));
+    assert_eq!(state["other"], Some("value".to_owned()));
+
+    // the remove isn't in the log anymore, so it can't be told
+    match changes(&mut store, before_remove) {
+        Err(KvsError::ResyncRequired(seq)) => assert_eq!(seq, before_remove),
+        res => panic!("a reader which missed a remove wasn't asked to resync: {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn change_feed_unsupported() {
+    assert!(MemoryStore::new().changes(0).is_err());
+}
+
+// kvs-client watch should print the changes of keys under its prefix
+#[test]
+fn cli_watch() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "user.1", "before", "--addr", addr])
+        .assert()
+        .success();
+
+    let mut watch = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "user.", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    for args in &[
+        ["set", "user.1", "alice"],
+        ["set", "group.1", "admins"],
+        ["set", "user.2", "bob"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "user.1", "--addr", addr])
+        .assert()
+        .success();
+
+    // the watcher ends once the server is gone
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv();
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    });
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let mut output = String::new();
+    watch.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    watch.wait().unwrap();
+    let lines: Vec<_> = output
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect();
+    assert_eq!(lines, vec!["set user.1 alice", "set user.2 bob", "rm user.1"]);
+
+    // values are stored without the padding of the buffer they came in
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("user.2".to_owned()).unwrap(), Some("bob".to_owned()));
+    let values: Vec<_> = changes(&mut store, 0)
+        .unwrap()
+        .into_iter()
+        .filter_map(|change| change.value)
+        .collect();
+    assert_eq!(values, vec!["before", "alice", "admins", "bob"]);
+}
+
+// `kvs-client wait` should block until the key changes or the timeout passes
+#[test]
+fn cli_wait() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--timeout", "1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("no change after version 0"));
+
+    let wait = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--timeout", "10", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "config", "v1", "--addr", addr])
+        .assert()
+        .success();
+    let output = wait.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 v1\n");
+
+    // a newer version is returned right away
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--since", "0", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("1 v1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--since", "1", "--timeout", "1", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--since", "5", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Version 5 is newer than the latest one, 1"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    // versions go on from where the store left them
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--since", "1", "--timeout", "1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("no change after version 1"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "config", "v2", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "config", "--since", "1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2 v2\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
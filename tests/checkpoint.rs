@@ -0,0 +1,73 @@
+use kvs::{EngineKind, EngineOptions, KvStore, KvStoreOptions, KvsEngine, Registry, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A checkpoint should hold the pairs of the moment it was taken
+// and be opened by the engine it was taken from
+#[test]
+fn checkpoint_every_engine() -> Result<()> {
+    let options = EngineOptions {
+        kvs: KvStoreOptions {
+            blob_threshold: Some(16),
+            ..KvStoreOptions::default()
+        },
+        snapshot_interval: Some(Duration::from_secs(3600)),
+        ..EngineOptions::default()
+    };
+
+    let registry = Registry::new();
+    for name in registry.names() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (dir, dest) = (temp_dir.path().join("store"), temp_dir.path().join("checkpoint"));
+        std::fs::create_dir(&dir)?;
+
+        let mut engine = registry.open(name, &dir, &options)?;
+        for key_id in 0..50 {
+            engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        engine.set("large".to_owned(), "a value kept in a blob file".to_owned())?;
+        engine.remove("key0".to_owned())?;
+        engine.checkpoint(&dest)?;
+
+        // changes after the checkpoint stay out of it
+        engine.set("key1".to_owned(), "changed".to_owned())?;
+        engine.set("key50".to_owned(), "value50".to_owned())?;
+        assert!(engine.checkpoint(&dest).is_err(), "{} overwrote a checkpoint", name);
+        drop(engine);
+
+        let mut engine = registry.open(name, &dest, &options)?;
+        assert_eq!(engine.get("key0".to_owned())?, None, "{}", name);
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()), "{}", name);
+        assert_eq!(engine.get("key50".to_owned())?, None, "{}", name);
+        assert_eq!(
+            engine.get("large".to_owned())?,
+            Some("a value kept in a blob file".to_owned()),
+            "{}",
+            name
+        );
+        assert_eq!(engine.pairs()?.count(), 50, "{}", name);
+    }
+
+    Ok(())
+}
+
+// A checkpoint of a KvStore should stay intact after the store compacts
+#[test]
+fn checkpoint_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (dir, dest) = (temp_dir.path().join("store"), temp_dir.path().join("checkpoint"));
+    std::fs::create_dir(&dir)?;
+
+    let mut store = KvStore::open(&dir)?;
+    store.set("key".to_owned(), "checkpointed".to_owned())?;
+    store.checkpoint(&dest)?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let mut store = Registry::new().open(EngineKind::Kvs.name(), &dest, &EngineOptions::default())?;
+    assert_eq!(store.get("key".to_owned())?, Some("checkpointed".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use assert_cmd::prelude::*;
+use kvs::KvsClient;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const MEMBERS: [&str; 3] = ["127.0.0.1:4013", "127.0.0.1:4014", "127.0.0.1:4015"];
+const JOINER: &str = "127.0.0.1:4016";
+
+fn client(args: &[&str], addr: &str) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .assert()
+}
+
+fn admin(args: &[&str]) -> String {
+    let output = Command::cargo_bin("kvs-admin").unwrap().args(args).output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn start(addr: &str, dir: &TempDir, cluster: &[&str]) -> Child {
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "kvs", "--addr", addr]).current_dir(dir);
+    match cluster.is_empty() {
+        true => cmd.arg("--join"),
+        false => cmd.args(["--cluster", &cluster.join(",")]),
+    };
+    cmd.spawn().unwrap()
+}
+
+/// Wait for one of `addrs` to tell it's the leader
+fn leader(addrs: &[&str]) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        for addr in addrs {
+            if admin(&["cluster", "--addr", addr]).contains(&format!("{} leader", addr)) {
+                return addr.to_string();
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("no leader was elected");
+}
+
+// Writes should survive the loss of the leader and reach a member added later
+#[test]
+fn cli_cluster() {
+    let dirs: Vec<TempDir> = (0..4).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Child> = MEMBERS.iter().zip(&dirs).map(|(addr, dir)| start(addr, dir, &MEMBERS)).collect();
+
+    let first = leader(&MEMBERS);
+    client(&["set", "key1", "value1"], &first).success().stdout("");
+    client(&["set", "key2", "value2"], &first).success().stdout("");
+    client(&["rm", "key2"], &first).success();
+    client(&["get", "key1"], &first).success().stdout("value1\n");
+    client(&["get", "key2"], &first).success().stdout("Key not found\n");
+
+    // followers point to the leader
+    let follower = MEMBERS.iter().find(|addr| **addr != first).unwrap();
+    client(&["get", "key1"], follower).success().stdout(format!("Not the leader: the leader is {}\n", first));
+    client(&["set", "key3", "value3"], follower).success().stdout(format!("Not the leader: the leader is {}\n", first));
+
+    let killed = MEMBERS.iter().position(|addr| *addr == first).unwrap();
+    servers[killed].kill().unwrap();
+    servers[killed].wait().unwrap();
+
+    let alive: Vec<&str> = MEMBERS.iter().cloned().filter(|addr| *addr != first).collect();
+    let second = leader(&alive);
+    client(&["get", "key1"], &second).success().stdout("value1\n");
+    client(&["set", "key3", "value3"], &second).success().stdout("");
+
+    let mut joiner = start(JOINER, &dirs[3], &[]);
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["add-member", JOINER, "--addr", &second])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    let status = admin(&["cluster", "--addr", JOINER]);
+    assert!(status.contains(&format!("leader {}", second)), "{}", status);
+    assert!(status.contains(JOINER), "{}", status);
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--addr", JOINER])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key3\",\"value\":\"value3\"}\n");
+
+    joiner.kill().unwrap();
+    joiner.wait().unwrap();
+    for server in &mut servers {
+        let _ = server.kill();
+    }
+}
+
+// A member added after the log was folded should get the snapshot in chunks
+#[test]
+fn cli_cluster_snapshot() {
+    let (leader_dir, joiner_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (first, joiner) = ("127.0.0.1:4032", "127.0.0.1:4033");
+    let mut server = start(first, &leader_dir, &[first]);
+    leader(&[first]);
+
+    // more entries than the log keeps and more bytes than a chunk holds
+    let client = KvsClient::new(first);
+    let value = "v".repeat(3000);
+    for i in 0..1010 {
+        client.set(format!("key{:04}", i), value.clone()).unwrap();
+    }
+
+    let mut joined = start(joiner, &joiner_dir, &[]);
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["add-member", joiner, "--addr", first])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    let pairs: Vec<(String, String)> = KvsClient::new(joiner).pairs().map(Result::unwrap).collect();
+    assert_eq!(pairs.len(), 1010);
+    assert!(pairs.iter().all(|(_, stored)| *stored == value));
+
+    joined.kill().unwrap();
+    joined.wait().unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
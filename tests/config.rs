@@ -0,0 +1,123 @@
+use assert_cmd::prelude::*;
+use kvs::{EngineKind, Metadata};
+use predicates::str::contains;
+use std::fs::{self, File};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start(args: &[&str], dir: &TempDir) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(args: &[&str], addr: &str) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .assert()
+}
+
+fn shutdown(server: &mut Child, addr: &str) {
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["shutdown", "--addr", addr])
+        .assert()
+        .success();
+    assert!(server.wait().unwrap().success());
+}
+
+// Settings should come from the file unless a flag overrides them
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config = temp_dir.path().join("kvs.toml");
+    let content = format!(
+        "addr = \"127.0.0.1:4025\"\nengine = \"lsm\"\ndata_dir = {:?}\n\n[lsm]\nmemtable_size = 1024\n\n[limits]\npool_size = 0\n",
+        data_dir.to_str().unwrap()
+    );
+    fs::write(&config, content).unwrap();
+    let config = config.to_str().unwrap();
+
+    let mut server = start(&["--config", config], &temp_dir);
+    client(&["set", "key1", "value1"], "127.0.0.1:4025").success();
+    // a watch would be held past the pool
+    client(&["wait", "key1", "--since", "1", "--timeout", "1"], "127.0.0.1:4025")
+        .failure()
+        .stderr(contains("waiting clients already"));
+    shutdown(&mut server, "127.0.0.1:4025");
+    assert_eq!(Metadata::load(&data_dir).unwrap().unwrap().engine, EngineKind::Lsm);
+
+    let mut server = start(&["--config", config, "--addr", "127.0.0.1:4026"], &temp_dir);
+    client(&["get", "key1"], "127.0.0.1:4026").success().stdout("value1\n");
+    shutdown(&mut server, "127.0.0.1:4026");
+}
+
+// A server without flags should listen at the default address and keep the engine of its directory
+#[test]
+fn cli_defaults() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let data_dir = data_dir.to_str().unwrap();
+
+    let mut server = start(&["--data-dir", data_dir, "--engine", "sled"], &temp_dir);
+    client(&["set", "key1", "value1"], "127.0.0.1:4000").success();
+    shutdown(&mut server, "127.0.0.1:4000");
+
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--data-dir", data_dir])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"], "127.0.0.1:4000").success().stdout("value1\n");
+    shutdown(&mut server, "127.0.0.1:4000");
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    assert!(content.contains("address=127.0.0.1:4000, engine=sled"), "{}", content);
+}
+
+// A file with an unknown setting should be refused
+#[test]
+fn cli_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "[limits]\npool = 16\n").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid configuration"));
+}
+
+// A directory the engine cannot open should be left without metadata
+#[test]
+fn cli_unopenable_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    fs::write(data_dir.join("0.sil"), b"not a generation").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--data-dir", data_dir.to_str().unwrap(), "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("cannot open"));
+    assert_eq!(Metadata::load(&data_dir).unwrap(), None);
+}
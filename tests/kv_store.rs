@@ -1,4 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionStep, Compression, EncryptionKey, IndexMode, KvStore, KvStoreOptions, KvsEngine,
+    Result, Versioned,
+};
+use std::sync::Arc;
+use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -123,4 +128,563 @@ fn compaction() -> Result<()> {
     }
 
     panic!("No compaction detected");
-}
\ No newline at end of file
+}
+
+// A crash at any step of compaction must leave the store
+// with exactly the data committed before it.
+#[test]
+fn compaction_crash_recovery() -> Result<()> {
+    let steps = [
+        CompactionStep::Write,
+        CompactionStep::Sync,
+        CompactionStep::Rename,
+        CompactionStep::Cleanup,
+    ];
+
+    for &step in &steps {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        // spread the data over several generations
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "value4".to_owned())?;
+
+        store.fail_compaction_at(step);
+        assert!(store.compact().is_err(), "no failure injected at {:?}", step);
+        drop(store);
+
+        let check = |store: &mut KvStore| -> Result<()> {
+            assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()), "{:?}", step);
+            assert_eq!(store.get("key2".to_owned())?, None, "{:?}", step);
+            assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()), "{:?}", step);
+            Ok(())
+        };
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        check(&mut store)?;
+
+        // the recovered store must be able to compact again
+        store.compact()?;
+        check(&mut store)?;
+        drop(store);
+        check(&mut KvStore::open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+// A store whose compaction failed at any step must keep serving
+// reads and writes, and keep them across a reopen.
+#[test]
+fn compaction_failure_keeps_store_usable() -> Result<()> {
+    let steps = [
+        CompactionStep::Write,
+        CompactionStep::Sync,
+        CompactionStep::Rename,
+        CompactionStep::Cleanup,
+    ];
+
+    for &step in &steps {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+
+        store.fail_compaction_at(step);
+        assert!(store.compact().is_err(), "no failure injected at {:?}", step);
+
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()), "{:?}", step);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()), "{:?}", step);
+        store.set("key1".to_owned(), "value4".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "value5".to_owned())?;
+
+        let check = |store: &mut KvStore| -> Result<()> {
+            assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()), "{:?}", step);
+            assert_eq!(store.get("key2".to_owned())?, None, "{:?}", step);
+            assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()), "{:?}", step);
+            Ok(())
+        };
+        check(&mut store)?;
+        drop(store);
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        check(&mut store)?;
+        store.compact()?;
+        check(&mut store)?;
+        drop(store);
+        check(&mut KvStore::open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+// Compressed and uncompressed records should coexist in one store
+// and survive recompression during compaction.
+#[test]
+fn mixed_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snappy = KvStoreOptions {
+        compression: Compression::Snappy,
+        ..KvStoreOptions::default()
+    };
+    let value = "{\"token\": \"value\"}".repeat(100);
+    let log_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.expect("cannot walk the directory"))
+            .filter(|entry| entry.path().extension() == Some("sil".as_ref()))
+            .map(|entry| entry.metadata().expect("cannot read metadata").len())
+            .sum()
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), value.clone())?;
+    drop(store);
+    assert!(log_size() > value.len() as u64);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), snappy.clone())?;
+    store.set("key2".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+    store.compact()?;
+    // both values are recompressed
+    assert!(log_size() < value.len() as u64 / 2, "{} bytes", log_size());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+    store.compact()?;
+    assert!(log_size() > 2 * value.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Generations written before records were framed should be upgraded on open,
+// and a record which doesn't decode should fail the open rather than end the log.
+#[test]
+fn legacy_and_corrupted_logs() -> Result<()> {
+    #[derive(serde::Serialize)]
+    enum LegacyCommand {
+        Remove { key: String },
+        Set { key: String, val: String },
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut legacy = Vec::new();
+    for command in &[
+        LegacyCommand::Set { key: "key1".to_owned(), val: "value1".to_owned() },
+        LegacyCommand::Set { key: "key2".to_owned(), val: "value2".to_owned() },
+        LegacyCommand::Remove { key: "key1".to_owned() },
+    ] {
+        legacy.extend(rmp_serde::encode::to_vec(command).expect("cannot encode a legacy command"));
+    }
+    std::fs::write(temp_dir.path().join("0.sil"), &legacy)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // flip a byte of the payload of the first record
+    let log = temp_dir.path().join("0.sil");
+    let mut content = std::fs::read(&log)?;
+    content[12 + 5 + 2] ^= 0xff;
+    std::fs::write(&log, &content)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Encrypted data shouldn't be readable without the key
+// and a rekey should make the old key unnecessary.
+#[test]
+fn encryption_and_rekey() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::new([1; 32]);
+    let new_key = EncryptionKey::from_hex(&"02".repeat(32))?;
+    let with_key = |key: &EncryptionKey| KvStoreOptions {
+        encryption: Some(key.clone()),
+        ..KvStoreOptions::default()
+    };
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), with_key(&old_key))?;
+    store.set("key1".to_owned(), "secret-token".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.expect("cannot walk the directory");
+        if entry.file_type().is_file() {
+            let content = std::fs::read(entry.path()).expect("cannot read a file");
+            assert!(!String::from_utf8_lossy(&content).contains("secret-token"));
+        }
+    }
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(KvStore::open_with_options(temp_dir.path(), with_key(&new_key)).is_err());
+
+    // sealed records are bound to the generation they were written to
+    let copy = temp_dir.path().join("9.sil");
+    std::fs::copy(temp_dir.path().join("0.sil"), &copy)?;
+    assert!(KvStore::open_with_options(temp_dir.path(), with_key(&old_key)).is_err());
+    std::fs::remove_file(&copy)?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), with_key(&old_key))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret-token".to_owned()));
+    store.rekey(Some(new_key.clone()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret-token".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), with_key(&new_key))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret-token".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Large values should live in blob files which compaction doesn't copy
+// and which are removed by garbage collection once they're dead.
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..KvStoreOptions::default()
+    };
+    let files = |extension: &str| -> Vec<u64> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.expect("cannot walk the directory"))
+            .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+            .map(|entry| entry.metadata().expect("cannot read metadata").len())
+            .collect()
+    };
+
+    let large = "x".repeat(64 * 1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("large1".to_owned(), large.clone())?;
+    store.set("large2".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.compact()?;
+
+    assert!(files("sil").iter().sum::<u64>() < 1024);
+    assert_eq!(store.get("large1".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    let mut streamed = Vec::new();
+    assert_eq!(store.get_to("large2", &mut streamed)?, Some(large.len() as u64));
+    assert_eq!(streamed, large.as_bytes());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("large2".to_owned())?, Some(large.clone()));
+    store.remove("large1".to_owned())?;
+    store.remove("large2".to_owned())?;
+    store.compact()?;
+    assert!(files("blob").is_empty());
+    assert_eq!(store.get("large1".to_owned())?, None);
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    // the id of the collected file isn't handed out again
+    store.set("large3".to_owned(), large.clone())?;
+    drop(store);
+    assert!(!temp_dir.path().join("0.blob").exists());
+    assert!(temp_dir.path().join("1.blob").exists());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("large3".to_owned())?, Some(large));
+
+    Ok(())
+}
+
+// Cached values should be invalidated by writes and stay correct
+// across compaction.
+#[test]
+fn read_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        cache_capacity: 64,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().cache_hits, 1);
+    assert_eq!(store.stats().cache_misses, 1);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats().cache_hits, 2);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // the cache never grows over its capacity
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        store.set(key.clone(), "value".to_owned())?;
+        assert_eq!(store.get(key)?, Some("value".to_owned()));
+    }
+    assert!(store.stats().cache_size <= 64);
+    assert_eq!(store.stats().keys, 100);
+
+    Ok(())
+}
+
+// Lookups need only a shared reference
+// so the store can be read from many threads at once.
+#[test]
+fn concurrent_lookups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("sealed{}", key_id))?;
+    }
+    drop(store);
+
+    // the first half is read from a sealed generation, the rest from the active one
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 50..100 {
+        store.set(format!("key{}", key_id), format!("active{}", key_id))?;
+    }
+
+    let store = Arc::new(store);
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    let expected = if key_id < 50 { "sealed" } else { "active" };
+                    assert_eq!(
+                        store.lookup(&format!("key{}", key_id))?,
+                        Some(format!("{}{}", expected, key_id))
+                    );
+                }
+                assert_eq!(store.lookup("key100")?, None);
+                Ok(())
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().expect("lookup thread panicked")?;
+    }
+
+    Ok(())
+}
+
+// Keeping only hashes of keys in memory should behave like the full index
+#[test]
+fn hashed_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index: IndexMode::Hashes,
+        ..KvStoreOptions::default()
+    };
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("key0".to_owned()).is_err());
+    assert_eq!(store.get("key100".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("19".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().keys, 50);
+    for key_id in 0..100 {
+        let expected = if key_id < 50 { None } else { Some("19".to_owned()) };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    let hashed = store.stats().index_memory;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(hashed < store.stats().index_memory);
+
+    Ok(())
+}
+
+// Bloom filters should be kept next to sealed generations and follow compactions
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index: IndexMode::Hashes,
+        bloom_fp_rate: Some(0.01),
+        ..KvStoreOptions::default()
+    };
+    let blooms = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("bloom".as_ref()))
+            .count()
+    };
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(blooms(), 0);
+    drop(store);
+
+    // the previous active generation is sealed now
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(blooms(), 1);
+    let stats = store.stats();
+    assert_eq!(stats.bloom_fp_rate, Some(0.01));
+    assert!(stats.bloom_memory > 0);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+
+    for iter in 0..100 {
+        store.set("key0".to_owned(), format!("{}", iter))?;
+    }
+    // compaction leaves only the filter of the compacted generation
+    assert_eq!(blooms(), 1);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("99".to_owned()));
+    assert_eq!(store.get("key10".to_owned())?, None);
+    store.remove("key9".to_owned())?;
+    assert!(store.remove("key9".to_owned()).is_err());
+
+    // lookups of absent keys are answered by the filters,
+    // the one of the active generation included
+    store.set("key10".to_owned(), "value".to_owned())?;
+    for key_id in 11..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert!(store.stats().bloom_negatives > 0);
+    assert_eq!(store.get("key10".to_owned())?, Some("value".to_owned()));
+
+    // a filter without bits is damaged and gets rebuilt
+    drop(store);
+    let empty = rmp_serde::encode::to_vec(&(0.01f64, 3u32, Vec::<u64>::new())).expect("cannot encode a filter");
+    for entry in WalkDir::new(temp_dir.path()).into_iter().filter_map(|entry| entry.ok()) {
+        if entry.path().extension() == Some("bloom".as_ref()) {
+            std::fs::write(entry.path(), &empty)?;
+        }
+    }
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key10".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key11".to_owned())?, None);
+
+    // a generation sealed by a checkpoint gets its filter
+    let checkpoint = TempDir::new().expect("unable to create temporary working directory");
+    let sealed = blooms();
+    store.checkpoint(&checkpoint.path().join("checkpoint"))?;
+    assert_eq!(blooms(), sealed + 1);
+    assert_eq!(store.get("key10".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Every write should get a newer version, also after a compaction and a reopen
+#[test]
+fn versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get_with_version("key1")?, Some(("value3".to_owned(), 3)));
+    assert_eq!(store.get_with_version("key2")?, Some(("value2".to_owned(), 2)));
+    assert_eq!(store.get_with_version("key3")?, None);
+
+    // the version of a remove is taken even though compaction drops the record
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.version()?, 4);
+    assert_eq!(store.get_with_version("key2")?, Some(("value2".to_owned(), 2)));
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get_with_version("key1")?, Some(("value5".to_owned(), 5)));
+    assert_eq!(
+        store.history("key1")?,
+        vec![Versioned { version: 5, value: Some("value5".to_owned()) }]
+    );
+
+    Ok(())
+}
+
+// The history should keep the latest versions of a key through compactions
+#[test]
+fn history_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        history: 3,
+        blob_threshold: Some(64),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let large = "x".repeat(100);
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), large.clone())?;
+    store.set("key".to_owned(), "value3".to_owned())?;
+    store.remove("key".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let expected = vec![
+        Versioned { version: 2, value: Some(large.clone()) },
+        Versioned { version: 3, value: Some("value3".to_owned()) },
+        Versioned { version: 4, value: None },
+    ];
+    assert_eq!(store.history("key")?, expected);
+
+    store.compact()?;
+    assert_eq!(store.history("key")?, expected);
+    assert_eq!(store.get("key".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.history("key")?, expected);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get_with_version("other")?, Some(("value".to_owned(), 5)));
+
+    // overwrites fall out of the history and become garbage
+    for i in 0..2000 {
+        store.set("other".to_owned(), format!("{}", i))?;
+    }
+    let history = store.history("other")?;
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[2], Versioned { version: 2005, value: Some("1999".to_owned()) });
+    assert_eq!(store.history("key")?, expected);
+
+    Ok(())
+}
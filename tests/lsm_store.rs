@@ -0,0 +1,188 @@
+use kvs::{KvsEngine, LsmOptions, LsmStore, Result};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// small limits make every test go through flushes and compactions
+fn options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 256,
+        level0_tables: 3,
+        table_size: 1024,
+        level1_size: 2048,
+    }
+}
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should remove keys and report removing a missing one
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    drop(store);
+    let mut store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Values should survive flushes and compactions
+// and removed keys should not come back
+#[test]
+fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open_with_options(temp_dir.path(), options())?;
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{:03}", key_id), format!("{}-{}", key_id, iter))?;
+        }
+    }
+    for key_id in (0..100).step_by(2) {
+        store.remove(format!("key{:03}", key_id))?;
+    }
+
+    let tables = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    assert!(tables() > 0);
+    assert!(tables() < 20);
+
+    for _ in 0..2 {
+        for key_id in 0..100 {
+            let expected = if key_id % 2 == 0 { None } else { Some(format!("{}-19", key_id)) };
+            assert_eq!(store.get(format!("key{:03}", key_id))?, expected);
+        }
+
+        drop(store);
+        store = LsmStore::open_with_options(temp_dir.path(), options())?;
+    }
+
+    Ok(())
+}
+
+// Scans should merge the memtable with every level
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open_with_options(temp_dir.path(), options())?;
+
+    for key_id in 0..50 {
+        store.set(format!("key{:02}", key_id), "old".to_owned())?;
+    }
+    store.compact()?;
+    for key_id in 10..20 {
+        store.set(format!("key{:02}", key_id), "new".to_owned())?;
+    }
+    store.flush()?;
+    store.remove("key15".to_owned())?;
+    store.set("key16".to_owned(), "newest".to_owned())?;
+
+    let pairs = store.scan("key12".."key18")?;
+    let expected: Vec<(String, String)> = vec![
+        ("key12", "new"),
+        ("key13", "new"),
+        ("key14", "new"),
+        ("key16", "newest"),
+        ("key17", "new"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+    .collect();
+    assert_eq!(pairs, expected);
+
+    assert_eq!(store.scan(..)?.len(), 49);
+    assert_eq!(store.scan("key45"..)?.len(), 5);
+    assert_eq!(store.scan(..="key00")?, vec![("key00".to_owned(), "old".to_owned())]);
+    assert!(store.scan("key18".."key12")?.is_empty());
+    assert!(store.scan("key12".."key12")?.is_empty());
+
+    Ok(())
+}
+
+// Compactions should push tables down through the levels
+// and removed keys should not come back from deeper ones
+#[test]
+fn leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open_with_options(temp_dir.path(), options())?;
+
+    for key_id in 0..2000 {
+        store.set(format!("key{:04}", key_id), format!("value-{}", key_id))?;
+    }
+    for key_id in (0..2000).step_by(3) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    for key_id in (0..2000).step_by(6) {
+        store.set(format!("key{:04}", key_id), "again".to_owned())?;
+    }
+    store.compact()?;
+
+    for _ in 0..2 {
+        for key_id in 0..2000 {
+            let expected = match key_id {
+                k if k % 6 == 0 => Some("again".to_owned()),
+                k if k % 3 == 0 => None,
+                k => Some(format!("value-{}", k)),
+            };
+            assert_eq!(store.get(format!("key{:04}", key_id))?, expected);
+        }
+        assert_eq!(store.scan(..)?.len(), 2000 - 333);
+
+        drop(store);
+        store = LsmStore::open_with_options(temp_dir.path(), options())?;
+    }
+
+    Ok(())
+}
+
+// A table whose footer points out of the file should fail to open
+#[test]
+fn corrupted_table() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    let table = temp_dir.path().join("0.sst");
+    let mut content = std::fs::read(&table)?;
+    let footer = content.len() - 12;
+    content[footer..footer + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+    std::fs::write(&table, content)?;
+
+    assert!(LsmStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
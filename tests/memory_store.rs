@@ -0,0 +1,81 @@
+use kvs::{KvStore, KvsEngine, MemoryStore, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should get previously stored value and forget removed ones
+#[test]
+fn get_set_remove() -> Result<()> {
+    let mut store = MemoryStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    assert_eq!(store.scan(..)?, vec![("key2".to_owned(), "value2".to_owned())]);
+
+    Ok(())
+}
+
+// A snapshot should warm start a new store and be readable by KvStore
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_secs(3600);
+
+    let mut store = MemoryStore::with_snapshots(temp_dir.path(), interval)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.snapshot()?;
+    store.remove("key0".to_owned())?;
+    // the last changes are written when the store is dropped
+    drop(store);
+
+    let mut store = MemoryStore::with_snapshots(temp_dir.path(), interval)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.scan("key1".."key2")?.len(), 11);
+    store.snapshot()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(store.stats().keys, 99);
+
+    Ok(())
+}
+
+// Sync should take a snapshot of the last writes and a warm start should write nothing
+#[test]
+fn sync_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_secs(3600);
+    let files = || -> Vec<_> {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+
+    let mut store = MemoryStore::with_snapshots(temp_dir.path(), interval)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sync()?;
+    let synced = files();
+
+    // the store is still open, the snapshot was taken by the sync
+    let warm = MemoryStore::with_snapshots(temp_dir.path(), interval)?;
+    assert_eq!(warm.scan(..)?, vec![("key1".to_owned(), "value1".to_owned())]);
+    drop(warm);
+    assert_eq!(files(), synced);
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    migrate, EngineKind, KvStore, KvsEngine, LsmStore, MemoryStore, Metadata, Result, SledStorage,
+};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+// Every live pair should be moved and nothing else
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (kvs_dir, lsm_dir) = (temp_dir.path().join("kvs"), temp_dir.path().join("lsm"));
+    std::fs::create_dir(&kvs_dir)?;
+    std::fs::create_dir(&lsm_dir)?;
+    let mut kvs = KvStore::open(kvs_dir)?;
+    for key_id in 0..100 {
+        kvs.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    kvs.remove("key0".to_owned())?;
+    kvs.set("key1".to_owned(), "changed".to_owned())?;
+
+    let mut lsm = LsmStore::open(lsm_dir)?;
+    let report = migrate(&mut kvs, &mut lsm)?;
+    assert_eq!(report.pairs, 99);
+    assert_eq!(lsm.get("key0".to_owned())?, None);
+    assert_eq!(lsm.get("key1".to_owned())?, Some("changed".to_owned()));
+
+    // the checksum doesn't depend on the order pairs come in
+    let mut memory = MemoryStore::new();
+    assert_eq!(migrate(&mut lsm, &mut memory)?, report);
+
+    // a destination which isn't empty fails the verification
+    let mut other = MemoryStore::new();
+    other.set("extra".to_owned(), "value".to_owned())?;
+    assert!(migrate(&mut memory, &mut other).is_err());
+
+    Ok(())
+}
+
+// kvs-admin migrate should move a kvs directory to sled and pin the new one
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (src, dst) = (temp_dir.path().join("src"), temp_dir.path().join("dst"));
+    std::fs::create_dir(&src)?;
+    {
+        let mut store = KvStore::open(&src)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+    }
+    let files = |dir: &std::path::Path| -> Vec<_> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let src_files = files(&src);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .args([&src, &dst])
+        .assert()
+        .success()
+        .stdout(contains("migrated 2 pairs"));
+
+    assert_eq!(Metadata::load(&dst)?.map(|meta| meta.engine), Some(EngineKind::Sled));
+    // the source was read from a copy, which is gone, only its lock was added
+    let mut locked = src_files.clone();
+    locked.push("LOCK".into());
+    assert_eq!(files(&src), locked);
+    assert!(!temp_dir.path().join("dst.source").exists());
+    let mut store = SledStorage::open(&dst)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // the destination holds a store now
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .args([&src, &dst])
+        .assert()
+        .failure();
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, Ring};
+use predicates::str::contains;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const BACKENDS: [&str; 2] = ["127.0.0.1:4020", "127.0.0.1:4021"];
+const PROXY: &str = "127.0.0.1:4022";
+
+fn client(args: &[&str], addr: &str) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .assert()
+}
+
+/// Pairs kept at `addr`
+fn pairs(addr: &str) -> Vec<(String, String)> {
+    KvsClient::new(addr).pairs().map(Result::unwrap).collect()
+}
+
+// The proxy should spread keys over the backends, merge scans and eject a failed backend
+#[test]
+fn cli_proxy() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let mut backends: Vec<Child> = BACKENDS
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--engine", "kvs", "--addr", addr])
+                .current_dir(dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let mut proxy = Command::cargo_bin("kvs-proxy")
+        .unwrap()
+        .args(["--addr", PROXY, "--backends", &BACKENDS.join(","), "--health-interval", "100"])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..20 {
+        client(&["set", &format!("key{:02}", i), &format!("value{}", i)], PROXY).success().stdout("");
+    }
+    client(&["get", "key07"], PROXY).success().stdout("value7\n");
+    client(&["rm", "key00"], PROXY).success();
+    client(&["rm", "key00"], PROXY).failure().stderr(contains("Key not found"));
+    client(&["get", "key00"], PROXY).success().stdout("Key not found\n");
+
+    // a scan of the proxy holds the pairs of every backend in order
+    let ring = Ring::new(BACKENDS.to_vec());
+    let expected: Vec<(String, String)> = (1..20).map(|i| (format!("key{:02}", i), format!("value{}", i))).collect();
+    assert_eq!(pairs(PROXY), expected);
+    for addr in &BACKENDS {
+        let kept = pairs(addr);
+        assert!(!kept.is_empty());
+        assert!(kept.iter().all(|(key, _)| ring.node(key) == Some(*addr)));
+    }
+
+    // a value longer than a single read of the socket goes through whole
+    let long = "v".repeat(100_000);
+    let proxied = KvsClient::new(PROXY);
+    proxied.set("long".to_owned(), long.clone()).unwrap();
+    assert_eq!(proxied.get("long".to_owned()).unwrap(), Some(long));
+    proxied.remove("long".to_owned()).unwrap();
+
+    backends[1].kill().expect("server exited before killed");
+    backends[1].wait().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let (lost, kept): (Vec<String>, Vec<String>) =
+        (1..20).map(|i| format!("key{:02}", i)).partition(|key| ring.node(key) == Some(BACKENDS[1]));
+    client(&["get", &lost[0]], PROXY).success().stdout(contains(format!("backend {} is down", BACKENDS[1])));
+    let value = format!("value{}\n", kept[0][3..].parse::<u32>().unwrap());
+    client(&["get", &kept[0]], PROXY).success().stdout(value);
+
+    proxy.kill().expect("proxy exited before killed");
+    proxy.wait().unwrap();
+    backends[0].kill().expect("server exited before killed");
+    backends[0].wait().unwrap();
+}
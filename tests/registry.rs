@@ -0,0 +1,101 @@
+use kvs::{
+    migrate_dir, EngineKind, EngineOptions, KvStore, KvStoreOptions, KvsError, Metadata, Registry,
+    Result, FORMAT_VERSION,
+};
+use tempfile::TempDir;
+
+// Every engine shipped with the library should be opened by its name
+#[test]
+fn open_registered_engines() -> Result<()> {
+    let registry = Registry::new();
+    assert_eq!(registry.names().collect::<Vec<_>>(), vec!["kvs", "lsm", "memory", "sled"]);
+
+    for name in registry.names() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let kind: EngineKind = name.parse().expect("unknown engine kind");
+        assert_eq!(kind.name(), name);
+
+        let mut engine = registry.open(name, temp_dir.path(), &EngineOptions::default())?;
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        engine.remove("key1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, None);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    match registry.open("rocks", temp_dir.path(), &EngineOptions::default()) {
+        Err(KvsError::UnknownEngine(name)) => assert_eq!(name, "rocks"),
+        _ => panic!("an unknown engine was opened"),
+    }
+    assert!("rocks".parse::<EngineKind>().is_err());
+
+    Ok(())
+}
+
+// A directory should stay pinned to the engine it was created with
+#[test]
+fn pin_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(Metadata::load(temp_dir.path())?, None);
+
+    let meta = Metadata::pin(temp_dir.path(), EngineKind::Lsm)?;
+    assert_eq!(meta.engine, EngineKind::Lsm);
+    assert_eq!(meta.format_version, FORMAT_VERSION);
+    assert_eq!(Metadata::pin(temp_dir.path(), EngineKind::Lsm)?, meta);
+    assert!(Metadata::pin(temp_dir.path(), EngineKind::Kvs).is_err());
+
+    // a directory written by a newer release is refused
+    let mut newer = meta.clone();
+    newer.format_version = FORMAT_VERSION + 1;
+    newer.save(temp_dir.path())?;
+    assert!(Metadata::pin(temp_dir.path(), EngineKind::Lsm).is_err());
+
+    Ok(())
+}
+
+// The marker written by older servers should be replaced by metadata
+#[test]
+fn legacy_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("engine"), "sled")?;
+
+    assert!(Metadata::pin(temp_dir.path(), EngineKind::Kvs).is_err());
+    assert_eq!(Metadata::load(temp_dir.path())?.map(|meta| meta.format_version), Some(0));
+    // checking writes nothing, the marker is upgraded once the engine opened the directory
+    assert_eq!(Metadata::check(temp_dir.path(), EngineKind::Sled)?.format_version, FORMAT_VERSION);
+    assert!(temp_dir.path().join("engine").exists());
+    let meta = Metadata::pin(temp_dir.path(), EngineKind::Sled)?;
+    assert_eq!(meta.format_version, FORMAT_VERSION);
+    assert_eq!(meta.engine, EngineKind::Sled);
+    assert!(!temp_dir.path().join("engine").exists());
+    assert_eq!(Metadata::load(temp_dir.path())?, Some(meta));
+
+    Ok(())
+}
+
+// Every opener of a data directory should be refused while another one holds it
+#[test]
+fn open_dir_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let registry = Registry::new();
+    let options = EngineOptions::default();
+
+    let (mut engine, meta, lock) = registry.open_dir(EngineKind::Kvs, temp_dir.path(), &options)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(Metadata::load(temp_dir.path())?, Some(meta));
+
+    match registry.open_dir(EngineKind::Kvs, temp_dir.path(), &options) {
+        Err(KvsError::Locked(_)) => (),
+        _ => panic!("a locked directory was opened"),
+    }
+    assert!(KvStore::repair(temp_dir.path(), &KvStoreOptions::default()).is_err());
+    assert!(migrate_dir(EngineKind::Kvs, temp_dir.path(), EngineKind::Sled, dst_dir.path(), &options).is_err());
+
+    drop(engine);
+    drop(lock);
+    let (mut engine, _, _lock) = registry.open_dir(EngineKind::Kvs, temp_dir.path(), &options)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
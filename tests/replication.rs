@@ -0,0 +1,151 @@
+use assert_cmd::prelude::*;
+use kvs::{construct_package, reply, ErrorKind, KvStore, KvsEngine, KvsError, Package, Subscription};
+use predicates::str::contains;
+use std::io::Write;
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn client(args: &[&str], addr: &str) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .assert()
+}
+
+// A follower should copy the leader, tail its changes and refuse writes
+#[test]
+fn cli_replica_of() {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (leader, follower) = ("127.0.0.1:4011", "127.0.0.1:4012");
+    let mut leader_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", leader])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    client(&["set", "key1", "value1"], leader).success();
+    client(&["set", "key2", "value2"], leader).success();
+
+    // the follower had a key the leader doesn't
+    let mut follower_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", follower])
+        .current_dir(&follower_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "stale", "value"], follower).success();
+    follower_server.kill().expect("server exited before killed");
+    follower_server.wait().unwrap();
+
+    let mut follower_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", follower, "--replica-of", leader])
+        .current_dir(&follower_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(2));
+
+    client(&["get", "key1"], follower).success().stdout("value1\n");
+    client(&["get", "stale"], follower).success().stdout("Key not found\n");
+
+    client(&["set", "key1", "value3"], leader).success();
+    client(&["rm", "key2"], leader).success();
+    thread::sleep(Duration::from_millis(500));
+    client(&["get", "key1"], follower).success().stdout("value3\n");
+    client(&["get", "key2"], follower).success().stdout("Key not found\n");
+
+    client(&["set", "key3", "value"], follower).success().stdout(contains("read-only replica of"));
+    client(&["get", "key3"], leader).success().stdout("Key not found\n");
+
+    // both sides report how far behind the follower is
+    thread::sleep(Duration::from_secs(2));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["status", "--addr", follower])
+        .assert()
+        .success()
+        .stdout(contains(format!("follower of {}", leader)));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["status", "--addr", leader])
+        .assert()
+        .success()
+        .stdout(contains(format!("follower {} applied", follower)));
+
+    follower_server.kill().expect("server exited before killed");
+    follower_server.wait().unwrap();
+    leader_server.kill().expect("server exited before killed");
+    leader_server.wait().unwrap();
+}
+
+// A follower of a leader which keeps no change feed shouldn't start
+#[test]
+fn cli_replica_of_without_feed() {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (leader, follower) = ("127.0.0.1:4029", "127.0.0.1:4030");
+    let mut leader_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", leader])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", follower, "--replica-of", leader])
+        .current_dir(&follower_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("leader {} cannot be followed", leader)));
+
+    leader_server.kill().expect("server exited before killed");
+    leader_server.wait().unwrap();
+}
+
+// A follower which missed a remove the leader compacted away
+// should be told to copy the leader again
+#[test]
+fn cli_replica_behind_compaction() {
+    let leader_dir = TempDir::new().unwrap();
+    let leader = "127.0.0.1:4031";
+    let mut store = KvStore::open(leader_dir.path()).unwrap();
+    store.set("removed".to_owned(), "value".to_owned()).unwrap();
+    let before_remove = store.seq().unwrap();
+    store.remove("removed".to_owned()).unwrap();
+    for iter in 0..2000 {
+        store.set("key".to_owned(), format!("{}", iter)).unwrap();
+    }
+    drop(store);
+
+    let mut leader_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", leader])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut socket = TcpStream::connect(leader).unwrap();
+    let subscription = Subscription {
+        prefix: String::new(),
+        since: Some(before_remove),
+    };
+    socket
+        .write_all(&construct_package(Package::Subscribe(&subscription.encode().unwrap())))
+        .unwrap();
+    match reply(&mut socket) {
+        Err(KvsError::Remote(ErrorKind::ResyncRequired, _)) => (),
+        res => panic!("a follower behind a compaction wasn't asked to resync: {:?}", res),
+    }
+
+    leader_server.kill().expect("server exited before killed");
+    leader_server.wait().unwrap();
+}
@@ -0,0 +1,96 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, KvsError, Result, Ring, ShardedClient};
+use std::collections::HashMap;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const SERVERS: [&str; 3] = ["127.0.0.1:4017", "127.0.0.1:4018", "127.0.0.1:4019"];
+
+fn start(addr: &str, dir: &TempDir) -> Child {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap()
+}
+
+// Adding a node should move only the keys it takes over
+#[test]
+fn ring_moves_few_keys() {
+    let mut ring = Ring::new(vec!["a", "b", "c"]);
+    let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+    let before: HashMap<&str, String> = keys.iter().map(|key| (key.as_str(), ring.node(key).unwrap().to_owned())).collect();
+
+    let mut counts = HashMap::new();
+    for node in before.values() {
+        *counts.entry(node.clone()).or_insert(0) += 1;
+    }
+    for node in &["a", "b", "c"] {
+        assert!(counts[*node] > 600, "{:?}", counts);
+    }
+
+    ring.add("d".to_owned());
+    assert_eq!(ring.nodes(), vec!["a", "b", "c", "d"]);
+    let mut moved = 0;
+    for key in &keys {
+        let node = ring.node(key).unwrap();
+        if node != before[key.as_str()] {
+            assert_eq!(node, "d");
+            moved += 1;
+        }
+    }
+    assert!(moved > 450 && moved < 1200, "{} keys moved", moved);
+
+    // the same nodes map keys the same way
+    ring.remove("d");
+    assert!(keys.iter().all(|key| ring.node(key).unwrap() == before[key.as_str()]));
+    assert_eq!(Ring::new(Vec::<String>::new()).node("key"), None);
+}
+
+// Keys should be spread over the servers and survive adding one
+#[test]
+fn sharded_client() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Child> = SERVERS.iter().zip(&dirs).map(|(addr, dir)| start(addr, dir)).collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = ShardedClient::new(vec![SERVERS[0], SERVERS[1]]);
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert!(matches!(client.remove("key0".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key0".to_owned())?, None);
+    for addr in &SERVERS[..2] {
+        assert!(KvsClient::new(*addr).pairs().count() > 20);
+    }
+
+    let moved = client.add_node(SERVERS[2].to_owned())?;
+    let kept: u64 = SERVERS
+        .iter()
+        .map(|addr| KvsClient::new(*addr).pairs().count() as u64)
+        .sum();
+    assert_eq!(kept, 99);
+    assert_eq!(KvsClient::new(SERVERS[2]).pairs().count() as u64, moved);
+    assert!(moved > 10, "{} keys moved", moved);
+
+    // every key is kept only by the server it's routed to
+    for i in 1..100 {
+        let key = format!("key{}", i);
+        assert_eq!(client.get(key.clone())?, Some(format!("value{}", i)));
+        let owner = client.ring().node(&key).unwrap();
+        for addr in SERVERS.iter().filter(|addr| **addr != owner) {
+            assert_eq!(KvsClient::new(*addr).get(key.clone())?, None);
+        }
+    }
+
+    for server in &mut servers {
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start(args: &[&str], dir: &TempDir) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(args: &[&str], addr: &str) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .assert()
+}
+
+/// Wait for `child` to exit on its own
+fn exited(child: &mut Child) -> ExitStatus {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    child.kill().unwrap();
+    panic!("the server didn't stop");
+}
+
+// A shutdown command should stop the server cleanly and let another one take the directory
+#[test]
+fn cli_shutdown() {
+    let addr = "127.0.0.1:4023";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = start(&["--engine", "kvs", "--addr", addr], &temp_dir);
+
+    client(&["set", "key1", "value1"], addr).success();
+    // the directory belongs to the running server
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4024"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Cannot lock"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["shutdown", "--addr", addr])
+        .assert()
+        .success();
+    assert!(exited(&mut server).success());
+    client(&["get", "key1"], addr).failure();
+
+    let mut server = start(&["--engine", "kvs", "--addr", addr], &temp_dir);
+    client(&["get", "key1"], addr).success().stdout("value1\n");
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// SIGTERM should stop the server after writing the store through to disk
+#[cfg(unix)]
+#[test]
+fn cli_sigterm() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    // the memory engine keeps its pairs on disk only once it takes a snapshot
+    let args = ["--engine", "memory", "--snapshot-interval", "3600", "--addr", addr];
+    let mut server = start(&args, &temp_dir);
+    client(&["set", "key1", "value1"], addr).success();
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .assert()
+        .success();
+    assert!(exited(&mut server).success());
+
+    let mut server = start(&args, &temp_dir);
+    client(&["get", "key1"], addr).success().stdout("value1\n");
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
@@ -0,0 +1,241 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    export, import, EncryptionKey, EngineOptions, Format, IndexMode, KvStore, KvStoreOptions,
+    KvsEngine, MemoryStore, Registry, Result,
+};
+use predicates::str::contains;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn awkward_pairs() -> Vec<(String, String)> {
+    vec![
+        ("plain".to_owned(), "value".to_owned()),
+        ("comma,key".to_owned(), "\"quoted\", value".to_owned()),
+        ("lines".to_owned(), "first\nsecond\r\n".to_owned()),
+        ("binary".to_owned(), "\u{0}\u{1}\u{7f}".to_owned()),
+        ("base64:prefixed".to_owned(), "base64:AAAA".to_owned()),
+        ("unicode".to_owned(), "значение ✓".to_owned()),
+        ("".to_owned(), "".to_owned()),
+    ]
+}
+
+// Every pair should come back as it was exported in both formats
+#[test]
+fn round_trip() -> Result<()> {
+    for &format in &[Format::Jsonl, Format::Csv] {
+        let pairs = awkward_pairs();
+        let mut buffer = Vec::new();
+        let count = export(pairs.clone().into_iter().map(Ok), &mut buffer, format)?;
+        assert_eq!(count, pairs.len() as u64);
+
+        let imported = import(&buffer[..], format).collect::<Result<Vec<_>>>()?;
+        assert_eq!(imported, pairs, "{:?}", format);
+    }
+
+    // a value with control characters takes a single line of CSV
+    let mut buffer = Vec::new();
+    export(vec![Ok(("key".to_owned(), "a\nb".to_owned()))], &mut buffer, Format::Csv)?;
+    assert_eq!(String::from_utf8(buffer)?, "key,value\nkey,base64:YQpi\n");
+
+    Ok(())
+}
+
+// Malformed input should fail the import rather than be skipped
+#[test]
+fn malformed_input() {
+    assert!(import(&b"{\"key\": \"k\"}\n"[..], Format::Jsonl).any(|pair| pair.is_err()));
+    assert!(import(&b"key,value\nk,v,extra\n"[..], Format::Csv).any(|pair| pair.is_err()));
+    assert!(import(&b"key,value\nk,base64:!!\n"[..], Format::Csv).any(|pair| pair.is_err()));
+}
+
+// Pages should cover every live pair in ascending order of keys
+#[test]
+fn pages() -> Result<()> {
+    let registry = Registry::new();
+    for name in registry.names() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = registry.open(name, temp_dir.path(), &EngineOptions::default())?;
+        for key_id in 0..100 {
+            engine.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+        }
+        engine.remove("key050".to_owned())?;
+
+        let mut keys = Vec::new();
+        let mut after = None;
+        loop {
+            let page = engine.page(after.as_deref(), 7)?;
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 7, "{}", name);
+            after = page.last().map(|(key, _)| key.clone());
+            keys.extend(page.into_iter().map(|(key, _)| key));
+        }
+
+        let expected = (0..100)
+            .filter(|&key_id| key_id != 50)
+            .map(|key_id| format!("key{:03}", key_id))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected, "{}", name);
+    }
+
+    Ok(())
+}
+
+// A KvStore should page the same with either index, values included
+#[test]
+fn pages_of_kv_store() -> Result<()> {
+    for index in [IndexMode::Keys, IndexMode::Hashes] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            index,
+            blob_threshold: Some(16),
+            ..KvStoreOptions::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..20 {
+            store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("key03".to_owned(), "a value kept in a blob file".to_owned())?;
+        store.remove("key04".to_owned())?;
+
+        let page = store.page(Some("key02"), 3)?;
+        let expected = vec![
+            ("key03".to_owned(), "a value kept in a blob file".to_owned()),
+            ("key05".to_owned(), "value5".to_owned()),
+            ("key06".to_owned(), "value6".to_owned()),
+        ];
+        assert_eq!(page, expected, "{:?}", index);
+        assert!(store.page(Some("key19"), 3)?.is_empty());
+    }
+
+    Ok(())
+}
+
+// kvs-admin should export a running server and import the file into a directory
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (server_dir, dst) = (temp_dir.path().join("server"), temp_dir.path().join("dst"));
+    let file = temp_dir.path().join("pairs.csv");
+    std::fs::create_dir(&server_dir)?;
+    {
+        let mut store = KvStore::open(&server_dir)?;
+        for key_id in 0..300 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("binary".to_owned(), "\u{0}\u{2}".to_owned())?;
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let addr = "127.0.0.1:4008";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&server_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--format", "csv", "--addr", addr, "--output"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stderr(contains("exported 301 pairs"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // the directory has no metadata yet, so the engine is required
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["import", "--format", "csv", "--dir"])
+        .arg(&dst)
+        .arg("--input")
+        .arg(&file)
+        .assert()
+        .failure()
+        .stderr(contains("--engine"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["import", "--format", "csv", "--engine", "lsm", "--dir"])
+        .arg(&dst)
+        .arg("--input")
+        .arg(&file)
+        .assert()
+        .success()
+        .stderr(contains("imported 301 pairs"));
+
+    // the directory is pinned to lsm now and exports to standard output
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--dir"])
+        .arg(&dst)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"binary","value":"\u0000\u0002"}"#))
+        .stderr(contains("exported 301 pairs"));
+
+    Ok(())
+}
+
+// kvs-admin should open a directory the way its server does,
+// with its key and from the snapshot of the memory engine
+#[test]
+fn cli_export_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (sealed, memory) = (temp_dir.path().join("sealed"), temp_dir.path().join("memory"));
+    let key_file = temp_dir.path().join("key");
+    std::fs::write(&key_file, "07".repeat(32))?;
+    std::fs::create_dir(&sealed)?;
+    {
+        let options = KvStoreOptions {
+            encryption: Some(EncryptionKey::from_file(&key_file)?),
+            ..KvStoreOptions::default()
+        };
+        let mut store = KvStore::open_with_options(&sealed, options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--engine", "kvs", "--dir"])
+        .arg(&sealed)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--engine", "kvs", "--dir"])
+        .arg(&sealed)
+        .arg("--key-file")
+        .arg(&key_file)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key1","value":"value1"}"#));
+
+    {
+        let mut store = MemoryStore::with_snapshots(&memory, Duration::from_secs(3600))?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+    }
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--engine", "memory", "--dir"])
+        .arg(&memory)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key2","value":"value2"}"#))
+        .stderr(contains("exported 1 pairs"));
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use assert_cmd::prelude::*;
+use kvs::{DirLock, KvStore, KvStoreOptions, KvsEngine, LogEntry, LogFile, Result};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn fill(path: &Path, options: KvStoreOptions) -> Result<()> {
+    let mut store = KvStore::open_with_options(path, options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("key10".to_owned(), "changed".to_owned())?;
+    Ok(())
+}
+
+fn overwrite(file: &Path, pos: u64, bytes: &[u8]) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(file)?;
+    file.seek(SeekFrom::Start(pos))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+// Verify should count live and dead records of a healthy store
+#[test]
+fn verify_healthy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path(), KvStoreOptions::default())?;
+
+    let report = KvStore::verify(temp_dir.path(), &KvStoreOptions::default())?;
+    assert!(report.is_healthy());
+    assert_eq!(report.keys, 90);
+    assert_eq!(report.generations[0].records, 111);
+    assert!(report.dead_bytes > 0);
+    assert!(report.dead_ratio() > 0.0 && report.dead_ratio() < 0.5);
+
+    Ok(())
+}
+
+// Records past a corrupted range should be found and salvaged by repair
+#[test]
+fn repair_corrupted_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path(), KvStoreOptions::default())?;
+
+    let file = temp_dir.path().join("0.sil");
+    let size = std::fs::metadata(&file)?.len();
+    overwrite(&file, size / 2, &[0xff; 20])?;
+
+    let report = KvStore::verify(temp_dir.path(), &KvStoreOptions::default())?;
+    assert_eq!(report.corrupted.len(), 1);
+    assert_eq!(report.corrupted[0].file, file);
+    assert!(report.corrupted[0].range.contains(&(size / 2)));
+    assert!(report.keys < 90 && report.keys > 80);
+
+    // open refuses the corruption rather than miss the writes past it
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let repaired = KvStore::repair(temp_dir.path(), &KvStoreOptions::default())?;
+    assert_eq!(repaired.keys, report.keys);
+    assert!(repaired.moved_to.join("0.sil").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key10".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+
+    assert!(KvStore::verify(temp_dir.path(), &KvStoreOptions::default())?.is_healthy());
+
+    Ok(())
+}
+
+// A value kept in a corrupted blob should be reported lost
+#[test]
+fn repair_corrupted_blob() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(16),
+        ..KvStoreOptions::default()
+    };
+    {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set("large".to_owned(), "a value kept in a blob file".to_owned())?;
+    }
+    overwrite(&temp_dir.path().join("0.blob"), 12, &[0xff])?;
+
+    let report = KvStore::verify(temp_dir.path(), &options)?;
+    assert_eq!(report.lost, vec!["large".to_owned()]);
+    assert_eq!(report.keys, 1);
+
+    let repaired = KvStore::repair(temp_dir.path(), &options)?;
+    assert_eq!(repaired.lost, vec!["large".to_owned()]);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, None);
+
+    Ok(())
+}
+
+// kvs-admin verify should fail on a corrupted directory until it's repaired
+#[test]
+fn cli_verify_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path(), KvStoreOptions::default())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("90 keys"));
+
+    let file = temp_dir.path().join("0.sil");
+    let size = std::fs::metadata(&file)?.len();
+    overwrite(&file, size / 2, &[0xff; 20])?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("corrupted"))
+        .stderr(contains("is corrupted"));
+
+    // a directory a server holds isn't repaired
+    let lock = DirLock::acquire(temp_dir.path())?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("repair")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("Cannot lock"));
+    drop(lock);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("repair")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("salvaged"));
+    assert!(!temp_dir.path().join("repair.tmp").exists());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+// A dump should list every record of a generation file in order
+#[test]
+fn log_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path(), KvStoreOptions::default())?;
+
+    let file = temp_dir.path().join("0.sil");
+    let log = LogFile::open(&file, &KvStoreOptions::default())?;
+    assert_eq!(log.key_id(), 0);
+    let records: Vec<_> = log.records().collect();
+    assert_eq!(records.len(), 111);
+    assert_eq!(records[0].offset, 12);
+    assert_eq!(
+        records[0].entry,
+        LogEntry::Set {
+            key: "key0".to_owned(),
+            value: "value0".to_owned()
+        }
+    );
+    assert_eq!(records[100].entry, LogEntry::Remove { key: "key0".to_owned() });
+    for pair in records.windows(2) {
+        assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+    }
+
+    let size = std::fs::metadata(&file)?.len();
+    overwrite(&file, size / 2, &[0xff; 20])?;
+    let log = LogFile::open(&file, &KvStoreOptions::default())?;
+    assert_eq!(log.records().filter(|record| record.entry == LogEntry::Corrupted).count(), 1);
+
+    Ok(())
+}
+
+// kvs-admin dump should print the records of a key in both formats
+#[test]
+fn cli_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path(), KvStoreOptions::default())?;
+    let file = temp_dir.path().join("0.sil");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(&file)
+        .args(["--key", "key10"])
+        .assert()
+        .success()
+        .stdout(contains("set \"key10\" \"value10\""))
+        .stdout(contains("set \"key10\" \"changed\""))
+        .stdout(contains("key1\"").not());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(&file)
+        .args(["--format", "json", "--from", "13", "--max-value", "3", "--key", "key10"])
+        .assert()
+        .success()
+        .stdout(contains(r#""command":"set","key":"key10","value":"val...""#))
+        .stdout(contains(r#""value":"cha...""#));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(&file)
+        .args(["--to", "13"])
+        .assert()
+        .success()
+        .stdout(contains("key0"))
+        .stdout(contains("key1").not());
+
+    Ok(())
+}